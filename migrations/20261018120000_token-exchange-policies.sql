@@ -0,0 +1,7 @@
+-- each row allows client_id to exchange a user's access token for one scoped to
+-- target_client_id (RFC 8693 token exchange)
+CREATE TABLE client_token_exchange_policies (
+  client_id TEXT NOT NULL REFERENCES clients(client_id),
+  target_client_id TEXT NOT NULL REFERENCES clients(client_id),
+  PRIMARY KEY (client_id, target_client_id)
+);
@@ -0,0 +1,3 @@
+-- native apps may only register loopback and private-use scheme redirect uris
+-- (RFC 8252), web apps keep exact https matching.
+ALTER TABLE clients ADD COLUMN is_native_app BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+-- lifetimes are in seconds, NULL falls back to the server-wide default
+ALTER TABLE clients ADD COLUMN access_token_lifetime INTEGER;
+ALTER TABLE clients ADD COLUMN id_token_lifetime INTEGER;
+ALTER TABLE clients ADD COLUMN refresh_token_idle_lifetime INTEGER;
+ALTER TABLE clients ADD COLUMN refresh_token_absolute_lifetime INTEGER;
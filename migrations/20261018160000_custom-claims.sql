@@ -0,0 +1,17 @@
+CREATE TABLE user_attributes (
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name TEXT NOT NULL,
+  value TEXT NOT NULL,
+  PRIMARY KEY (user_id, name)
+);
+
+-- source_type is one of 'claim', 'attribute' or 'static'
+CREATE TABLE client_claim_mappings (
+  client_id TEXT NOT NULL REFERENCES clients(client_id) ON DELETE CASCADE,
+  claim TEXT NOT NULL,
+  source_type TEXT NOT NULL,
+  source TEXT NOT NULL,
+  filter_prefix TEXT,
+  remove_source BOOLEAN NOT NULL DEFAULT false,
+  PRIMARY KEY (client_id, claim)
+);
@@ -0,0 +1,4 @@
+-- groups_claim_mode is one of 'all', 'none', 'list', 'prefix' or 'referenced'.
+-- groups_claim_filter holds the slugs for 'list' and the prefixes for 'prefix'.
+ALTER TABLE clients ADD COLUMN groups_claim_mode TEXT NOT NULL DEFAULT 'all';
+ALTER TABLE clients ADD COLUMN groups_claim_filter TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,6 @@
+-- last_used_at is a unix timestamp in seconds, NULL if the passkey was never used
+ALTER TABLE user_webauthn_credentials ADD COLUMN last_used_at BIGINT;
+ALTER TABLE user_webauthn_credentials ADD COLUMN backup_eligible BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE user_webauthn_credentials ADD COLUMN backup_state BOOLEAN NOT NULL DEFAULT FALSE;
+-- set when the signature counter goes backwards, which means the authenticator may be cloned
+ALTER TABLE user_webauthn_credentials ADD COLUMN is_locked BOOLEAN NOT NULL DEFAULT FALSE;
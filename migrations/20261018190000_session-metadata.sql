@@ -0,0 +1,6 @@
+-- timestamps are unix seconds. existing sessions just get the time of the migration.
+ALTER TABLE user_sessions ADD COLUMN created_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT;
+ALTER TABLE user_sessions ADD COLUMN last_refreshed_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT;
+-- captured when the session was created (at login)
+ALTER TABLE user_sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE user_sessions ADD COLUMN ip_address TEXT;
@@ -0,0 +1,15 @@
+-- bumped every time the session is refreshed, and also stored in the refresh
+-- token. an old generation means a superseded refresh token was presented.
+ALTER TABLE user_sessions ADD COLUMN refresh_generation INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE security_events (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  event_type TEXT NOT NULL,
+  description TEXT NOT NULL,
+  ip_address TEXT,
+  user_agent TEXT,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX idx_security_events_by_user ON security_events(user_id);
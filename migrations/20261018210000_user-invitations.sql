@@ -0,0 +1,13 @@
+-- registration links are backed by an invitation, so they can be revoked and
+-- only ever register a single passkey
+CREATE TABLE user_invitations (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  created_at BIGINT NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  revoked_at BIGINT
+);
+
+CREATE INDEX idx_user_invitations_by_user ON user_invitations(user_id);
@@ -0,0 +1,10 @@
+-- one-time recovery codes, stored as argon2 hashes
+CREATE TABLE user_recovery_codes (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  code_hash TEXT NOT NULL,
+  created_at BIGINT NOT NULL,
+  used_at BIGINT
+);
+
+CREATE INDEX idx_user_recovery_codes_by_user ON user_recovery_codes(user_id);
@@ -0,0 +1,17 @@
+-- 'allowed', 'forbidden' or NULL when the group has no say. users can use TOTP
+-- if one of their groups allows it and none of them forbid it.
+ALTER TABLE permission_groups ADD COLUMN totp_policy TEXT;
+
+-- how the session was started, so refreshing keeps the same method
+ALTER TABLE user_sessions ADD COLUMN method TEXT NOT NULL DEFAULT 'passkey';
+
+CREATE TABLE user_totp_credentials (
+  user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  -- AES-256-GCM encrypted, the nonce is prepended to the ciphertext
+  encrypted_secret TEXT NOT NULL,
+  -- unconfirmed secrets can't be used to sign in
+  is_confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at BIGINT NOT NULL,
+  -- the last time step a code was accepted for, so codes can't be replayed
+  last_used_step BIGINT
+);
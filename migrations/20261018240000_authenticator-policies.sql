@@ -0,0 +1,10 @@
+-- AAGUIDs of the authenticator models members may or may not register. An
+-- empty allow list doesn't restrict anything.
+ALTER TABLE permission_groups ADD COLUMN allowed_aaguids UUID[] NOT NULL DEFAULT '{}';
+ALTER TABLE permission_groups ADD COLUMN denied_aaguids UUID[] NOT NULL DEFAULT '{}';
+
+-- what the authenticator claimed to be when it was registered, which is only
+-- trustworthy if the attestation was verified
+ALTER TABLE user_webauthn_credentials ADD COLUMN aaguid UUID;
+ALTER TABLE user_webauthn_credentials ADD COLUMN attestation_format TEXT;
+ALTER TABLE user_webauthn_credentials ADD COLUMN attestation_verified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- set once the user opened a verification link sent to their email. Nobody's
+-- email has been verified so far, so existing users start out unverified.
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,15 @@
+-- reusable codes that let people sign up on their own. max_uses and expires_at
+-- are NULL for codes without a limit.
+CREATE TABLE signup_invite_codes (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  code TEXT NOT NULL UNIQUE,
+  max_uses INTEGER,
+  uses INTEGER NOT NULL DEFAULT 0,
+  created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  created_at BIGINT NOT NULL,
+  expires_at BIGINT,
+  revoked_at BIGINT
+);
+
+-- users who sign up on their own are added to these groups
+ALTER TABLE permission_groups ADD COLUMN signup_default BOOLEAN NOT NULL DEFAULT FALSE;
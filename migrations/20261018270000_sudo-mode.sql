@@ -0,0 +1,3 @@
+-- when the user last used a passkey in the session, for actions that need a
+-- recent one. NULL for sessions that haven't used one yet.
+ALTER TABLE user_sessions ADD COLUMN passkey_verified_at BIGINT;
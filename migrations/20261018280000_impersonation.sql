@@ -0,0 +1,20 @@
+-- the admin who started the session, for sessions where an admin is acting as
+-- the user. NULL for the user's own sessions.
+ALTER TABLE user_sessions ADD COLUMN impersonator_id INTEGER REFERENCES users(id) ON DELETE CASCADE;
+
+-- every request made in an impersonation session. kept when the admin is
+-- deleted, so the trail doesn't disappear with them.
+CREATE TABLE impersonation_actions (
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  impersonator_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  session_id BIGINT NOT NULL,
+  method TEXT NOT NULL,
+  path TEXT NOT NULL,
+  status INTEGER NOT NULL,
+  ip_address TEXT,
+  user_agent TEXT,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX idx_impersonation_actions_by_user ON impersonation_actions(user_id);
@@ -0,0 +1,3 @@
+-- security keys are registered and used without requiring user verification,
+-- so keys without a PIN or biometrics work too
+ALTER TABLE user_webauthn_credentials ADD COLUMN is_security_key BOOLEAN NOT NULL DEFAULT FALSE;
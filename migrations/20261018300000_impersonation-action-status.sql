@@ -0,0 +1,3 @@
+-- actions are recorded before the request is handled, and the status is filled
+-- in once it's done. NULL if the request never finished.
+ALTER TABLE impersonation_actions ALTER COLUMN status DROP NOT NULL;
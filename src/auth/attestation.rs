@@ -0,0 +1,321 @@
+// Which authenticators may be registered. Groups (and the admin config) can
+// allow or deny authenticator models by their AAGUID. Allow and deny lists can
+// only be enforced with verified attestation, which needs the attestation roots
+// from a locally loaded FIDO Metadata Service (MDS) blob. The same blob is used
+// to name new passkeys after their authenticator.
+
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  error::Error,
+};
+
+use base64::{
+  Engine,
+  prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use serde::Deserialize;
+use serde_cbor_2::Value;
+use sqlx::types::Uuid;
+use webauthn_rs::prelude::{
+  AttestationCaList, AttestationCaListBuilder, RegisterPublicKeyCredential,
+};
+
+use crate::{AppState, response::ApiErr, user::User};
+
+#[derive(Deserialize)]
+struct MetadataBlob {
+  entries: Vec<MetadataBlobEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MetadataBlobEntry {
+  /// Only FIDO2 authenticators have one, U2F ones are identified differently
+  aaguid: Option<Uuid>,
+  metadata_statement: Option<MetadataStatement>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MetadataStatement {
+  description: String,
+  /// Base64 (not url safe) encoded DER certificates
+  #[serde(default)]
+  attestation_root_certificates: Vec<String>,
+}
+
+pub struct AuthenticatorModel {
+  pub description: String,
+  root_certificates: Vec<Vec<u8>>,
+}
+
+/// Authenticator models from the MDS blob, by AAGUID
+#[derive(Default)]
+pub struct AuthenticatorMetadata {
+  models: HashMap<Uuid, AuthenticatorModel>,
+}
+
+impl AuthenticatorMetadata {
+  /// Loads the blob as downloaded from the MDS (a JWT), or just its JSON
+  /// payload. The blob's signature isn't checked, since whoever runs the server
+  /// put it there.
+  pub fn from_file(path: &str) -> Result<AuthenticatorMetadata, Box<dyn Error>> {
+    let blob = std::fs::read_to_string(path)?;
+    let blob = blob.trim();
+    let payload = match blob.split('.').collect::<Vec<&str>>()[..] {
+      [_, payload, _] => BASE64_URL_SAFE_NO_PAD.decode(payload)?,
+      _ => blob.as_bytes().to_vec(),
+    };
+    let blob = serde_json::from_slice::<MetadataBlob>(&payload)?;
+
+    let models = blob
+      .entries
+      .into_iter()
+      .filter_map(|entry| {
+        let statement = entry.metadata_statement?;
+        let model = AuthenticatorModel {
+          description: statement.description,
+          root_certificates: statement
+            .attestation_root_certificates
+            .iter()
+            .filter_map(|x| BASE64_STANDARD.decode(x).ok())
+            .collect(),
+        };
+        Some((entry.aaguid?, model))
+      })
+      .collect();
+
+    Ok(AuthenticatorMetadata { models })
+  }
+
+  pub fn get_description(&self, aaguid: &Uuid) -> Option<&str> {
+    self.models.get(aaguid).map(|x| x.description.as_str())
+  }
+
+  /// Builds a CA list that only trusts the given authenticator models. Models
+  /// that aren't in the blob can't be verified, so they're left out.
+  fn get_ca_list<'a>(
+    &self,
+    aaguids: impl IntoIterator<Item = &'a Uuid>,
+  ) -> Result<AttestationCaList, Box<dyn Error>> {
+    let mut builder = AttestationCaListBuilder::new();
+    for aaguid in aaguids {
+      let Some(model) = self.models.get(aaguid) else {
+        tracing::warn!("Allowed authenticator {aaguid} is not in the metadata blob, skipping");
+        continue;
+      };
+      for certificate in model.root_certificates.iter() {
+        builder.insert_device_der(
+          certificate,
+          *aaguid,
+          model.description.clone(),
+          BTreeMap::new(),
+        )?;
+      }
+    }
+    Ok(builder.build())
+  }
+}
+
+/// What can be read from a new credential's attestation object. Unless the
+/// attestation was verified, this is whatever the authenticator claims.
+pub struct CredentialAttestation {
+  /// None if the authenticator didn't give one (it's all zeroes then)
+  pub aaguid: Option<Uuid>,
+  /// The attestation statement format, e.g. "packed" or "none"
+  pub format: Option<String>,
+}
+
+impl CredentialAttestation {
+  pub fn from_credential(credential: &RegisterPublicKeyCredential) -> CredentialAttestation {
+    let Ok(Value::Map(object)) =
+      serde_cbor_2::from_slice::<Value>(&credential.response.attestation_object)
+    else {
+      return CredentialAttestation {
+        aaguid: None,
+        format: None,
+      };
+    };
+
+    let format = match object.get(&Value::Text("fmt".to_string())) {
+      Some(Value::Text(format)) => Some(format.clone()),
+      _ => None,
+    };
+
+    // authData is the rp id hash (32 bytes), flags (1), sign count (4) and then
+    // the attested credential data, which starts with the AAGUID (16)
+    let aaguid = match object.get(&Value::Text("authData".to_string())) {
+      Some(Value::Bytes(auth_data)) if auth_data.len() >= 53 && auth_data[32] & 0x40 != 0 => {
+        Uuid::from_slice(&auth_data[37..53]).ok()
+      }
+      _ => None,
+    }
+    .filter(|x| !x.is_nil());
+
+    CredentialAttestation { aaguid, format }
+  }
+}
+
+/// Which authenticators a user may register, combined from all of their groups
+/// (and the admin config for admins).
+#[derive(Default)]
+pub struct AuthenticatorPolicy {
+  /// None if every authenticator that isn't denied is allowed
+  pub allowed: Option<HashSet<Uuid>>,
+  pub denied: HashSet<Uuid>,
+}
+
+impl AuthenticatorPolicy {
+  /// Allow lists only ever narrow the policy down, and any deny wins
+  fn restrict(&mut self, allowed: &[Uuid], denied: &[Uuid]) {
+    if !allowed.is_empty() {
+      let allowed = allowed.iter().copied().collect::<HashSet<Uuid>>();
+      self.allowed = Some(match self.allowed.take() {
+        Some(existing) => existing.intersection(&allowed).copied().collect(),
+        None => allowed,
+      });
+    }
+    self.denied.extend(denied.iter().copied());
+  }
+
+  pub async fn for_user(
+    state: &AppState,
+    user: &User,
+  ) -> Result<AuthenticatorPolicy, Box<dyn Error>> {
+    let mut policy = AuthenticatorPolicy::default();
+    for group in user.get_groups(&state.pool).await? {
+      policy.restrict(&group.allowed_aaguids, &group.denied_aaguids);
+    }
+    if user.is_admin {
+      policy.restrict(
+        &state.authenticators.admin_allowed_aaguids,
+        &state.authenticators.admin_denied_aaguids,
+      );
+    }
+    Ok(policy)
+  }
+
+  /// Allow and deny lists both need the attestation to be verified, otherwise
+  /// a denied authenticator could claim to be a different model, or no model
+  /// at all.
+  pub fn permits(&self, attestation: &CredentialAttestation, verified: bool) -> bool {
+    if !self.denied.is_empty() {
+      match attestation.aaguid {
+        Some(aaguid) if verified && !self.denied.contains(&aaguid) => {}
+        _ => return false,
+      }
+    }
+    match &self.allowed {
+      Some(allowed) => verified && attestation.aaguid.is_some_and(|x| allowed.contains(&x)),
+      None => true,
+    }
+  }
+
+  /// The CA list registrations have to be attested with, or None if there's
+  /// neither an allow nor a deny list and attestation isn't needed. With only a
+  /// deny list, every model in the blob that isn't denied is trusted.
+  pub fn get_ca_list(&self, state: &AppState) -> Result<Option<AttestationCaList>, ApiErr> {
+    let metadata = &state.authenticators.metadata;
+    let ca_list = match &self.allowed {
+      Some(allowed) => metadata.get_ca_list(allowed.difference(&self.denied)),
+      None if !self.denied.is_empty() => {
+        metadata.get_ca_list(metadata.models.keys().filter(|x| !self.denied.contains(x)))
+      }
+      None => return Ok(None),
+    };
+
+    let Ok(ca_list) = ca_list else {
+      return Err(ApiErr::InternalServerError);
+    };
+
+    if ca_list.is_empty() {
+      return Err(ApiErr::Other(
+        "no_allowed_authenticators".to_string(),
+        "None of the security keys you're allowed to use can be registered right now. Please contact an administrator."
+          .to_string(),
+      ));
+    }
+
+    Ok(Some(ca_list))
+  }
+}
+
+pub fn authenticator_not_allowed() -> ApiErr {
+  ApiErr::Other(
+    "authenticator_not_allowed".to_string(),
+    "This authenticator is not allowed for your account. Please use a different one.".to_string(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const KEY_A: Uuid = Uuid::from_u128(0xa);
+  const KEY_B: Uuid = Uuid::from_u128(0xb);
+  const KEY_C: Uuid = Uuid::from_u128(0xc);
+
+  fn attestation(aaguid: Option<Uuid>) -> CredentialAttestation {
+    CredentialAttestation {
+      aaguid,
+      format: Some("packed".to_string()),
+    }
+  }
+
+  fn restricted(restrictions: &[(&[Uuid], &[Uuid])]) -> AuthenticatorPolicy {
+    let mut policy = AuthenticatorPolicy::default();
+    for (allowed, denied) in restrictions {
+      policy.restrict(allowed, denied);
+    }
+    policy
+  }
+
+  #[test]
+  fn no_restrictions_permit_anything() {
+    let policy = restricted(&[]);
+    assert!(policy.permits(&attestation(Some(KEY_A)), false));
+    assert!(policy.permits(&attestation(None), false));
+  }
+
+  #[test]
+  fn allow_lists_need_a_verified_matching_aaguid() {
+    let policy = restricted(&[(&[KEY_A], &[])]);
+    assert!(policy.permits(&attestation(Some(KEY_A)), true));
+    assert!(!policy.permits(&attestation(Some(KEY_A)), false));
+    assert!(!policy.permits(&attestation(Some(KEY_B)), true));
+    assert!(!policy.permits(&attestation(None), true));
+  }
+
+  #[test]
+  fn deny_lists_need_a_verified_aaguid() {
+    let policy = restricted(&[(&[], &[KEY_A])]);
+    assert!(policy.permits(&attestation(Some(KEY_B)), true));
+    assert!(!policy.permits(&attestation(Some(KEY_B)), false));
+    assert!(!policy.permits(&attestation(Some(KEY_A)), true));
+    assert!(!policy.permits(&attestation(None), true));
+  }
+
+  #[test]
+  fn allow_lists_narrow_each_other_down() {
+    let policy = restricted(&[(&[KEY_A, KEY_B], &[]), (&[KEY_B, KEY_C], &[])]);
+    assert!(policy.permits(&attestation(Some(KEY_B)), true));
+    assert!(!policy.permits(&attestation(Some(KEY_A)), true));
+    assert!(!policy.permits(&attestation(Some(KEY_C)), true));
+  }
+
+  #[test]
+  fn empty_allow_lists_dont_restrict() {
+    let policy = restricted(&[(&[KEY_A], &[]), (&[], &[])]);
+    assert!(policy.permits(&attestation(Some(KEY_A)), true));
+  }
+
+  #[test]
+  fn denies_win_over_allows() {
+    let policy = restricted(&[(&[KEY_A, KEY_B], &[]), (&[], &[KEY_A])]);
+    assert!(!policy.permits(&attestation(Some(KEY_A)), true));
+    assert!(policy.permits(&attestation(Some(KEY_B)), true));
+
+    let policy = restricted(&[(&[KEY_A], &[KEY_A])]);
+    assert!(!policy.permits(&attestation(Some(KEY_A)), true));
+  }
+}
@@ -0,0 +1,43 @@
+// Signed webauthn challenges are otherwise self-contained, so every issued
+// challenge is also tracked in Redis. Finishing a ceremony consumes it, which
+// keeps the same signed challenge from being used twice.
+
+use std::error::Error;
+
+use rand::distributions::{Alphanumeric, DistString};
+use redis::AsyncCommands;
+
+use crate::AppState;
+
+/// How long a signed challenge can be used for
+pub const CHALLENGE_LIFETIME: u64 = 330;
+
+pub const LOGIN_CHALLENGE: &str = "login";
+pub const USERNAME_LOGIN_CHALLENGE: &str = "username_login";
+pub const REGISTRATION_CHALLENGE: &str = "registration";
+pub const REAUTHENTICATION_CHALLENGE: &str = "reauthentication";
+
+/// Records a new challenge for `purpose` and returns its id, which has to be
+/// included in the signed claims.
+pub async fn issue_challenge(state: &AppState, purpose: &str) -> Result<String, Box<dyn Error>> {
+  let challenge_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+  let key = format!("webauthn_challenge:{}", challenge_id);
+  let _: () = state
+    .redis_connection
+    .clone()
+    .set_ex(key, purpose, CHALLENGE_LIFETIME)
+    .await?;
+  Ok(challenge_id)
+}
+
+/// Returns false if the challenge was never issued for `purpose`, has expired
+/// or was already used.
+pub async fn consume_challenge(
+  state: &AppState,
+  challenge_id: &str,
+  purpose: &str,
+) -> Result<bool, Box<dyn Error>> {
+  let key = format!("webauthn_challenge:{}", challenge_id);
+  let issued_for: Option<String> = state.redis_connection.clone().get_del(key).await?;
+  Ok(issued_for.is_some_and(|x| x == purpose))
+}
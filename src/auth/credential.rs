@@ -1,7 +1,11 @@
-use std::error::Error;
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize};
 use sqlx::{PgPool, types::Uuid};
+use webauthn_rs::prelude::{AuthenticationResult, Passkey};
 
 #[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct WebauthnCredential {
@@ -10,8 +14,27 @@ pub struct WebauthnCredential {
   #[serde(skip)]
   pub credential_uuid: Uuid,
   pub credential_id: String,
+  /// A serialized Passkey, or SecurityKey if `is_security_key` is set. Both
+  /// wrap the same credential, so either can be used to update it.
   #[serde(skip)]
   pub serialized_passkey: String,
+  pub last_used_at: Option<i64>,
+  /// Whether the passkey can be synced (e.g. to a password manager or cloud account)
+  pub backup_eligible: bool,
+  /// Whether the passkey is currently synced
+  pub backup_state: bool,
+  /// Locked passkeys can't be used to sign in. This happens when the signature
+  /// counter goes backwards, since that usually means the authenticator was cloned.
+  pub is_locked: bool,
+  /// The authenticator model, see [`crate::auth::attestation`]
+  pub aaguid: Option<Uuid>,
+  pub attestation_format: Option<String>,
+  /// Whether the attestation was checked against the metadata blob, otherwise
+  /// the AAGUID is only what the authenticator claimed
+  pub attestation_verified: bool,
+  /// Registered without requiring user verification, see
+  /// [`crate::auth::register::AuthenticatorKind::SecurityKey`]
+  pub is_security_key: bool,
 }
 
 impl WebauthnCredential {
@@ -23,7 +46,8 @@ impl WebauthnCredential {
       WebauthnCredential,
       r#"
         SELECT 
-          id, name, credential_id, credential_uuid, serialized_passkey
+          id, name, credential_id, credential_uuid, serialized_passkey, last_used_at, backup_eligible, backup_state, is_locked,
+          aaguid, attestation_format, attestation_verified, is_security_key
         FROM user_webauthn_credentials WHERE credential_uuid = $1
       "#,
       credential_uuid
@@ -36,24 +60,71 @@ impl WebauthnCredential {
   pub async fn create(&mut self, pool: &PgPool) -> Result<&WebauthnCredential, Box<dyn Error>> {
     let result = sqlx::query_scalar!(
       r#"
-        INSERT INTO user_webauthn_credentials(name, credential_uuid, credential_id, serialized_passkey) VALUES 
-          ($1, $2, $3, $4) RETURNING id
+        INSERT INTO user_webauthn_credentials(name, credential_uuid, credential_id, serialized_passkey, last_used_at, backup_eligible, backup_state, is_locked, aaguid, attestation_format, attestation_verified, is_security_key) VALUES 
+          ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id
       "#,
-      self.name, self.credential_uuid, self.credential_id, self.serialized_passkey
+      self.name, self.credential_uuid, self.credential_id, self.serialized_passkey, self.last_used_at, self.backup_eligible, self.backup_state, self.is_locked,
+      self.aaguid, self.attestation_format, self.attestation_verified, self.is_security_key
     ).fetch_one(pool).await?;
     self.id = result;
     Ok(self)
   }
-  /*
-    pub async fn update(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
-      sqlx::query!(
-        r#"
-        UPDATE users SET email=$1, username=$2, name=$3, is_suspended=$4, credential_uuid=$5
-        WHERE id=$6
+
+  pub async fn update(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        UPDATE user_webauthn_credentials SET name=$1, serialized_passkey=$2, last_used_at=$3, backup_eligible=$4, backup_state=$5, is_locked=$6
+        WHERE id=$7
+      "#,
+      self.name, self.serialized_passkey, self.last_used_at, self.backup_eligible, self.backup_state, self.is_locked, self.id
+    ).execute(pool).await?;
+    Ok(())
+  }
+
+  /// Deletes the passkey along with every session that was started with it
+  pub async fn delete(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+      r#"
+        DELETE FROM user_sessions WHERE webauthn_id = $1
+      "#,
+      self.id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+      r#"
+        DELETE FROM user_webauthn_credentials WHERE id = $1
       "#,
-        self.email, self.username, self.name, self.is_suspended, self.credential_uuid, self.id
-      ).execute(pool).await?;
-      Ok(())
+      self.id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+  }
+
+  /// Writes back the passkey state (counter, backup flags) after a successful
+  /// login and marks the passkey as used.
+  pub async fn record_login(
+    &mut self,
+    pool: &PgPool,
+    passkey: &mut Passkey,
+    result: &AuthenticationResult,
+  ) -> Result<(), Box<dyn Error>> {
+    if passkey.update_credential(result) == Some(true) {
+      self.serialized_passkey = serde_json::to_string(passkey)?;
     }
-  */
+
+    self.last_used_at = Some(
+      SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards lol")
+        .as_secs() as i64,
+    );
+    self.backup_eligible = result.backup_eligible();
+    self.backup_state = result.backup_state();
+
+    self.update(pool).await
+  }
 }
@@ -0,0 +1,228 @@
+// Signing in on a shared device by scanning a QR code with a phone that's
+// already signed in. The shared device starts a pending handoff and shows its
+// code, the user approves it from their phone, and the shared device picks up a
+// short-lived session the next time it polls.
+
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Json, extract::State};
+use rand::distributions::{Alphanumeric, DistString};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  AppState,
+  auth::{
+    identity::{HANDOFF_METHOD, IdentityAccessClaims, IdentityRefreshClaims},
+    session::UserSession,
+  },
+  ratelimit::{IpRateLimit, LoginRoutes},
+  response::{ApiErr, ApiResponse},
+  smtp::get_frontend_link,
+  user::User,
+  util::RequestMetadata,
+};
+
+/// How long a handoff can be approved for after it was started
+const HANDOFF_LIFETIME: u64 = 300;
+
+#[derive(Serialize, Deserialize)]
+pub struct PendingHandoff {
+  /// Only the device that started the handoff knows this, unlike the code,
+  /// which anyone near its screen can see
+  pub poll_token: String,
+  /// Of the device that started the handoff, so the user can tell what they're
+  /// approving
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+  pub created_at: u64,
+  pub expires_at: u64,
+  /// Set once a signed in user approved the handoff
+  pub approved_by: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct HandoffStartResponse {
+  /// Shown on screen, this is what the user approves
+  pub code: String,
+  /// Put this in the QR code
+  pub approval_url: String,
+  /// Needed to poll, keep this to yourself
+  pub poll_token: String,
+  pub expires_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct HandoffPollRequest {
+  pub code: String,
+  pub poll_token: String,
+}
+
+#[derive(Serialize)]
+pub struct HandoffLoginResponse {
+  pub access_token: String,
+  pub refresh_token: String,
+  pub session: UserSession,
+  pub user: User,
+}
+
+#[derive(Serialize)]
+pub struct HandoffPollResponse {
+  /// None until the handoff is approved
+  pub login: Option<HandoffLoginResponse>,
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs()
+}
+
+impl PendingHandoff {
+  pub async fn from_code(
+    state: &AppState,
+    code: &str,
+  ) -> Result<Option<PendingHandoff>, Box<dyn Error>> {
+    let key = format!("login_handoff:{}", code);
+    let handoff_data: Option<String> = state.redis_connection.clone().get(key).await?;
+    match handoff_data {
+      Some(data) => Ok(Some(serde_json::from_str::<PendingHandoff>(data.as_str())?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Like [`PendingHandoff::from_code`], but deletes the handoff at the same
+  /// time so only one session can ever be started from it.
+  pub async fn take_from_code(
+    state: &AppState,
+    code: &str,
+  ) -> Result<Option<PendingHandoff>, Box<dyn Error>> {
+    let key = format!("login_handoff:{}", code);
+    let handoff_data: Option<String> = state.redis_connection.clone().get_del(key).await?;
+    match handoff_data {
+      Some(data) => Ok(Some(serde_json::from_str::<PendingHandoff>(data.as_str())?)),
+      None => Ok(None),
+    }
+  }
+
+  pub async fn save_to_code(&self, state: &AppState, code: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("login_handoff:{}", code);
+    let value = serde_json::to_string(self)?;
+    let lifetime = self.expires_at.saturating_sub(now()).max(1);
+    let _: () = state
+      .redis_connection
+      .clone()
+      .set_ex(key, value, lifetime)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn delete(state: &AppState, code: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("login_handoff:{}", code);
+    let _: () = state.redis_connection.clone().del(key).await?;
+    Ok(())
+  }
+}
+
+pub fn unknown_handoff() -> ApiErr {
+  ApiErr::Other(
+    "unknown_handoff".to_string(),
+    "This sign-in request has expired or was already used. Please scan a new code.".to_string(),
+  )
+}
+
+/// Started by the shared device, which should then show the code and poll
+pub async fn start_handoff(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  metadata: RequestMetadata,
+) -> ApiResponse<HandoffStartResponse> {
+  let code = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+  let poll_token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+  let created_at = now();
+
+  let handoff = PendingHandoff {
+    poll_token: poll_token.clone(),
+    user_agent: metadata.user_agent,
+    ip_address: metadata.ip_address,
+    created_at,
+    expires_at: created_at + HANDOFF_LIFETIME,
+    approved_by: None,
+  };
+  if handoff.save_to_code(&state, &code).await.is_err() {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  let approval_url = get_frontend_link(&state, &format!("/auth/handoff?c={}", code));
+
+  ApiResponse::Ok(HandoffStartResponse {
+    code,
+    approval_url,
+    poll_token,
+    expires_at: handoff.expires_at,
+  })
+}
+
+/// Starts the session once the handoff is approved. Until then, this returns
+/// no login and the shared device should poll again in a few seconds.
+pub async fn poll_handoff(
+  State(state): State<AppState>,
+  metadata: RequestMetadata,
+  Json(payload): Json<HandoffPollRequest>,
+) -> ApiResponse<HandoffPollResponse> {
+  let Ok(handoff) = PendingHandoff::from_code(&state, &payload.code).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(handoff) = handoff.filter(|x| x.poll_token == payload.poll_token) else {
+    return ApiResponse::Err(unknown_handoff());
+  };
+
+  if handoff.approved_by.is_none() {
+    return ApiResponse::Ok(HandoffPollResponse { login: None });
+  }
+
+  // taken, so concurrent polls can't start several sessions
+  let Ok(handoff) = PendingHandoff::take_from_code(&state, &payload.code).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(user_id) = handoff.and_then(|x| x.approved_by) else {
+    return ApiResponse::Err(unknown_handoff());
+  };
+
+  let Ok(user) = User::from_user_id(&state.pool, user_id).await else {
+    return ApiResponse::Err(ApiErr::UserDeleted);
+  };
+
+  if user.is_suspended {
+    return ApiResponse::Err(ApiErr::UserSuspended);
+  }
+
+  let Ok((refresh_token, session)) =
+    UserSession::create_session(&state.pool, user.id, 0, HANDOFF_METHOD, metadata).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let access_claims = IdentityAccessClaims::create_for_session(&user, &session);
+
+  let refresh_claims = IdentityRefreshClaims {
+    session_id: session.session_id,
+    refresh_token,
+    generation: session.refresh_generation,
+  };
+
+  ApiResponse::Ok(HandoffPollResponse {
+    login: Some(HandoffLoginResponse {
+      access_token: access_claims.to_token(&state),
+      refresh_token: refresh_claims.to_jwt(&state),
+      session,
+      user,
+    }),
+  })
+}
@@ -4,18 +4,38 @@ use std::{
 };
 
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use axum::{Extension, Json, extract::State};
+use axum::{Json, extract::State};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use serde_with::{DisplayFromStr, serde_as};
+use webauthn_rs::prelude::Url;
 
 use crate::{
   AppState,
-  auth::session::UserSession,
+  auth::{session::UserSession, totp::is_totp_allowed},
+  ratelimit::{IpRateLimit, RefreshRoutes},
   response::{ApiErr, ApiResponse, EmptyResponse},
-  user::User,
+  smtp::{new_session_reuse_message, send_mail},
+  user::{
+    RestrictedCtx, User,
+    events::{REFRESH_TOKEN_REUSE_EVENT, SecurityEvent},
+  },
+  util::RequestMetadata,
 };
 
+/// Regular sessions, started by signing in with a passkey
+pub const PASSKEY_METHOD: &str = "passkey";
+/// Restricted sessions started with a recovery code, which can only be used to
+/// register a new passkey
+pub const RECOVERY_CODE_METHOD: &str = "recovery_code";
+/// Sessions started with a TOTP code, for users whose groups allow it
+pub const TOTP_METHOD: &str = "totp";
+/// Short-lived sessions handed off to another device by approving its QR code,
+/// see [`crate::auth::handoff`]
+pub const HANDOFF_METHOD: &str = "handoff";
+/// Sessions where an admin acts as the user, see [`crate::user::impersonation`]
+pub const IMPERSONATION_METHOD: &str = "impersonation";
+
 #[serde_as]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct IdentityAccessClaims {
@@ -34,6 +54,14 @@ pub struct IdentityAccessClaims {
   pub is_admin: bool,
   #[serde_as(as = "DisplayFromStr")]
   pub session_id: i64,
+  /// When the user last used a passkey in this session, see
+  /// [`IdentityAccessClaims::has_recent_passkey`]
+  #[serde(default)]
+  pub passkey_verified_at: Option<u64>,
+  /// Set when an admin is acting as the user. Clients should make this obvious,
+  /// and anything done with these claims is logged.
+  #[serde(default)]
+  pub impersonator_id: Option<i32>,
 }
 
 /// The purpose of putting the refresh token in a JWT is less about the
@@ -45,6 +73,10 @@ pub struct IdentityRefreshClaims {
   #[serde_as(as = "DisplayFromStr")]
   pub session_id: i64,
   pub refresh_token: String,
+  /// The session's refresh generation when this token was issued. Tokens from
+  /// before this was added are treated as generation 0.
+  #[serde(default)]
+  pub generation: i32,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -63,7 +95,7 @@ pub fn authenticate_jwt(token: String, state: &AppState) -> Option<IdentityAcces
     &DecodingKey::from_secret(state.private_keys.identity_access_jwt_key.as_bytes());
   let decoded_token = jsonwebtoken::decode::<IdentityAccessClaims>(
     &token,
-    &decoded_key,
+    decoded_key,
     &Validation::new(jsonwebtoken::Algorithm::HS256),
   )
   .ok()?;
@@ -71,30 +103,54 @@ pub fn authenticate_jwt(token: String, state: &AppState) -> Option<IdentityAcces
 }
 
 impl IdentityAccessClaims {
-  pub fn create_from_passkey(
-    user: &User,
-    webauthn_id: i32,
-    session_id: i64,
-  ) -> IdentityAccessClaims {
+  /// Restricted sessions are short-lived, since they are never refreshed.
+  /// Handoff sessions are too, since they're used on shared devices, and so are
+  /// impersonation sessions.
+  pub fn create_for_session(user: &User, session: &UserSession) -> IdentityAccessClaims {
     let iat = SystemTime::now()
       .duration_since(UNIX_EPOCH)
       .expect("Time went backwards lol")
       .as_secs();
 
+    let lifetime = match session.method.as_str() {
+      RECOVERY_CODE_METHOD | HANDOFF_METHOD | IMPERSONATION_METHOD => 900,
+      _ => 3600,
+    };
+
     IdentityAccessClaims {
       user_id: user.id,
-      method: "passkey".to_string(),
+      method: session.method.clone(),
       iat,
-      exp: iat + 3600,
+      exp: iat + lifetime,
       email: user.email.clone(),
       username: user.username.clone(),
       name: user.name.clone(),
       is_admin: user.is_admin,
-      webauthn_id,
-      session_id,
+      webauthn_id: session.webauthn_id,
+      session_id: session.session_id,
+      passkey_verified_at: session.passkey_verified_at.map(|x| x as u64),
+      impersonator_id: session.impersonator_id,
     }
   }
 
+  /// Whether the user used a passkey within the last `lifetime` seconds, which
+  /// sensitive actions require on top of being signed in
+  pub fn has_recent_passkey(&self, lifetime: u64) -> bool {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .expect("Time went backwards lol")
+      .as_secs();
+    self.passkey_verified_at.is_some_and(|x| x + lifetime > now)
+  }
+
+  pub fn is_restricted(&self) -> bool {
+    self.method == RECOVERY_CODE_METHOD
+  }
+
+  pub fn is_impersonated(&self) -> bool {
+    self.impersonator_id.is_some()
+  }
+
   pub fn to_token(&self, state: &AppState) -> String {
     let encoding_key =
       &EncodingKey::from_secret(state.private_keys.identity_access_jwt_key.as_bytes());
@@ -112,18 +168,73 @@ impl IdentityRefreshClaims {
   pub fn from_jwt(jwt: String, state: &AppState) -> Option<IdentityRefreshClaims> {
     let decoded_key =
       &DecodingKey::from_secret(state.private_keys.identity_refresh_jwt_key.as_bytes());
-    // refesh tokens currently don't expire, so we disable this validation
+    // the token itself doesn't expire, the session it belongs to does. that is
+    // checked against the database in refresh_auth.
     let mut validation = Validation::new(jsonwebtoken::Algorithm::HS256);
     validation.validate_exp = false;
     validation.required_spec_claims = HashSet::new();
     let decoded_token =
-      jsonwebtoken::decode::<IdentityRefreshClaims>(&jwt, &decoded_key, &validation).ok()?;
+      jsonwebtoken::decode::<IdentityRefreshClaims>(&jwt, decoded_key, &validation).ok()?;
     Some(decoded_token.claims)
   }
 }
 
+/// Handles a superseded refresh token being presented. Either the legitimate
+/// client or whoever stole the token already refreshed, and we can't tell which
+/// one this is, so the whole session is terminated.
+async fn handle_refresh_token_reuse(
+  state: &AppState,
+  session: &mut UserSession,
+  metadata: &RequestMetadata,
+) {
+  if session.delete_session(&state.pool).await.is_err() {
+    tracing::error!(
+      "Failed to terminate session {} after refresh token reuse",
+      session.session_id
+    );
+  }
+
+  let description = format!(
+    "A superseded refresh token was used for session {}, the session was terminated",
+    session.session_id
+  );
+  if SecurityEvent::record(
+    &state.pool,
+    session.user_id,
+    REFRESH_TOKEN_REUSE_EVENT,
+    description,
+    metadata,
+  )
+  .await
+  .is_err()
+  {
+    tracing::error!("Failed to record refresh token reuse event");
+  }
+
+  if !state.notify_session_reuse {
+    return;
+  }
+
+  let Ok(user) = User::from_user_id(&state.pool, session.user_id).await else {
+    return;
+  };
+
+  let origin = Url::parse(&state.oidc_issuer_uri)
+    .ok()
+    .and_then(|x| x.host_str().map(|x| x.to_string()))
+    .unwrap_or(state.oidc_issuer_uri.clone());
+  if send_mail(state, new_session_reuse_message(&user, origin))
+    .await
+    .is_err()
+  {
+    tracing::error!("Failed to send refresh token reuse email");
+  }
+}
+
 pub async fn refresh_auth(
   State(state): State<crate::AppState>,
+  _: IpRateLimit<RefreshRoutes>,
+  metadata: RequestMetadata,
   Json(payload): Json<RefreshTokenRequest>,
 ) -> ApiResponse<RefreshTokenResponse> {
   let Some(refresh_claims) = IdentityRefreshClaims::from_jwt(payload.refresh_token, &state) else {
@@ -137,19 +248,38 @@ pub async fn refresh_auth(
     return ApiResponse::Err(ApiErr::SessionExpired);
   };
 
+  if session.is_expired(&state.session_lifetimes) {
+    tracing::info!("session expired");
+    // the purge job would get to it eventually, but there's no reason to wait
+    let _ = session.delete_session(&state.pool).await;
+    return ApiResponse::Err(ApiErr::SessionExpired);
+  }
+
   let Ok(refresh_hash) = PasswordHash::new(&session.refresh_hash) else {
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
-  if !Argon2::default()
+  if Argon2::default()
     .verify_password(refresh_claims.refresh_token.as_bytes(), &refresh_hash)
-    .is_ok()
+    .is_err()
   {
-    tracing::info!("refresh token not valid!");
+    if refresh_claims.generation < session.refresh_generation {
+      handle_refresh_token_reuse(&state, &mut session, &metadata).await;
+    } else {
+      tracing::info!("refresh token not valid!");
+    }
     return ApiResponse::Err(ApiErr::SessionExpired);
   }
 
   let Ok(refresh_token) = session.refresh_session(&state.pool).await else {
+    // if the generation moved on, the same token was used for a concurrent refresh
+    let refreshed_concurrently = UserSession::from_session_id(&state.pool, session.session_id)
+      .await
+      .is_ok_and(|x| x.refresh_generation != session.refresh_generation);
+    if refreshed_concurrently {
+      handle_refresh_token_reuse(&state, &mut session, &metadata).await;
+      return ApiResponse::Err(ApiErr::SessionExpired);
+    }
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
@@ -161,12 +291,25 @@ pub async fn refresh_auth(
     return ApiResponse::Err(ApiErr::UserSuspended);
   }
 
-  let access_token =
-    IdentityAccessClaims::create_from_passkey(&user, session.webauthn_id, session.session_id);
+  // the user's groups might not allow TOTP anymore
+  if session.method == TOTP_METHOD {
+    let Ok(groups) = user.get_groups(&state.pool).await else {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    };
+    if !is_totp_allowed(&groups) {
+      if session.delete_session(&state.pool).await.is_err() {
+        return ApiResponse::Err(ApiErr::InternalServerError);
+      }
+      return ApiResponse::Err(ApiErr::SessionExpired);
+    }
+  }
+
+  let access_token = IdentityAccessClaims::create_for_session(&user, &session);
 
   let jwt_refresh_claims = IdentityRefreshClaims {
     session_id: session.session_id,
     refresh_token,
+    generation: session.refresh_generation,
   };
   let jwt_refresh_token = jwt_refresh_claims.to_jwt(&state);
 
@@ -176,18 +319,19 @@ pub async fn refresh_auth(
   })
 }
 
+/// Restricted sessions can sign out too
 pub async fn logout_current_session(
   State(state): State<crate::AppState>,
-  _: User,
-  Extension(claims): Extension<IdentityAccessClaims>,
+  ctx: RestrictedCtx,
 ) -> ApiResponse<EmptyResponse> {
-  let Ok(mut session) = UserSession::from_session_id(&state.pool, claims.session_id).await else {
+  let Ok(mut session) = UserSession::from_session_id(&state.pool, ctx.claims.session_id).await
+  else {
     // they probably already logged out but still have a valid access token, so just
     // tell them they're already logged out.
     return ApiResponse::EmptyOk;
   };
 
-  if !session.delete_session(&state.pool).await.is_ok() {
+  if session.delete_session(&state.pool).await.is_err() {
     return ApiResponse::Err(ApiErr::InternalServerError);
   }
 
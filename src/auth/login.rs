@@ -1,32 +1,82 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
-use axum::{Json, extract::State};
+use axum::{Extension, Json, extract::State};
 use base64::{Engine, prelude::BASE64_STANDARD};
+use hmac::{Hmac, Mac};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use sqlx::types::Uuid;
 use webauthn_rs::prelude::{
-  DiscoverableAuthentication, DiscoverableKey, Passkey, PublicKeyCredential,
-  RequestChallengeResponse,
+  AuthenticationResult, DiscoverableAuthentication, DiscoverableKey, Passkey,
+  PasskeyAuthentication, PublicKeyCredential, RequestChallengeResponse, SecurityKey,
+  SecurityKeyAuthentication, WebauthnError,
 };
+use webauthn_rs_proto::{AllowCredentials, PublicKeyCredentialHints, UserVerificationPolicy};
 
 use crate::{
+  AppState,
   auth::{
+    challenge::{
+      CHALLENGE_LIFETIME, LOGIN_CHALLENGE, REAUTHENTICATION_CHALLENGE, USERNAME_LOGIN_CHALLENGE,
+      consume_challenge, issue_challenge,
+    },
     credential::WebauthnCredential,
-    identity::{IdentityAccessClaims, IdentityRefreshClaims},
+    identity::{IdentityAccessClaims, IdentityRefreshClaims, PASSKEY_METHOD},
     session::UserSession,
   },
+  ratelimit::{IpRateLimit, LoginRoutes, get_lockout, hit, lock_out},
   response::{ApiErr, ApiResponse},
-  user::User,
+  user::{
+    User,
+    events::{PASSKEY_COUNTER_REGRESSION_EVENT, SecurityEvent},
+  },
+  util::RequestMetadata,
 };
 
 #[derive(Serialize, Deserialize)]
 pub struct SignedLoginChallengeClaims {
+  /// Consumed when the login is finished, so the challenge can't be replayed
+  pub challenge_id: String,
   pub iat: u64,
   pub exp: u64,
   pub auth: DiscoverableAuthentication,
 }
 
+/// Unlike [`SignedLoginChallengeClaims`], the authentication state is kept in
+/// Redis, since it would give away whether the username exists. Also used to
+/// re-authenticate, where the user is already known.
+#[derive(Serialize, Deserialize)]
+pub struct SignedUsernameLoginChallengeClaims {
+  pub challenge_id: String,
+  pub iat: u64,
+  pub exp: u64,
+}
+
+/// Security keys don't have to verify the user, so once a user has one, the
+/// whole ceremony is a security key one. Their passkeys still need user
+/// verification, which is checked after the signature.
+#[derive(Serialize, Deserialize)]
+enum UserAuthentication {
+  Passkey(PasskeyAuthentication),
+  SecurityKey(SecurityKeyAuthentication),
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsernameLoginState {
+  pub user_id: i32,
+  pub auth: UserAuthentication,
+}
+
+#[derive(Deserialize)]
+pub struct UsernameLoginInitiateRequest {
+  pub username: String,
+}
+
 #[derive(Serialize)]
 pub struct LoginInitiateResponse {
   pub challenge_signature: String,
@@ -39,6 +89,12 @@ pub struct LoginFinalizeRequest {
   pub pk_credential: PublicKeyCredential,
 }
 
+#[derive(Serialize)]
+pub struct ReauthenticationResponse {
+  /// Has a fresh `passkey_verified_at`, use it instead of the old one
+  pub access_token: String,
+}
+
 #[derive(Serialize)]
 pub struct LoginFinalizeResponse {
   pub access_token: String,
@@ -51,13 +107,13 @@ pub struct LoginFinalizeResponse {
 impl SignedLoginChallengeClaims {
   fn to_token(&self, state: &crate::AppState) -> String {
     let encoding_key =
-      &EncodingKey::from_secret(state.private_keys.passkey_registration_key.as_bytes());
+      &EncodingKey::from_secret(state.private_keys.passkey_authentication_key.as_bytes());
     jsonwebtoken::encode(&Header::default(), &self, encoding_key).expect("Failed to encode key!")
   }
 
   fn from_token(token: String, state: &crate::AppState) -> Option<SignedLoginChallengeClaims> {
     let decoded_key =
-      DecodingKey::from_secret(state.private_keys.passkey_registration_key.as_bytes());
+      DecodingKey::from_secret(state.private_keys.passkey_authentication_key.as_bytes());
     let decoded_token = match jsonwebtoken::decode::<SignedLoginChallengeClaims>(
       &token,
       &decoded_key,
@@ -73,6 +129,58 @@ impl SignedLoginChallengeClaims {
   }
 }
 
+impl SignedUsernameLoginChallengeClaims {
+  fn to_token(&self, state: &AppState) -> String {
+    let encoding_key =
+      &EncodingKey::from_secret(state.private_keys.passkey_authentication_key.as_bytes());
+    jsonwebtoken::encode(&Header::default(), &self, encoding_key).expect("Failed to encode key!")
+  }
+
+  fn from_token(token: String, state: &AppState) -> Option<SignedUsernameLoginChallengeClaims> {
+    let decoded_key =
+      DecodingKey::from_secret(state.private_keys.passkey_authentication_key.as_bytes());
+    let decoded_token = match jsonwebtoken::decode::<SignedUsernameLoginChallengeClaims>(
+      &token,
+      &decoded_key,
+      &Validation::new(jsonwebtoken::Algorithm::HS256),
+    ) {
+      Ok(t) => t,
+      Err(e) => {
+        tracing::warn!("Failed to decode signed challenge token: {e}");
+        return None;
+      }
+    };
+    Some(decoded_token.claims)
+  }
+}
+
+impl UsernameLoginState {
+  async fn save(&self, state: &AppState, challenge_id: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("username_login:{}", challenge_id);
+    let value = serde_json::to_string(self)?;
+    let _: () = state
+      .redis_connection
+      .clone()
+      .set_ex(key, value, CHALLENGE_LIFETIME)
+      .await?;
+    Ok(())
+  }
+
+  async fn take(
+    state: &AppState,
+    challenge_id: &str,
+  ) -> Result<Option<UsernameLoginState>, Box<dyn Error>> {
+    let key = format!("username_login:{}", challenge_id);
+    let login_state: Option<String> = state.redis_connection.clone().get_del(key).await?;
+    match login_state {
+      Some(data) => Ok(Some(serde_json::from_str::<UsernameLoginState>(
+        data.as_str(),
+      )?)),
+      None => Ok(None),
+    }
+  }
+}
+
 pub async fn start_passkey_login(
   State(state): State<crate::AppState>,
 ) -> ApiResponse<LoginInitiateResponse> {
@@ -85,9 +193,14 @@ pub async fn start_passkey_login(
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
+  let Ok(challenge_id) = issue_challenge(&state, LOGIN_CHALLENGE).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
   let signed_claims = SignedLoginChallengeClaims {
+    challenge_id,
     iat,
-    exp: iat + 330,
+    exp: iat + CHALLENGE_LIFETIME,
     auth: pka,
   };
 
@@ -99,6 +212,8 @@ pub async fn start_passkey_login(
 
 pub async fn finish_passkey_login(
   State(state): State<crate::AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  metadata: RequestMetadata,
   Json(payload): Json<LoginFinalizeRequest>,
 ) -> ApiResponse<LoginFinalizeResponse> {
   let Some(signed_challenge) =
@@ -107,6 +222,12 @@ pub async fn finish_passkey_login(
     return ApiResponse::Err(ApiErr::InvalidChallenge);
   };
 
+  match consume_challenge(&state, &signed_challenge.challenge_id, LOGIN_CHALLENGE).await {
+    Ok(true) => {}
+    Ok(false) => return ApiResponse::Err(ApiErr::InvalidChallenge),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
   let Some(credential_handle) = payload.pk_credential.get_user_unique_id() else {
     return ApiResponse::Err(ApiErr::InvalidCredential);
   };
@@ -125,61 +246,582 @@ pub async fn finish_passkey_login(
     return ApiResponse::Err(ApiErr::UserDeleted);
   };
 
+  if let Err(err) = check_user_login_lockout(&state, &user, &metadata).await {
+    return ApiResponse::Err(err);
+  }
+
   if user.is_suspended {
     return ApiResponse::Err(ApiErr::UserSuspended);
   }
 
-  let credentials = credential_vec
-    .iter()
-    .map(|x| serde_json::from_str::<Passkey>(&x.serialized_passkey))
-    .filter_map(Result::ok)
-    .map(DiscoverableKey::from)
-    .collect::<Vec<DiscoverableKey>>();
+  let Some(credential) = credential_vec.into_iter().find(|x| {
+    BASE64_STANDARD
+      .decode(&x.credential_id)
+      .is_ok_and(|y| y == *payload.pk_credential.raw_id)
+  }) else {
+    return ApiResponse::Err(ApiErr::InvalidCredential);
+  };
+
+  if credential.is_locked {
+    return ApiResponse::Err(ApiErr::CredentialLocked);
+  }
+
+  let Ok(passkey) = serde_json::from_str::<Passkey>(&credential.serialized_passkey) else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
 
   match state.webauthn.finish_discoverable_authentication(
     &payload.pk_credential,
     signed_challenge.auth,
-    &credentials,
+    &[DiscoverableKey::from(&passkey)],
   ) {
     Ok(result) => {
-      let possible_credentials = credential_vec
-        .into_iter()
-        .filter(|x| {
-          BASE64_STANDARD
-            .decode(&x.credential_id)
-            .is_ok_and(|y| &y == result.cred_id())
-        })
-        .collect::<Vec<WebauthnCredential>>();
-
-      let Some(credential) = possible_credentials.first() else {
-        return ApiResponse::Err(ApiErr::InternalServerError);
-      };
+      complete_passkey_login(&state, metadata, user, credential, passkey, &result).await
+    }
+    Err(WebauthnError::CredentialPossibleCompromise) => {
+      lock_compromised_passkey(&state, &metadata, &user, credential).await
+    }
+    Err(_) => {
+      if let Err(err) = record_failed_user_login(&state, &user, &metadata).await {
+        return ApiResponse::Err(err);
+      }
+      ApiResponse::Err(ApiErr::Other(
+        "webauthn_error".to_string(),
+        "An unexpected webauthn passkey registration error occurred.".to_string(),
+      ))
+    }
+  }
+}
+
+fn user_login_key(user: &User, metadata: &RequestMetadata) -> String {
+  let ip_address = metadata.ip_address.as_deref().unwrap_or("unknown");
+  format!("login:user:{}:ip:{}", user.id, ip_address)
+}
+
+/// Checked before the passkey, so an IP that's locked out of an account can't
+/// keep guessing. Other IPs can still sign in to it.
+async fn check_user_login_lockout(
+  state: &AppState,
+  user: &User,
+  metadata: &RequestMetadata,
+) -> Result<(), ApiErr> {
+  match get_lockout(state, &user_login_key(user, metadata)).await {
+    Ok(None) => Ok(()),
+    Ok(Some(retry_after)) => Err(ApiErr::RateLimited(retry_after)),
+    Err(_) => Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Counts a failed signature check, and locks the IP out of the account until
+/// the oldest failure leaves the window once there are too many
+async fn record_failed_user_login(
+  state: &AppState,
+  user: &User,
+  metadata: &RequestMetadata,
+) -> Result<(), ApiErr> {
+  let key = user_login_key(user, metadata);
+  let Ok(retry_after) = hit(
+    state,
+    &format!("{}:failures", key),
+    &state.rate_limits.login,
+  )
+  .await
+  else {
+    return Err(ApiErr::InternalServerError);
+  };
+  if let Some(retry_after) = retry_after
+    && lock_out(state, &key, retry_after).await.is_err()
+  {
+    return Err(ApiErr::InternalServerError);
+  }
+  Ok(())
+}
+
+/// Starts a session once the passkey's signature was verified
+async fn complete_passkey_login(
+  state: &AppState,
+  metadata: RequestMetadata,
+  user: User,
+  mut credential: WebauthnCredential,
+  mut passkey: Passkey,
+  result: &AuthenticationResult,
+) -> ApiResponse<LoginFinalizeResponse> {
+  if credential
+    .record_login(&state.pool, &mut passkey, result)
+    .await
+    .is_err()
+  {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  let Ok((refresh_token, session)) = UserSession::create_session(
+    &state.pool,
+    user.id,
+    credential.id,
+    PASSKEY_METHOD,
+    metadata,
+  )
+  .await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let access_claims = IdentityAccessClaims::create_for_session(&user, &session);
+
+  let refresh_claims = IdentityRefreshClaims {
+    session_id: session.session_id,
+    refresh_token,
+    generation: session.refresh_generation,
+  };
+
+  ApiResponse::Ok(LoginFinalizeResponse {
+    access_token: access_claims.to_token(state),
+    refresh_token: refresh_claims.to_jwt(state),
+    credential,
+    user,
+    session,
+  })
+}
+
+async fn lock_compromised_passkey<T: Serialize>(
+  state: &AppState,
+  metadata: &RequestMetadata,
+  user: &User,
+  mut credential: WebauthnCredential,
+) -> ApiResponse<T> {
+  tracing::warn!(
+    "Locking passkey {} of user {} after its signature counter went backwards",
+    credential.id,
+    user.id
+  );
+  credential.is_locked = true;
+  if credential.update(&state.pool).await.is_err() {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+  let description = format!(
+    "The signature counter of passkey \"{}\" went backwards, the passkey was locked",
+    credential.name
+  );
+  if SecurityEvent::record(
+    &state.pool,
+    user.id,
+    PASSKEY_COUNTER_REGRESSION_EVENT,
+    description,
+    metadata,
+  )
+  .await
+  .is_err()
+  {
+    tracing::error!("Failed to record passkey counter regression event");
+  }
+  ApiResponse::Err(ApiErr::CredentialLocked)
+}
+
+/// Starts authenticating with the passkeys `user` can sign in with. Returns
+/// None if they have no usable passkeys.
+async fn start_user_authentication(
+  state: &AppState,
+  user: &User,
+) -> Result<Option<(RequestChallengeResponse, UsernameLoginState)>, Box<dyn Error>> {
+  let credentials = WebauthnCredential::from_credential_uuid(&state.pool, user.credential_uuid)
+    .await?
+    .into_iter()
+    .filter(|x| !x.is_locked)
+    .collect::<Vec<WebauthnCredential>>();
+
+  if credentials.is_empty() {
+    return Ok(None);
+  }
+
+  // both are stored the same way, so any passkey can be read as a security key
+  let (rcr, auth) = if credentials.iter().any(|x| x.is_security_key) {
+    let keys = credentials
+      .iter()
+      .filter_map(|x| serde_json::from_str::<SecurityKey>(&x.serialized_passkey).ok())
+      .collect::<Vec<SecurityKey>>();
+    let (rcr, auth) = state.webauthn.start_securitykey_authentication(&keys)?;
+    (rcr, UserAuthentication::SecurityKey(auth))
+  } else {
+    let passkeys = credentials
+      .iter()
+      .filter_map(|x| serde_json::from_str::<Passkey>(&x.serialized_passkey).ok())
+      .collect::<Vec<Passkey>>();
+    let (rcr, auth) = state.webauthn.start_passkey_authentication(&passkeys)?;
+    (rcr, UserAuthentication::Passkey(auth))
+  };
+
+  Ok(Some((
+    rcr,
+    UsernameLoginState {
+      user_id: user.id,
+      auth,
+    },
+  )))
+}
 
-      let Ok((refresh_token, session)) =
-        UserSession::create_session(&state.pool, user.id, credential.id).await
-      else {
+/// Verifies the signature of `credential` against `auth`
+fn finish_user_authentication(
+  state: &AppState,
+  pk_credential: &PublicKeyCredential,
+  auth: &UserAuthentication,
+  credential: &WebauthnCredential,
+) -> Result<AuthenticationResult, WebauthnError> {
+  match auth {
+    UserAuthentication::Passkey(auth) => state
+      .webauthn
+      .finish_passkey_authentication(pk_credential, auth),
+    UserAuthentication::SecurityKey(auth) => {
+      let result = state
+        .webauthn
+        .finish_securitykey_authentication(pk_credential, auth)?;
+      if !credential.is_security_key && !result.user_verified() {
+        return Err(WebauthnError::UserNotVerified);
+      }
+      Ok(result)
+    }
+  }
+}
+
+/// Looks up the passkeys `username` can sign in with and starts authenticating
+/// with them. Returns None if there's no such user or they have no usable
+/// passkeys.
+async fn start_username_authentication(
+  state: &AppState,
+  username: &str,
+) -> Result<Option<(RequestChallengeResponse, UsernameLoginState)>, Box<dyn Error>> {
+  let Ok(user) = User::from_username(&state.pool, username.to_string()).await else {
+    return Ok(None);
+  };
+
+  let Some((mut rcr, login_state)) = start_user_authentication(state, &user).await? else {
+    return Ok(None);
+  };
+
+  // decoys can't have transports, so real credentials don't get them either
+  for credential in rcr.public_key.allow_credentials.iter_mut() {
+    credential.transports = None;
+  }
+
+  Ok(Some((rcr, login_state)))
+}
+
+/// A challenge for usernames that can't sign in, which looks like a real one.
+/// How many made up credentials there are, their ids and whether it looks like
+/// a security key ceremony are all derived from the username, so it's the same
+/// every time it's asked for.
+fn create_decoy_challenge(
+  state: &AppState,
+  username: &str,
+) -> Result<RequestChallengeResponse, Box<dyn Error>> {
+  let key = state.private_keys.passkey_authentication_key.as_bytes();
+  let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)?;
+  mac.update(username.as_bytes());
+  let seed = mac.finalize().into_bytes();
+
+  // most users only have one or two passkeys
+  let count = match seed[0] {
+    0..160 => 1,
+    160..224 => 2,
+    _ => 3,
+  };
+  let is_security_key = seed[1] < 64;
+
+  let mut allow_credentials = Vec::with_capacity(count);
+  for i in 0..count as u8 {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)?;
+    mac.update(username.as_bytes());
+    mac.update(&[i]);
+    allow_credentials.push(AllowCredentials {
+      type_: "public-key".to_string(),
+      id: mac.finalize().into_bytes().to_vec().into(),
+      transports: None,
+    });
+  }
+
+  let (mut rcr, _) = state.webauthn.start_discoverable_authentication()?;
+  rcr.mediation = None;
+  rcr.public_key.extensions = None;
+  rcr.public_key.allow_credentials = allow_credentials;
+  if is_security_key {
+    rcr.public_key.user_verification = UserVerificationPolicy::Preferred;
+    rcr.public_key.hints = Some(vec![PublicKeyCredentialHints::SecurityKey]);
+  }
+  Ok(rcr)
+}
+
+/// For security keys that can't store credentials, which only work if the
+/// server tells them which credentials to use. Every username gets a challenge,
+/// so this can't be used to find out which usernames exist.
+pub async fn start_username_login(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  Json(payload): Json<UsernameLoginInitiateRequest>,
+) -> ApiResponse<LoginInitiateResponse> {
+  let Ok(challenge_id) = issue_challenge(&state, USERNAME_LOGIN_CHALLENGE).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(authentication) = start_username_authentication(&state, &payload.username).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let challenge_response = match authentication {
+    Some((rcr, login_state)) => {
+      if login_state.save(&state, &challenge_id).await.is_err() {
         return ApiResponse::Err(ApiErr::InternalServerError);
-      };
-
-      let access_claims =
-        IdentityAccessClaims::create_from_passkey(&user, credential.id, session.session_id);
-
-      let refresh_claims = IdentityRefreshClaims {
-        session_id: session.session_id,
-        refresh_token,
-      };
-
-      ApiResponse::Ok(LoginFinalizeResponse {
-        access_token: access_claims.to_token(&state),
-        refresh_token: refresh_claims.to_jwt(&state),
-        credential: credential.clone(),
-        user,
-        session,
-      })
+      }
+      rcr
+    }
+    None => match create_decoy_challenge(&state, &payload.username) {
+      Ok(rcr) => rcr,
+      Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+    },
+  };
+
+  let iat = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs();
+
+  let signed_claims = SignedUsernameLoginChallengeClaims {
+    challenge_id,
+    iat,
+    exp: iat + CHALLENGE_LIFETIME,
+  };
+
+  ApiResponse::Ok(LoginInitiateResponse {
+    challenge_signature: signed_claims.to_token(&state),
+    challenge_response,
+  })
+}
+
+/// Every failure before the signature is verified gives the same error, so
+/// decoy challenges can't be told apart from real ones here either.
+pub async fn finish_username_login(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  metadata: RequestMetadata,
+  Json(payload): Json<LoginFinalizeRequest>,
+) -> ApiResponse<LoginFinalizeResponse> {
+  let Some(signed_challenge) =
+    SignedUsernameLoginChallengeClaims::from_token(payload.challenge_signature, &state)
+  else {
+    return ApiResponse::Err(ApiErr::InvalidChallenge);
+  };
+
+  match consume_challenge(
+    &state,
+    &signed_challenge.challenge_id,
+    USERNAME_LOGIN_CHALLENGE,
+  )
+  .await
+  {
+    Ok(true) => {}
+    Ok(false) => return ApiResponse::Err(ApiErr::InvalidChallenge),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
+  let login_state = match UsernameLoginState::take(&state, &signed_challenge.challenge_id).await {
+    Ok(Some(login_state)) => login_state,
+    Ok(None) => return ApiResponse::Err(ApiErr::InvalidCredential),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  };
+
+  let Ok(user) = User::from_user_id(&state.pool, login_state.user_id).await else {
+    return ApiResponse::Err(ApiErr::UserDeleted);
+  };
+
+  if let Err(err) = check_user_login_lockout(&state, &user, &metadata).await {
+    return ApiResponse::Err(err);
+  }
+
+  let Ok(credential_vec) =
+    WebauthnCredential::from_credential_uuid(&state.pool, user.credential_uuid).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(credential) = credential_vec.into_iter().find(|x| {
+    BASE64_STANDARD
+      .decode(&x.credential_id)
+      .is_ok_and(|y| y == *payload.pk_credential.raw_id)
+  }) else {
+    return ApiResponse::Err(ApiErr::InvalidCredential);
+  };
+
+  let Ok(passkey) = serde_json::from_str::<Passkey>(&credential.serialized_passkey) else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  // locked passkeys were left out of the challenge, so they can't get this far
+  match finish_user_authentication(
+    &state,
+    &payload.pk_credential,
+    &login_state.auth,
+    &credential,
+  ) {
+    Ok(result) => {
+      // checked after the signature, so only the owner finds out
+      if user.is_suspended {
+        return ApiResponse::Err(ApiErr::UserSuspended);
+      }
+      complete_passkey_login(&state, metadata, user, credential, passkey, &result).await
+    }
+    Err(WebauthnError::CredentialPossibleCompromise) => {
+      lock_compromised_passkey(&state, &metadata, &user, credential).await
+    }
+    Err(_) => {
+      if let Err(err) = record_failed_user_login(&state, &user, &metadata).await {
+        return ApiResponse::Err(err);
+      }
+      ApiResponse::Err(ApiErr::InvalidCredential)
+    }
+  }
+}
+
+/// Asks a signed in user to use one of their passkeys again, for actions that
+/// need a recent one (see [`crate::user::SudoCtx`])
+pub async fn start_reauthentication(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  current_user: User,
+) -> ApiResponse<LoginInitiateResponse> {
+  let Ok(challenge_id) = issue_challenge(&state, REAUTHENTICATION_CHALLENGE).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(authentication) = start_user_authentication(&state, &current_user).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some((challenge_response, login_state)) = authentication else {
+    return ApiResponse::Err(ApiErr::Other(
+      "no_passkeys".to_string(),
+      "You need a passkey on your account to confirm it's you.".to_string(),
+    ));
+  };
+
+  if login_state.save(&state, &challenge_id).await.is_err() {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  let iat = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs();
+
+  let signed_claims = SignedUsernameLoginChallengeClaims {
+    challenge_id,
+    iat,
+    exp: iat + CHALLENGE_LIFETIME,
+  };
+
+  ApiResponse::Ok(LoginInitiateResponse {
+    challenge_signature: signed_claims.to_token(&state),
+    challenge_response,
+  })
+}
+
+/// Marks the current session as recently verified and returns an access token
+/// that says so. The session itself stays the same.
+pub async fn finish_reauthentication(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+  metadata: RequestMetadata,
+  Json(payload): Json<LoginFinalizeRequest>,
+) -> ApiResponse<ReauthenticationResponse> {
+  let Some(signed_challenge) =
+    SignedUsernameLoginChallengeClaims::from_token(payload.challenge_signature, &state)
+  else {
+    return ApiResponse::Err(ApiErr::InvalidChallenge);
+  };
+
+  match consume_challenge(
+    &state,
+    &signed_challenge.challenge_id,
+    REAUTHENTICATION_CHALLENGE,
+  )
+  .await
+  {
+    Ok(true) => {}
+    Ok(false) => return ApiResponse::Err(ApiErr::InvalidChallenge),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
+  let login_state = match UsernameLoginState::take(&state, &signed_challenge.challenge_id).await {
+    Ok(Some(login_state)) if login_state.user_id == current_user.id => login_state,
+    Ok(_) => return ApiResponse::Err(ApiErr::InvalidChallenge),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  };
+
+  if let Err(err) = check_user_login_lockout(&state, &current_user, &metadata).await {
+    return ApiResponse::Err(err);
+  }
+
+  let Ok(mut session) = UserSession::from_session_id(&state.pool, claims.session_id).await else {
+    return ApiResponse::Err(ApiErr::SessionExpired);
+  };
+
+  if session.user_id != current_user.id {
+    return ApiResponse::Err(ApiErr::SessionExpired);
+  }
+
+  let Ok(credential_vec) =
+    WebauthnCredential::from_credential_uuid(&state.pool, current_user.credential_uuid).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(mut credential) = credential_vec.into_iter().find(|x| {
+    BASE64_STANDARD
+      .decode(&x.credential_id)
+      .is_ok_and(|y| y == *payload.pk_credential.raw_id)
+  }) else {
+    return ApiResponse::Err(ApiErr::InvalidCredential);
+  };
+
+  let Ok(mut passkey) = serde_json::from_str::<Passkey>(&credential.serialized_passkey) else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let result = match finish_user_authentication(
+    &state,
+    &payload.pk_credential,
+    &login_state.auth,
+    &credential,
+  ) {
+    Ok(result) => result,
+    Err(WebauthnError::CredentialPossibleCompromise) => {
+      return lock_compromised_passkey(&state, &metadata, &current_user, credential).await;
+    }
+    Err(_) => {
+      if let Err(err) = record_failed_user_login(&state, &current_user, &metadata).await {
+        return ApiResponse::Err(err);
+      }
+      return ApiResponse::Err(ApiErr::InvalidCredential);
     }
-    Err(_) => ApiResponse::Err(ApiErr::Other(
-      "webauthn_error".to_string(),
-      "An unexpected webauthn passkey registration error occurred.".to_string(),
-    )),
+  };
+
+  if credential
+    .record_login(&state.pool, &mut passkey, &result)
+    .await
+    .is_err()
+  {
+    return ApiResponse::Err(ApiErr::InternalServerError);
   }
+
+  if session
+    .record_passkey_verification(&state.pool)
+    .await
+    .is_err()
+  {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  let access_claims = IdentityAccessClaims::create_for_session(&current_user, &session);
+  ApiResponse::Ok(ReauthenticationResponse {
+    access_token: access_claims.to_token(&state),
+  })
 }
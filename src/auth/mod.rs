@@ -11,11 +11,18 @@ use crate::{
   user::{AdminCtx, User},
 };
 
+pub mod attestation;
+pub mod challenge;
 pub mod credential;
+pub mod handoff;
 pub mod identity;
 pub mod login;
+pub mod recovery;
+pub mod recovery_code;
 pub mod register;
 pub mod session;
+pub mod signup;
+pub mod totp;
 
 #[derive(Serialize, Deserialize)]
 struct TestResponse {
@@ -62,6 +69,40 @@ pub fn router() -> Router<crate::AppState> {
       "/v1/auth/login/passkey/finalize",
       post(login::finish_passkey_login),
     )
+    .route(
+      "/v1/auth/login/username/initiate",
+      post(login::start_username_login),
+    )
+    .route(
+      "/v1/auth/login/username/finalize",
+      post(login::finish_username_login),
+    )
+    .route(
+      "/v1/auth/reauthenticate/initiate",
+      post(login::start_reauthentication),
+    )
+    .route(
+      "/v1/auth/reauthenticate/finalize",
+      post(login::finish_reauthentication),
+    )
+    .route("/v1/auth/recover", post(recovery::request_account_recovery))
+    .route(
+      "/v1/auth/recover/passkey/initiate",
+      post(recovery::start_account_recovery),
+    )
+    .route(
+      "/v1/auth/recover/passkey/finalize",
+      post(recovery::finish_account_recovery),
+    )
+    .route(
+      "/v1/auth/login/recovery-code",
+      post(recovery_code::login_with_recovery_code),
+    )
+    .route("/v1/auth/login/totp", post(totp::login_with_totp))
+    .route("/v1/auth/signup", post(signup::request_signup))
+    .route("/v1/auth/signup/confirm", post(signup::confirm_signup))
+    .route("/v1/auth/handoff", post(handoff::start_handoff))
+    .route("/v1/auth/handoff/poll", post(handoff::poll_handoff))
     .route("/v1/auth/refresh", post(identity::refresh_auth))
     .route("/v1/auth/logout", post(identity::logout_current_session))
 }
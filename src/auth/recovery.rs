@@ -0,0 +1,331 @@
+// Self-service account recovery for users who lost all of their passkeys. A
+// short-lived, single-use link is emailed to the user, which lets them enroll a
+// new passkey. Requesting a link never reveals whether the email belongs to an
+// account.
+
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Json, extract::State};
+use rand::distributions::{Alphanumeric, DistString};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::RegisterPublicKeyCredential;
+
+use crate::{
+  AppState,
+  auth::{
+    credential::WebauthnCredential,
+    register::{
+      AuthenticatorKind, RegistrationInitiateResponse, SignedChallengeClaims,
+      create_registration_challenge, save_registered_passkey,
+    },
+    session::UserSession,
+  },
+  ratelimit::{IpRateLimit, LoginRoutes, RateLimit, RegistrationRoutes, check_rate_limit},
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  smtp::{
+    get_frontend_link, get_origin, new_account_recovered_message, new_account_recovery_message,
+    send_mail,
+  },
+  user::{
+    User,
+    events::{ACCOUNT_RECOVERY_EVENT, SecurityEvent},
+  },
+  util::RequestMetadata,
+};
+
+/// How long a recovery link stays valid
+const RECOVERY_LINK_LIFETIME: u64 = 900;
+/// How many recovery links can be requested for one email per window
+const RECOVERY_RATE_LIMIT: RateLimit = RateLimit {
+  limit: 3,
+  window: 3600,
+};
+
+#[derive(Serialize, Deserialize)]
+struct RecoveryTokenData {
+  pub user_id: i32,
+  /// The link stops working if the account's email changes
+  pub email: String,
+  pub expires_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryRequest {
+  pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryInitiateRequest {
+  pub recovery_token: String,
+  #[serde(default)]
+  pub authenticator: AuthenticatorKind,
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryFinalizeRequest {
+  pub recovery_token: String,
+  pub challenge_signature: String,
+  pub pk_credential: RegisterPublicKeyCredential,
+  /// Removes every other passkey and signs out every session
+  #[serde(default)]
+  pub revoke_existing: bool,
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs()
+}
+
+impl RecoveryTokenData {
+  async fn from_token(
+    state: &AppState,
+    token: &str,
+  ) -> Result<Option<RecoveryTokenData>, Box<dyn Error>> {
+    let key = format!("account_recovery:{}", token);
+    let token_data: Option<String> = state.redis_connection.clone().get(key).await?;
+    match token_data {
+      Some(data) => Ok(Some(serde_json::from_str::<RecoveryTokenData>(
+        data.as_str(),
+      )?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Like [`RecoveryTokenData::from_token`], but deletes the token at the same
+  /// time so it can only ever be redeemed once.
+  async fn take_from_token(
+    state: &AppState,
+    token: &str,
+  ) -> Result<Option<RecoveryTokenData>, Box<dyn Error>> {
+    let key = format!("account_recovery:{}", token);
+    let token_data: Option<String> = state.redis_connection.clone().get_del(key).await?;
+    match token_data {
+      Some(data) => Ok(Some(serde_json::from_str::<RecoveryTokenData>(
+        data.as_str(),
+      )?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn save_to_token(&self, state: &AppState, token: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("account_recovery:{}", token);
+    let value = serde_json::to_string(self)?;
+    let lifetime = self.expires_at.saturating_sub(now()).max(1);
+    let _: () = state
+      .redis_connection
+      .clone()
+      .set_ex(key, value, lifetime)
+      .await?;
+    Ok(())
+  }
+}
+
+async fn send_recovery_link(state: AppState, email: String) {
+  let Ok(user) = User::from_email(&state.pool, email).await else {
+    return;
+  };
+
+  if user.is_suspended {
+    return;
+  }
+
+  let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+  let data = RecoveryTokenData {
+    user_id: user.id,
+    email: user.email.clone(),
+    expires_at: now() + RECOVERY_LINK_LIFETIME,
+  };
+  if data.save_to_token(&state, &token).await.is_err() {
+    tracing::error!("Failed to save account recovery token");
+    return;
+  }
+
+  let recovery_link = get_frontend_link(&state, &format!("/auth/recover?t={}", token));
+  let message = new_account_recovery_message(&user, recovery_link, get_origin(&state));
+  if send_mail(&state, message).await.is_err() {
+    tracing::error!("Failed to send account recovery email");
+  }
+}
+
+fn invalid_recovery_link() -> ApiErr {
+  ApiErr::Other(
+    "invalid_recovery_link".to_string(),
+    "This recovery link has expired or was already used. Please request another one.".to_string(),
+  )
+}
+
+/// Looks up the user a recovery token was issued for, making sure the token can
+/// still be used.
+async fn get_recovery_user(state: &AppState, data: &RecoveryTokenData) -> Result<User, ApiErr> {
+  if data.expires_at <= now() {
+    return Err(invalid_recovery_link());
+  }
+
+  let Ok(user) = User::from_user_id(&state.pool, data.user_id).await else {
+    return Err(ApiErr::UserDeleted);
+  };
+
+  if user.is_suspended {
+    return Err(ApiErr::UserSuspended);
+  }
+
+  if user.email != data.email {
+    return Err(invalid_recovery_link());
+  }
+
+  Ok(user)
+}
+
+/// Always succeeds (unless rate limited), whether or not the email belongs to
+/// an account. The lookup and email happen in the background so response times
+/// don't give it away either.
+pub async fn request_account_recovery(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  Json(payload): Json<RecoveryRequest>,
+) -> ApiResponse<EmptyResponse> {
+  let email = payload.email.trim().to_lowercase();
+  if email.is_empty() {
+    return ApiResponse::Err(ApiErr::Other(
+      "invalid_email".to_string(),
+      "Please enter the email address of your account.".to_string(),
+    ));
+  }
+
+  // unknown emails are counted the same way
+  let rate_limit_key = format!("account_recovery:email:{}", email);
+  if let Err(err) = check_rate_limit(&state, &rate_limit_key, &RECOVERY_RATE_LIMIT).await {
+    return ApiResponse::Err(err);
+  }
+
+  tokio::spawn(send_recovery_link(state, email));
+
+  ApiResponse::EmptyOk
+}
+
+pub async fn start_account_recovery(
+  State(state): State<AppState>,
+  _: IpRateLimit<RegistrationRoutes>,
+  Json(payload): Json<RecoveryInitiateRequest>,
+) -> ApiResponse<RegistrationInitiateResponse> {
+  let Ok(data) = RecoveryTokenData::from_token(&state, &payload.recovery_token).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(data) = data else {
+    return ApiResponse::Err(invalid_recovery_link());
+  };
+
+  let user = match get_recovery_user(&state, &data).await {
+    Ok(user) => user,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  match create_registration_challenge(&state, &user, payload.authenticator).await {
+    Ok(response) => ApiResponse::Ok(response),
+    Err(err) => ApiResponse::Err(err),
+  }
+}
+
+/// Enrolls the new passkey, which uses up the recovery link. The user has to
+/// sign in with it afterwards.
+pub async fn finish_account_recovery(
+  State(state): State<AppState>,
+  _: IpRateLimit<RegistrationRoutes>,
+  metadata: RequestMetadata,
+  Json(payload): Json<RecoveryFinalizeRequest>,
+) -> ApiResponse<EmptyResponse> {
+  let Some(signed_challenge) =
+    SignedChallengeClaims::from_token(payload.challenge_signature, &state)
+  else {
+    return ApiResponse::Err(ApiErr::InvalidChallenge);
+  };
+
+  // taken up front, so concurrent requests can't enroll several passkeys
+  let Ok(data) = RecoveryTokenData::take_from_token(&state, &payload.recovery_token).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(data) = data else {
+    return ApiResponse::Err(invalid_recovery_link());
+  };
+
+  let user = match get_recovery_user(&state, &data).await {
+    Ok(user) => user,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  let passkey = match save_registered_passkey(
+    &state,
+    &user,
+    signed_challenge,
+    &payload.pk_credential,
+    Some("Recovery Passkey".to_string()),
+  )
+  .await
+  {
+    Ok(passkey) => passkey,
+    Err(err) => {
+      // the link can be tried again if the passkey couldn't be saved
+      if data
+        .save_to_token(&state, &payload.recovery_token)
+        .await
+        .is_err()
+      {
+        tracing::error!("Failed to restore account recovery token");
+      }
+      return ApiResponse::Err(err);
+    }
+  };
+
+  if payload.revoke_existing {
+    let Ok(passkeys) =
+      WebauthnCredential::from_credential_uuid(&state.pool, user.credential_uuid).await
+    else {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    };
+
+    for existing in passkeys.iter().filter(|x| x.id != passkey.id) {
+      if existing.delete(&state.pool).await.is_err() {
+        return ApiResponse::Err(ApiErr::InternalServerError);
+      }
+    }
+
+    if UserSession::delete_sessions_for_user(&state.pool, user.id, None)
+      .await
+      .is_err()
+    {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    }
+  }
+
+  let description = match payload.revoke_existing {
+    true => "The account was recovered by email, other passkeys and sessions were revoked",
+    false => "The account was recovered by email",
+  };
+  if SecurityEvent::record(
+    &state.pool,
+    user.id,
+    ACCOUNT_RECOVERY_EVENT,
+    description.to_string(),
+    &metadata,
+  )
+  .await
+  .is_err()
+  {
+    tracing::error!("Failed to record account recovery event");
+  }
+
+  let message = new_account_recovered_message(&user, get_origin(&state), payload.revoke_existing);
+  if send_mail(&state, message).await.is_err() {
+    tracing::error!("Failed to send account recovered email");
+  }
+
+  ApiResponse::EmptyOk
+}
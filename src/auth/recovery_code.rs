@@ -0,0 +1,330 @@
+use std::{
+  error::Error,
+  sync::LazyLock,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use axum::{Json, extract::State};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use sqlx::PgPool;
+use tokio::task::spawn_blocking;
+
+use crate::{
+  AppState,
+  auth::{
+    identity::{IdentityAccessClaims, RECOVERY_CODE_METHOD},
+    session::UserSession,
+  },
+  ratelimit::{IpRateLimit, LoginRoutes, RateLimit, check_rate_limit},
+  response::{ApiErr, ApiResponse},
+  user::{
+    User,
+    events::{RECOVERY_CODE_USED_EVENT, SecurityEvent},
+  },
+  util::RequestMetadata,
+};
+
+/// How many codes are generated at once
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Per username, on top of the per-IP login limit
+const RECOVERY_CODE_LOGIN_RATE_LIMIT: RateLimit = RateLimit {
+  limit: 5,
+  window: 300,
+};
+
+/// Checked in place of missing codes, so every attempt costs the same number of
+/// hashes whether the user exists, or how many unused codes they have left
+static PLACEHOLDER_CODE_HASH: LazyLock<String> = LazyLock::new(|| {
+  let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+  Argon2::default()
+    .hash_password(normalize_code(&generate_code()).as_bytes(), &salt)
+    .expect("Failed to hash placeholder recovery code")
+    .to_string()
+});
+
+fn now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs() as i64
+}
+
+/// Codes are shown as two groups of five characters, but dashes, whitespace and
+/// case don't matter when they're entered.
+fn normalize_code(code: &str) -> String {
+  code
+    .chars()
+    .filter(|x| x.is_ascii_alphanumeric())
+    .map(|x| x.to_ascii_lowercase())
+    .collect()
+}
+
+fn generate_code() -> String {
+  let code = Alphanumeric
+    .sample_string(&mut rand::thread_rng(), 10)
+    .to_lowercase();
+  format!("{}-{}", &code[..5], &code[5..])
+}
+
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RecoveryCode {
+  #[serde_as(as = "DisplayFromStr")]
+  pub id: i64,
+  pub user_id: i32,
+  #[serde(skip)]
+  pub code_hash: String,
+  pub created_at: i64,
+  pub used_at: Option<i64>,
+}
+
+impl RecoveryCode {
+  pub async fn from_user_id(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<Vec<RecoveryCode>, Box<dyn Error>> {
+    let codes = sqlx::query_as!(
+      RecoveryCode,
+      r#"
+        SELECT id, user_id, code_hash, created_at, used_at FROM user_recovery_codes WHERE user_id = $1
+      "#,
+      user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(codes)
+  }
+
+  /// Replaces all of the user's codes with new ones. The plain codes are only
+  /// returned here, just the hashes are stored.
+  pub async fn regenerate_for_user(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<Vec<String>, Box<dyn Error>> {
+    let codes = (0..RECOVERY_CODE_COUNT)
+      .map(|_| generate_code())
+      .collect::<Vec<String>>();
+
+    let normalized_codes = codes.iter().map(|x| normalize_code(x)).collect::<Vec<_>>();
+    let code_hashes = spawn_blocking(move || {
+      normalized_codes
+        .iter()
+        .map(|code| {
+          let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+          Argon2::default()
+            .hash_password(code.as_bytes(), &salt)
+            .map(|x| x.to_string())
+        })
+        .collect::<Result<Vec<String>, _>>()
+    })
+    .await??;
+
+    let created_at = now();
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+      r#"
+        DELETE FROM user_recovery_codes WHERE user_id = $1
+      "#,
+      user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    for code_hash in code_hashes {
+      sqlx::query!(
+        r#"
+          INSERT INTO user_recovery_codes(user_id, code_hash, created_at) VALUES ($1, $2, $3)
+        "#,
+        user_id,
+        code_hash,
+        created_at
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(codes)
+  }
+
+  /// Checks `code` against every one of `unused_codes`, and the placeholder
+  /// hash for the rest of a full set, without stopping at a match
+  async fn find_matching_code(
+    unused_codes: Vec<RecoveryCode>,
+    code: &str,
+  ) -> Result<Option<i64>, Box<dyn Error>> {
+    let code = normalize_code(code);
+    let matching_id = spawn_blocking(move || {
+      let placeholders = RECOVERY_CODE_COUNT.saturating_sub(unused_codes.len());
+      let hashes = unused_codes
+        .iter()
+        .map(|x| (Some(x.id), x.code_hash.as_str()))
+        .chain((0..placeholders).map(|_| (None, PLACEHOLDER_CODE_HASH.as_str())));
+
+      let mut matching_id = None;
+      for (id, code_hash) in hashes {
+        let matches = PasswordHash::new(code_hash).is_ok_and(|hash| {
+          Argon2::default()
+            .verify_password(code.as_bytes(), &hash)
+            .is_ok()
+        });
+        if matches && matching_id.is_none() {
+          matching_id = id;
+        }
+      }
+      matching_id
+    })
+    .await?;
+    Ok(matching_id)
+  }
+
+  /// Does the same work as [`RecoveryCode::redeem`] for a user that doesn't
+  /// exist, so the response time doesn't give that away
+  pub async fn redeem_for_unknown_user(code: &str) -> Result<(), Box<dyn Error>> {
+    RecoveryCode::find_matching_code(Vec::new(), code).await?;
+    Ok(())
+  }
+
+  /// Finds the unused code matching `code` and marks it as used. Returns false
+  /// if there's no such code, or a concurrent request used it first.
+  pub async fn redeem(pool: &PgPool, user_id: i32, code: &str) -> Result<bool, Box<dyn Error>> {
+    let unused_codes = RecoveryCode::from_user_id(pool, user_id)
+      .await?
+      .into_iter()
+      .filter(|x| x.used_at.is_none())
+      .collect::<Vec<RecoveryCode>>();
+
+    let matching_id = RecoveryCode::find_matching_code(unused_codes, code).await?;
+
+    let Some(id) = matching_id else {
+      return Ok(false);
+    };
+
+    let result = sqlx::query!(
+      r#"
+        UPDATE user_recovery_codes SET used_at = $1 WHERE id = $2 AND used_at IS NULL
+      "#,
+      now(),
+      id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+  }
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryCodeLoginRequest {
+  pub username: String,
+  pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodeLoginResponse {
+  /// Can only be used to register a new passkey, and is never refreshed
+  pub access_token: String,
+  pub user: User,
+}
+
+/// Signs in with a recovery code, which starts a restricted session that can
+/// only register a new passkey. The session ends once a passkey is registered.
+pub async fn login_with_recovery_code(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  metadata: RequestMetadata,
+  Json(payload): Json<RecoveryCodeLoginRequest>,
+) -> ApiResponse<RecoveryCodeLoginResponse> {
+  // unknown users get the same error after the same amount of hashing, so this
+  // can't be used to find usernames
+  let invalid_code = ApiErr::Other(
+    "invalid_recovery_code".to_string(),
+    "This recovery code is not valid or was already used.".to_string(),
+  );
+
+  // unknown usernames are counted the same way
+  let rate_limit_key = format!(
+    "recovery_code_login:user:{}",
+    payload.username.to_lowercase()
+  );
+  if let Err(err) = check_rate_limit(&state, &rate_limit_key, &RECOVERY_CODE_LOGIN_RATE_LIMIT).await
+  {
+    return ApiResponse::Err(err);
+  }
+
+  let Ok(user) = User::from_username(&state.pool, payload.username).await else {
+    if RecoveryCode::redeem_for_unknown_user(&payload.code)
+      .await
+      .is_err()
+    {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    }
+    return ApiResponse::Err(invalid_code);
+  };
+
+  match RecoveryCode::redeem(&state.pool, user.id, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => return ApiResponse::Err(invalid_code),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
+  // checked after the code, so only its owner finds out the account is suspended
+  if user.is_suspended {
+    return ApiResponse::Err(ApiErr::UserSuspended);
+  }
+
+  // the refresh token is never handed out, so the session can't be extended
+  let Ok((_, session)) = UserSession::create_session(
+    &state.pool,
+    user.id,
+    0,
+    RECOVERY_CODE_METHOD,
+    metadata.clone(),
+  )
+  .await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  if SecurityEvent::record(
+    &state.pool,
+    user.id,
+    RECOVERY_CODE_USED_EVENT,
+    "A recovery code was used to sign in".to_string(),
+    &metadata,
+  )
+  .await
+  .is_err()
+  {
+    tracing::error!("Failed to record recovery code event");
+  }
+
+  let access_claims = IdentityAccessClaims::create_for_session(&user, &session);
+
+  ApiResponse::Ok(RecoveryCodeLoginResponse {
+    access_token: access_claims.to_token(&state),
+    user,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ignores_dashes_whitespace_and_case() {
+    assert_eq!(normalize_code("abcde-12345"), "abcde12345");
+    assert_eq!(normalize_code(" ABCDE 12345\n"), "abcde12345");
+    assert_eq!(normalize_code("aBcDe—1_2.3-4-5"), "abcde12345");
+  }
+
+  #[test]
+  fn generated_codes_normalize_without_the_dash() {
+    let code = generate_code();
+    assert_eq!(code.len(), 11);
+    assert_eq!(normalize_code(&code), code.replace('-', ""));
+  }
+}
@@ -7,18 +7,28 @@ use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use sqlx::types::Uuid;
 use webauthn_rs::prelude::{
-  CreationChallengeResponse, PasskeyRegistration, RegisterPublicKeyCredential,
+  AttestedPasskeyRegistration, CreationChallengeResponse, CredentialID, Passkey,
+  PasskeyRegistration, RegisterPublicKeyCredential, SecurityKeyRegistration, WebauthnError,
 };
+use webauthn_rs_proto::ResidentKeyRequirement;
 
 use crate::{
-  auth::credential::WebauthnCredential,
+  AppState,
+  auth::{
+    attestation::{AuthenticatorPolicy, CredentialAttestation, authenticator_not_allowed},
+    challenge::{CHALLENGE_LIFETIME, REGISTRATION_CHALLENGE, consume_challenge, issue_challenge},
+    credential::WebauthnCredential,
+  },
+  ratelimit::{IpRateLimit, RegistrationRoutes},
   response::{ApiErr, ApiResponse},
-  user::User,
+  user::{User, invitations::Invitation},
 };
 
 #[derive(Serialize, Deserialize)]
 pub struct RegistrationClaims {
   pub user_id: i32,
+  /// The invitation backing this link, which decides whether it's still usable
+  pub invitation_id: i64,
   pub iat: u64,
   pub exp: u64,
   // These values are mostly for the client, server should still check
@@ -29,15 +39,46 @@ pub struct RegistrationClaims {
 
 #[derive(Serialize, Deserialize)]
 pub struct SignedChallengeClaims {
+  /// Consumed when the passkey is saved, so the challenge can't be replayed
+  pub challenge_id: String,
   pub credential_uuid: Uuid,
   pub iat: u64,
   pub exp: u64,
-  pub reg: PasskeyRegistration,
+  pub reg: PendingRegistration,
+}
+
+/// Attested registrations are only used when the user's authenticators are
+/// restricted to an allow list, see [`crate::auth::attestation`]
+#[derive(Serialize, Deserialize)]
+pub enum PendingRegistration {
+  Passkey(PasskeyRegistration),
+  Attested(AttestedPasskeyRegistration),
+  /// Security key registrations check the attestation themselves when they're
+  /// started with a CA list
+  SecurityKey {
+    reg: SecurityKeyRegistration,
+    attested: bool,
+  },
+}
+
+/// What the user chose to register, which decides whether the credential has
+/// to be stored on the authenticator
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthenticatorKind {
+  /// Discoverable, so it can sign in without a username
+  #[default]
+  Passkey,
+  /// For security keys that can't store credentials, these can only be used
+  /// with username-first login
+  SecurityKey,
 }
 
 #[derive(Deserialize)]
 pub struct RegistrationInitiateRequest {
   pub registration_token: String,
+  #[serde(default)]
+  pub authenticator: AuthenticatorKind,
 }
 
 #[derive(Serialize)]
@@ -54,15 +95,16 @@ pub struct RegistrationFinalizeRequest {
 }
 
 impl RegistrationClaims {
-  pub fn new(user: &User) -> Self {
+  pub fn new(user: &User, invitation: &Invitation) -> Self {
     let iat = SystemTime::now()
       .duration_since(UNIX_EPOCH)
       .expect("Time went backwards lol")
       .as_secs();
     RegistrationClaims {
       user_id: user.id,
+      invitation_id: invitation.id,
       iat,
-      exp: iat + 86400,
+      exp: invitation.expires_at as u64,
       email: user.email.clone(),
       username: user.username.clone(),
       name: user.name.clone(),
@@ -73,7 +115,7 @@ impl RegistrationClaims {
     let decoded_key = &DecodingKey::from_secret(state.private_keys.registration_jwt_key.as_bytes());
     let decoded_token = match jsonwebtoken::decode::<RegistrationClaims>(
       &token,
-      &decoded_key,
+      decoded_key,
       &Validation::new(jsonwebtoken::Algorithm::HS256),
     ) {
       Ok(t) => t,
@@ -85,8 +127,6 @@ impl RegistrationClaims {
     Some(decoded_token.claims)
   }
 
-  // TODO: remove this when i actually use it
-  #[allow(dead_code)]
   pub fn to_token(&self, state: &crate::AppState) -> String {
     let encoding_key =
       &EncodingKey::from_secret(state.private_keys.registration_jwt_key.as_bytes());
@@ -101,7 +141,7 @@ impl SignedChallengeClaims {
     jsonwebtoken::encode(&Header::default(), &self, encoding_key).expect("Failed to encode key!")
   }
 
-  fn from_token(token: String, state: &crate::AppState) -> Option<SignedChallengeClaims> {
+  pub fn from_token(token: String, state: &crate::AppState) -> Option<SignedChallengeClaims> {
     let decoded_key =
       DecodingKey::from_secret(state.private_keys.passkey_registration_key.as_bytes());
     let decoded_token = match jsonwebtoken::decode::<SignedChallengeClaims>(
@@ -119,35 +159,125 @@ impl SignedChallengeClaims {
   }
 }
 
-pub async fn start_passkey_registration(
-  State(state): State<crate::AppState>,
-  Json(payload): Json<RegistrationInitiateRequest>,
-) -> ApiResponse<RegistrationInitiateResponse> {
-  let Some(registration) = RegistrationClaims::from_token(payload.registration_token, &state)
-  else {
-    return ApiResponse::Err(ApiErr::ExpiredRegistration);
+/// Looks up the user and invitation behind a registration link, making sure the
+/// link can still be used.
+async fn get_registration_user(
+  state: &AppState,
+  registration: &RegistrationClaims,
+) -> Result<(User, Invitation), ApiErr> {
+  let Ok(invitation) = Invitation::from_id(&state.pool, registration.invitation_id).await else {
+    return Err(ApiErr::ExpiredRegistration);
   };
 
+  if invitation.user_id != registration.user_id || !invitation.is_pending() {
+    return Err(ApiErr::ExpiredRegistration);
+  }
+
   let Ok(user) = User::from_user_id(&state.pool, registration.user_id).await else {
-    return ApiResponse::Err(ApiErr::UserDeleted);
+    return Err(ApiErr::UserDeleted);
   };
 
   if user.is_suspended {
-    return ApiResponse::Err(ApiErr::UserSuspended);
+    return Err(ApiErr::UserSuspended);
   }
 
   if user.email != registration.email {
-    return ApiResponse::Err(ApiErr::Other(
+    return Err(ApiErr::Other(
       "email_changed".to_string(),
       "The email associated with this account has changed, so this link is no longer valid."
         .to_string(),
     ));
   }
 
+  Ok((user, invitation))
+}
+
+pub async fn start_passkey_registration(
+  State(state): State<crate::AppState>,
+  _: IpRateLimit<RegistrationRoutes>,
+  Json(payload): Json<RegistrationInitiateRequest>,
+) -> ApiResponse<RegistrationInitiateResponse> {
+  let Some(registration) = RegistrationClaims::from_token(payload.registration_token, &state)
+  else {
+    return ApiResponse::Err(ApiErr::ExpiredRegistration);
+  };
+
+  let user = match get_registration_user(&state, &registration).await {
+    Ok((user, _)) => user,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  match create_registration_challenge(&state, &user, payload.authenticator).await {
+    Ok(response) => ApiResponse::Ok(response),
+    Err(err) => ApiResponse::Err(err),
+  }
+}
+
+pub async fn finish_passkey_registration(
+  State(state): State<crate::AppState>,
+  _: IpRateLimit<RegistrationRoutes>,
+  Json(payload): Json<RegistrationFinalizeRequest>,
+) -> ApiResponse<RegistrationInitiateResponse> {
+  let Some(registration) = RegistrationClaims::from_token(payload.registration_token, &state)
+  else {
+    return ApiResponse::Err(ApiErr::ExpiredRegistration);
+  };
+
+  let Some(signed_challenge) =
+    SignedChallengeClaims::from_token(payload.challenge_signature, &state)
+  else {
+    return ApiResponse::Err(ApiErr::InvalidChallenge);
+  };
+
+  let (user, mut invitation) = match get_registration_user(&state, &registration).await {
+    Ok(x) => x,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  // consumed up front, so concurrent requests can't register several passkeys
+  match invitation.consume(&state.pool).await {
+    Ok(true) => {}
+    Ok(false) => return ApiResponse::Err(ApiErr::ExpiredRegistration),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
+  match save_registered_passkey(
+    &state,
+    &user,
+    signed_challenge,
+    &payload.pk_credential,
+    None,
+  )
+  .await
+  {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(err) => {
+      // the link can be tried again if the passkey couldn't be saved
+      if invitation.release(&state.pool).await.is_err() {
+        tracing::error!("Failed to release invitation {}", invitation.id);
+      }
+      ApiResponse::Err(err)
+    }
+  }
+}
+
+/// Starts registering an additional passkey for `user`. Passkeys the user already
+/// has are excluded so the same authenticator isn't registered twice. If the
+/// user's authenticators are restricted to an allow list, the authenticator has
+/// to prove what it is with its attestation.
+pub async fn create_registration_challenge(
+  state: &AppState,
+  user: &User,
+  authenticator: AuthenticatorKind,
+) -> Result<RegistrationInitiateResponse, ApiErr> {
   let Ok(credential_vec) =
     WebauthnCredential::from_credential_uuid(&state.pool, user.credential_uuid).await
   else {
-    return ApiResponse::Err(ApiErr::InternalServerError);
+    return Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(policy) = AuthenticatorPolicy::for_user(state, user).await else {
+    return Err(ApiErr::InternalServerError);
   };
 
   let exclude_credentials = credential_vec
@@ -157,108 +287,211 @@ pub async fn start_passkey_registration(
     .map(HumanBinaryData::from)
     .collect();
 
-  let Ok((ccr, pkr)) = state.webauthn.start_passkey_registration(
-    user.credential_uuid,
-    &user.username,
-    &user.name,
-    Some(exclude_credentials),
-  ) else {
-    return ApiResponse::Err(ApiErr::Other(
+  let registration = match (authenticator, policy.get_ca_list(state)?) {
+    (AuthenticatorKind::SecurityKey, ca_list) => {
+      let attested = ca_list.is_some();
+      state
+        .webauthn
+        .start_securitykey_registration(
+          user.credential_uuid,
+          &user.username,
+          &user.name,
+          Some(exclude_credentials),
+          ca_list,
+          None,
+        )
+        .map(|(ccr, reg)| (ccr, PendingRegistration::SecurityKey { reg, attested }))
+    }
+    (AuthenticatorKind::Passkey, Some(ca_list)) => state
+      .webauthn
+      .start_attested_passkey_registration(
+        user.credential_uuid,
+        &user.username,
+        &user.name,
+        Some(exclude_credentials),
+        ca_list,
+        None,
+      )
+      .map(|(ccr, reg)| (ccr, PendingRegistration::Attested(reg))),
+    (AuthenticatorKind::Passkey, None) => state
+      .webauthn
+      .start_passkey_registration(
+        user.credential_uuid,
+        &user.username,
+        &user.name,
+        Some(exclude_credentials),
+      )
+      .map(|(ccr, reg)| (ccr, PendingRegistration::Passkey(reg))),
+  };
+
+  let Ok((mut ccr, reg)) = registration else {
+    return Err(ApiErr::Other(
       "webauthn_error".to_string(),
       "An unexpected webauthn passkey registration error occurred.".to_string(),
     ));
   };
 
+  // webauthn-rs always discourages resident keys, but discoverable login needs them
+  if let Some(selection) = ccr.public_key.authenticator_selection.as_mut() {
+    let require_resident_key = matches!(authenticator, AuthenticatorKind::Passkey);
+    selection.require_resident_key = require_resident_key;
+    selection.resident_key = Some(match require_resident_key {
+      true => ResidentKeyRequirement::Required,
+      false => ResidentKeyRequirement::Discouraged,
+    });
+  }
+
+  let Ok(challenge_id) = issue_challenge(state, REGISTRATION_CHALLENGE).await else {
+    return Err(ApiErr::InternalServerError);
+  };
+
   let iat = SystemTime::now()
     .duration_since(UNIX_EPOCH)
     .expect("Time went backwards lol")
     .as_secs();
 
   let signed_claims = SignedChallengeClaims {
+    challenge_id,
     credential_uuid: user.credential_uuid,
     iat,
-    exp: iat + 330,
-    reg: pkr,
+    exp: iat + CHALLENGE_LIFETIME,
+    reg,
   };
 
-  ApiResponse::Ok(RegistrationInitiateResponse {
-    challenge_signature: signed_claims.to_token(&state),
+  Ok(RegistrationInitiateResponse {
+    challenge_signature: signed_claims.to_token(state),
     challenge_response: ccr,
   })
 }
 
-pub async fn finish_passkey_registration(
-  State(state): State<crate::AppState>,
-  Json(payload): Json<RegistrationFinalizeRequest>,
-) -> ApiResponse<RegistrationInitiateResponse> {
-  let Some(registration) = RegistrationClaims::from_token(payload.registration_token, &state)
-  else {
-    return ApiResponse::Err(ApiErr::ExpiredRegistration);
-  };
-
-  let Some(signed_challenge) =
-    SignedChallengeClaims::from_token(payload.challenge_signature, &state)
-  else {
-    return ApiResponse::Err(ApiErr::InvalidChallenge);
-  };
-
-  let Ok(user) = User::from_user_id(&state.pool, registration.user_id).await else {
-    return ApiResponse::Err(ApiErr::UserDeleted);
-  };
-
-  if user.is_suspended {
-    return ApiResponse::Err(ApiErr::UserSuspended);
-  }
-
+/// Finishes a registration started with [`create_registration_challenge`] and
+/// saves the new passkey on `user`. The challenge is used up either way. Without
+/// a `name`, the passkey is named after its authenticator model if it's known.
+pub async fn save_registered_passkey(
+  state: &AppState,
+  user: &User,
+  signed_challenge: SignedChallengeClaims,
+  pk_credential: &RegisterPublicKeyCredential,
+  name: Option<String>,
+) -> Result<WebauthnCredential, ApiErr> {
   if signed_challenge.credential_uuid != user.credential_uuid {
-    return ApiResponse::Err(ApiErr::InvalidChallenge);
+    return Err(ApiErr::InvalidChallenge);
   }
 
-  if user.email != registration.email {
-    return ApiResponse::Err(ApiErr::Other(
-      "email_changed".to_string(),
-      "The email associated with this account has changed, so this link is no longer valid."
-        .to_string(),
-    ));
+  match consume_challenge(
+    state,
+    &signed_challenge.challenge_id,
+    REGISTRATION_CHALLENGE,
+  )
+  .await
+  {
+    Ok(true) => {}
+    Ok(false) => return Err(ApiErr::InvalidChallenge),
+    Err(_) => return Err(ApiErr::InternalServerError),
   }
 
   let Ok(credential_vec) =
     WebauthnCredential::from_credential_uuid(&state.pool, user.credential_uuid).await
   else {
-    return ApiResponse::Err(ApiErr::InternalServerError);
+    return Err(ApiErr::InternalServerError);
   };
 
   for credential in credential_vec {
-    if credential.credential_id == payload.pk_credential.id {
-      return ApiResponse::Err(ApiErr::Other(
+    if BASE64_STANDARD
+      .decode(&credential.credential_id)
+      .is_ok_and(|x| x == *pk_credential.raw_id)
+    {
+      return Err(ApiErr::Other(
         "credential_already_registered".to_string(),
         "This credential is already registered!".to_string(),
       ));
     }
   }
 
-  match state
-    .webauthn
-    .finish_passkey_registration(&payload.pk_credential, &signed_challenge.reg)
-  {
-    Ok(reg) => {
-      let mut db_cred = WebauthnCredential {
-        id: 0,
-        name: "Unnamed Passkey".to_string(),
-        credential_id: BASE64_STANDARD.encode(reg.cred_id()),
-        credential_uuid: user.credential_uuid,
-        serialized_passkey: serde_json::to_string(&reg).expect("Failed to serialize passkey"),
-      };
-
-      let Ok(_) = db_cred.create(&state.pool).await else {
-        return ApiResponse::Err(ApiErr::InternalServerError);
-      };
-
-      ApiResponse::EmptyOk
+  // both kinds are stored as their serialized credential
+  let (result, attestation_verified, is_security_key) = match &signed_challenge.reg {
+    PendingRegistration::Passkey(reg) => (
+      state
+        .webauthn
+        .finish_passkey_registration(pk_credential, reg)
+        .map(|x| (x.cred_id().clone(), serde_json::to_string(&x))),
+      false,
+      false,
+    ),
+    PendingRegistration::Attested(reg) => (
+      state
+        .webauthn
+        .finish_attested_passkey_registration(pk_credential, reg)
+        .map(Passkey::from)
+        .map(|x| (x.cred_id().clone(), serde_json::to_string(&x))),
+      true,
+      false,
+    ),
+    PendingRegistration::SecurityKey { reg, attested } => (
+      state
+        .webauthn
+        .finish_securitykey_registration(pk_credential, reg)
+        .map(|x| (x.cred_id().clone(), serde_json::to_string(&x))),
+      *attested,
+      true,
+    ),
+  };
+
+  let (cred_id, serialized): (CredentialID, _) = match result {
+    Ok(reg) => reg,
+    Err(
+      WebauthnError::AttestationNotSupported
+      | WebauthnError::AttestationNotVerifiable
+      | WebauthnError::AttestationTrustFailure
+      | WebauthnError::AttestationUntrustedAaguid
+      | WebauthnError::AttestationFormatMissingAaguid
+      | WebauthnError::AttestationChainNotTrusted(_),
+    ) => return Err(authenticator_not_allowed()),
+    Err(_) => {
+      return Err(ApiErr::Other(
+        "webauthn_error".to_string(),
+        "An unexpected webauthn passkey registration error occurred.".to_string(),
+      ));
     }
-    Err(_) => ApiResponse::Err(ApiErr::Other(
-      "webauthn_error".to_string(),
-      "An unexpected webauthn passkey registration error occurred.".to_string(),
-    )),
+  };
+
+  // checked again, the user's groups may have changed since the registration started
+  let Ok(policy) = AuthenticatorPolicy::for_user(state, user).await else {
+    return Err(ApiErr::InternalServerError);
+  };
+
+  let attestation = CredentialAttestation::from_credential(pk_credential);
+  if !policy.permits(&attestation, attestation_verified) {
+    return Err(authenticator_not_allowed());
   }
+
+  let name = name.unwrap_or_else(|| {
+    attestation
+      .aaguid
+      .and_then(|x| state.authenticators.metadata.get_description(&x))
+      .unwrap_or("Unnamed Passkey")
+      .to_string()
+  });
+
+  let mut db_cred = WebauthnCredential {
+    id: 0,
+    name,
+    credential_id: BASE64_STANDARD.encode(cred_id),
+    credential_uuid: user.credential_uuid,
+    serialized_passkey: serialized.expect("Failed to serialize passkey"),
+    last_used_at: None,
+    backup_eligible: false,
+    backup_state: false,
+    is_locked: false,
+    aaguid: attestation.aaguid,
+    attestation_format: attestation.format,
+    attestation_verified,
+    is_security_key,
+  };
+
+  let Ok(_) = db_cred.create(&state.pool).await else {
+    return Err(ApiErr::InternalServerError);
+  };
+
+  Ok(db_cred)
 }
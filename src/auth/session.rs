@@ -1,4 +1,7 @@
-use std::error::Error;
+use std::{
+  error::Error,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use argon2::{Argon2, PasswordHasher, password_hash::SaltString};
 use rand::distributions::{Alphanumeric, DistString};
@@ -7,6 +10,22 @@ use serde_with::{DisplayFromStr, serde_as};
 use sqlx::PgPool;
 use tokio::task::spawn_blocking;
 
+use crate::{
+  AppSessionLifetimes, AppState,
+  auth::identity::{HANDOFF_METHOD, IMPERSONATION_METHOD, PASSKEY_METHOD},
+  util::RequestMetadata,
+};
+
+/// How often expired sessions are purged from the database
+const SESSION_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+fn now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs() as i64
+}
+
 #[serde_as]
 #[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct UserSession {
@@ -15,7 +34,21 @@ pub struct UserSession {
   pub user_id: i32,
   #[serde(skip)]
   pub refresh_hash: String,
+  /// 0 for sessions that weren't started with a passkey
   pub webauthn_id: i32,
+  /// How the session was started, see the methods in [`crate::auth::identity`]
+  pub method: String,
+  pub created_at: i64,
+  pub last_refreshed_at: i64,
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+  #[serde(skip)]
+  pub refresh_generation: i32,
+  /// When the user last proved it's them with a passkey in this session, either
+  /// by signing in with one or by re-authenticating
+  pub passkey_verified_at: Option<i64>,
+  /// The admin acting as the user, for impersonation sessions
+  pub impersonator_id: Option<i32>,
 }
 
 impl UserSession {
@@ -27,7 +60,7 @@ impl UserSession {
       UserSession,
       r#"
         SELECT 
-          session_id, user_id, refresh_hash, webauthn_id
+          session_id, user_id, refresh_hash, webauthn_id, method, created_at, last_refreshed_at, user_agent, ip_address, refresh_generation, passkey_verified_at, impersonator_id
         FROM user_sessions WHERE user_id = $1
       "#,
       user_id
@@ -45,7 +78,7 @@ impl UserSession {
       UserSession,
       r#"
         SELECT 
-          session_id, user_id, refresh_hash, webauthn_id
+          session_id, user_id, refresh_hash, webauthn_id, method, created_at, last_refreshed_at, user_agent, ip_address, refresh_generation, passkey_verified_at, impersonator_id
         FROM user_sessions WHERE session_id = $1
       "#,
       session_id
@@ -59,6 +92,38 @@ impl UserSession {
     pool: &PgPool,
     user_id: i32,
     webauthn_id: i32,
+    method: &str,
+    metadata: RequestMetadata,
+  ) -> Result<(String, UserSession), Box<dyn Error>> {
+    UserSession::insert_session(pool, user_id, webauthn_id, method, None, metadata).await
+  }
+
+  /// A session for `impersonator_id` to act as the user, see
+  /// [`crate::user::impersonation`]
+  pub async fn create_impersonation_session(
+    pool: &PgPool,
+    user_id: i32,
+    impersonator_id: i32,
+    metadata: RequestMetadata,
+  ) -> Result<(String, UserSession), Box<dyn Error>> {
+    UserSession::insert_session(
+      pool,
+      user_id,
+      0,
+      IMPERSONATION_METHOD,
+      Some(impersonator_id),
+      metadata,
+    )
+    .await
+  }
+
+  async fn insert_session(
+    pool: &PgPool,
+    user_id: i32,
+    webauthn_id: i32,
+    method: &str,
+    impersonator_id: Option<i32>,
+    metadata: RequestMetadata,
   ) -> Result<(String, UserSession), Box<dyn Error>> {
     // NOTE: if we ever support concurrent servers in the future, we need to pass an "instance ID"
     // from an environment variable in here to avoid conflicts.
@@ -75,22 +140,38 @@ impl UserSession {
     })
     .await??;
 
+    let created_at = now();
     let session = UserSession {
       session_id,
       user_id,
       refresh_hash,
       webauthn_id,
+      method: method.to_string(),
+      created_at,
+      last_refreshed_at: created_at,
+      user_agent: metadata.user_agent,
+      ip_address: metadata.ip_address,
+      refresh_generation: 0,
+      passkey_verified_at: (method == PASSKEY_METHOD).then_some(created_at),
+      impersonator_id,
     };
 
     sqlx::query!(
       r#"
-        INSERT INTO user_sessions(session_id, user_id, refresh_hash, webauthn_id)
-        VALUES ($1, $2, $3, $4)
+        INSERT INTO user_sessions(session_id, user_id, refresh_hash, webauthn_id, method, created_at, last_refreshed_at, user_agent, ip_address, passkey_verified_at, impersonator_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
       "#,
       session.session_id,
       session.user_id,
       session.refresh_hash,
-      session.webauthn_id
+      session.webauthn_id,
+      session.method,
+      session.created_at,
+      session.last_refreshed_at,
+      session.user_agent,
+      session.ip_address,
+      session.passkey_verified_at,
+      session.impersonator_id
     )
     .execute(pool)
     .await?;
@@ -109,21 +190,51 @@ impl UserSession {
     })
     .await??;
 
-    sqlx::query!(
+    let last_refreshed_at = now();
+    let refresh_generation = self.refresh_generation + 1;
+
+    // the generation check makes sure two concurrent refreshes can't both win
+    let result = sqlx::query!(
       r#"
-        UPDATE user_sessions SET refresh_hash = $1 WHERE session_id = $2
+        UPDATE user_sessions SET refresh_hash = $1, last_refreshed_at = $2, refresh_generation = $3
+        WHERE session_id = $4 AND refresh_generation = $5
       "#,
       refresh_hash,
-      self.session_id
+      last_refreshed_at,
+      refresh_generation,
+      self.session_id,
+      self.refresh_generation
     )
     .execute(pool)
     .await?;
 
+    if result.rows_affected() == 0 {
+      return Err("session was refreshed concurrently".into());
+    }
+
     self.refresh_hash = refresh_hash;
+    self.last_refreshed_at = last_refreshed_at;
+    self.refresh_generation = refresh_generation;
 
     Ok(refresh_token)
   }
 
+  /// Called after the user re-authenticated with a passkey
+  pub async fn record_passkey_verification(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let passkey_verified_at = now();
+    sqlx::query!(
+      r#"
+        UPDATE user_sessions SET passkey_verified_at = $1 WHERE session_id = $2
+      "#,
+      passkey_verified_at,
+      self.session_id
+    )
+    .execute(pool)
+    .await?;
+    self.passkey_verified_at = Some(passkey_verified_at);
+    Ok(())
+  }
+
   pub async fn delete_session(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
     sqlx::query!(
       r#"
@@ -135,6 +246,59 @@ impl UserSession {
     .await?;
     Ok(())
   }
+  /// Whether the session has gone unused for too long, or has been kept alive
+  /// for longer than it is allowed to
+  pub fn is_expired(&self, lifetimes: &AppSessionLifetimes) -> bool {
+    let absolute = match self.method.as_str() {
+      HANDOFF_METHOD => lifetimes.handoff.min(lifetimes.absolute),
+      IMPERSONATION_METHOD => lifetimes.impersonation.min(lifetimes.absolute),
+      _ => lifetimes.absolute,
+    };
+    let now = now();
+    self.last_refreshed_at + lifetimes.idle as i64 <= now
+      || self.created_at + absolute as i64 <= now
+  }
+
+  pub async fn delete_expired_sessions(
+    pool: &PgPool,
+    lifetimes: &AppSessionLifetimes,
+  ) -> Result<u64, Box<dyn Error>> {
+    let now = now();
+    let result = sqlx::query!(
+      r#"
+        DELETE FROM user_sessions
+        WHERE last_refreshed_at <= $1 OR created_at <= $2
+          OR (method = $3 AND created_at <= $4) OR (method = $5 AND created_at <= $6)
+      "#,
+      now - lifetimes.idle as i64,
+      now - lifetimes.absolute as i64,
+      HANDOFF_METHOD,
+      now - lifetimes.handoff as i64,
+      IMPERSONATION_METHOD,
+      now - lifetimes.impersonation as i64
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+  }
+
+  /// Signs the user out everywhere, except for `keep_session_id` if given
+  pub async fn delete_sessions_for_user(
+    pool: &PgPool,
+    user_id: i32,
+    keep_session_id: Option<i64>,
+  ) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        DELETE FROM user_sessions WHERE user_id = $1 AND session_id IS DISTINCT FROM $2
+      "#,
+      user_id,
+      keep_session_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
   /*
     pub async fn update(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
       sqlx::query!(
@@ -148,3 +312,17 @@ impl UserSession {
     }
   */
 }
+
+/// Runs forever, periodically deleting expired sessions. Expired sessions can't
+/// be refreshed anyway, this just keeps them from piling up.
+pub async fn purge_expired_sessions_job(state: AppState) {
+  let mut interval = tokio::time::interval(SESSION_PURGE_INTERVAL);
+  loop {
+    interval.tick().await;
+    match UserSession::delete_expired_sessions(&state.pool, &state.session_lifetimes).await {
+      Ok(0) => {}
+      Ok(count) => tracing::info!("Purged {} expired sessions", count),
+      Err(e) => tracing::warn!("Failed to purge expired sessions: {e}"),
+    }
+  }
+}
@@ -0,0 +1,315 @@
+// Self-service signup, which is off unless SIGNUP_ENABLED is set. People can
+// sign up with an email on one of the allowed domains, or with an invite code
+// from an admin. The account is only created once the emailed link is opened,
+// so its email is always verified before a passkey can be enrolled. Requesting
+// a link always looks the same, whether the username or email is taken or not.
+
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Json, extract::State};
+use lettre::Address;
+use rand::distributions::{Alphanumeric, DistString};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+use crate::{
+  AppState,
+  auth::register::RegistrationClaims,
+  group::IdentityGroup,
+  ratelimit::{IpRateLimit, RateLimit, RegistrationRoutes, check_rate_limit},
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  smtp::{
+    get_frontend_link, get_origin, new_account_exists_message, new_signup_message, send_mail,
+  },
+  user::{User, invitations::Invitation, invite_codes::InviteCode},
+  util::UniqueConstraintViolation,
+};
+
+/// How long a signup link stays valid
+const SIGNUP_LINK_LIFETIME: u64 = 86400;
+/// How many signup links can be sent to one email per window
+const SIGNUP_RATE_LIMIT: RateLimit = RateLimit {
+  limit: 3,
+  window: 3600,
+};
+
+/// Everything needed to create the account once the email is confirmed
+#[derive(Serialize, Deserialize)]
+struct PendingSignup {
+  pub email: String,
+  pub username: String,
+  pub name: String,
+  /// The invite code is only used up when the account is created
+  pub invite_code_id: Option<i64>,
+  pub expires_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct SignupRequest {
+  pub email: String,
+  pub username: String,
+  pub name: String,
+  /// Not needed if the email is on an allowed domain
+  #[serde(default)]
+  pub invite_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SignupConfirmRequest {
+  pub signup_token: String,
+}
+
+#[derive(Serialize)]
+pub struct SignupConfirmResponse {
+  /// Used like a registration link to enroll the first passkey
+  pub registration_token: String,
+  pub user: User,
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs()
+}
+
+impl PendingSignup {
+  /// Deletes the token at the same time, so it can only ever be used once
+  async fn take_from_token(
+    state: &AppState,
+    token: &str,
+  ) -> Result<Option<PendingSignup>, Box<dyn Error>> {
+    let key = format!("signup:{}", token);
+    let token_data: Option<String> = state.redis_connection.clone().get_del(key).await?;
+    match token_data {
+      Some(data) => Ok(Some(serde_json::from_str::<PendingSignup>(data.as_str())?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn save_to_token(&self, state: &AppState, token: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("signup:{}", token);
+    let value = serde_json::to_string(self)?;
+    let _: () = state
+      .redis_connection
+      .clone()
+      .set_ex(key, value, SIGNUP_LINK_LIFETIME)
+      .await?;
+    Ok(())
+  }
+}
+
+fn invalid_invite_code() -> ApiErr {
+  ApiErr::Other(
+    "invalid_invite_code".to_string(),
+    "This invite code is not valid or can't be used anymore.".to_string(),
+  )
+}
+
+fn invalid_signup_link() -> ApiErr {
+  ApiErr::Other(
+    "invalid_signup_link".to_string(),
+    "This signup link has expired or was already used. Please sign up again.".to_string(),
+  )
+}
+
+fn is_allowed_email_domain(state: &AppState, email: &str) -> bool {
+  email.rsplit_once('@').is_some_and(|(_, domain)| {
+    state
+      .signup
+      .allowed_email_domains
+      .contains(&domain.to_lowercase())
+  })
+}
+
+/// Emails on allowed domains don't need an invite code, everyone else does
+async fn check_signup_allowed(
+  state: &AppState,
+  email: &str,
+  invite_code: Option<&str>,
+) -> Result<Option<InviteCode>, ApiErr> {
+  match invite_code.map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    Some(code) => match InviteCode::from_code(&state.pool, code).await {
+      Ok(invite_code) if invite_code.is_usable() => Ok(Some(invite_code)),
+      _ => Err(invalid_invite_code()),
+    },
+    None if is_allowed_email_domain(state, email) => Ok(None),
+    None => Err(ApiErr::Other(
+      "invite_code_required".to_string(),
+      "You need an invite code to sign up with this email address.".to_string(),
+    )),
+  }
+}
+
+/// Sends a link to confirm the email. Nothing is created until it's opened, and
+/// the username is only checked then. If the email is already taken, its owner
+/// is told instead.
+pub async fn request_signup(
+  State(state): State<AppState>,
+  _: IpRateLimit<RegistrationRoutes>,
+  Json(payload): Json<SignupRequest>,
+) -> ApiResponse<EmptyResponse> {
+  if !state.signup.enabled {
+    return ApiResponse::Err(ApiErr::Other(
+      "signup_disabled".to_string(),
+      "Signing up is disabled on this server. Please ask an administrator for an account."
+        .to_string(),
+    ));
+  }
+
+  if state.mailer.is_none() {
+    return ApiResponse::Err(ApiErr::Other(
+      "mail_disabled".to_string(),
+      "Signing up isn't possible since this server can't send emails.".to_string(),
+    ));
+  }
+
+  let email = payload.email.trim().to_string();
+  let username = payload.username.trim().to_string();
+  let name = payload.name.trim().to_string();
+
+  if email.parse::<Address>().is_err() {
+    return ApiResponse::Err(ApiErr::Other(
+      "invalid_email".to_string(),
+      "Please enter a valid email address.".to_string(),
+    ));
+  }
+
+  if username.is_empty() || name.is_empty() {
+    return ApiResponse::Err(ApiErr::Other(
+      "missing_details".to_string(),
+      "Please enter a username and your name.".to_string(),
+    ));
+  }
+
+  let invite_code = match check_signup_allowed(&state, &email, payload.invite_code.as_deref()).await
+  {
+    Ok(invite_code) => invite_code,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  let rate_limit_key = format!("signup:email:{}", email.to_lowercase());
+  if let Err(err) = check_rate_limit(&state, &rate_limit_key, &SIGNUP_RATE_LIMIT).await {
+    return ApiResponse::Err(err);
+  }
+
+  let existing_user = User::from_email(&state.pool, email.clone()).await.ok();
+  if let Some(user) = existing_user {
+    let message = new_account_exists_message(&user, get_origin(&state));
+    if send_mail(&state, message).await.is_err() {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    }
+    return ApiResponse::EmptyOk;
+  }
+
+  let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+  let pending = PendingSignup {
+    email: email.clone(),
+    username: username.clone(),
+    name: name.clone(),
+    invite_code_id: invite_code.map(|x| x.id),
+    expires_at: now() + SIGNUP_LINK_LIFETIME,
+  };
+  if pending.save_to_token(&state, &token).await.is_err() {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  let signup_link = get_frontend_link(&state, &format!("/auth/signup/confirm?t={}", token));
+  let message = new_signup_message(email, name, username, signup_link, get_origin(&state));
+  if send_mail(&state, message).await.is_err() {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  ApiResponse::EmptyOk
+}
+
+/// Creates the account from the emailed link and adds it to the default groups.
+/// The returned registration token enrolls its first passkey.
+pub async fn confirm_signup(
+  State(state): State<AppState>,
+  _: IpRateLimit<RegistrationRoutes>,
+  Json(payload): Json<SignupConfirmRequest>,
+) -> ApiResponse<SignupConfirmResponse> {
+  let Ok(pending) = PendingSignup::take_from_token(&state, &payload.signup_token).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(pending) = pending else {
+    return ApiResponse::Err(invalid_signup_link());
+  };
+
+  if pending.expires_at <= now() || !state.signup.enabled {
+    return ApiResponse::Err(invalid_signup_link());
+  }
+
+  // the invite code or domain has to still be good when the account is created
+  let mut invite_code = None;
+  match pending.invite_code_id {
+    Some(invite_code_id) => {
+      let Ok(mut code) = InviteCode::from_id(&state.pool, invite_code_id).await else {
+        return ApiResponse::Err(invalid_invite_code());
+      };
+      match code.consume(&state.pool).await {
+        Ok(true) => invite_code = Some(code),
+        Ok(false) => return ApiResponse::Err(invalid_invite_code()),
+        Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+      }
+    }
+    None if is_allowed_email_domain(&state, &pending.email) => {}
+    None => return ApiResponse::Err(invalid_signup_link()),
+  }
+
+  let mut user = User {
+    id: 0,
+    email: pending.email,
+    username: pending.username,
+    name: pending.name,
+    is_suspended: false,
+    credential_uuid: Uuid::new_v4(),
+    is_admin: false,
+    email_verified: true,
+  };
+
+  let created = match user.create(&state.pool).await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(match UniqueConstraintViolation::from(err) {
+      Some(violation) => match violation.constraint_name.as_str() {
+        "users_username_key" => ApiErr::UsernameExists,
+        "users_email_key" => ApiErr::EmailExists,
+        _ => ApiErr::InternalServerError,
+      },
+      None => ApiErr::InternalServerError,
+    }),
+  };
+
+  if let Err(err) = created {
+    if let Some(mut code) = invite_code
+      && code.release(&state.pool).await.is_err()
+    {
+      tracing::error!("Failed to release invite code use");
+    }
+    return ApiResponse::Err(err);
+  }
+
+  let Ok(groups) = IdentityGroup::get_signup_defaults(&state.pool).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+  for group in groups {
+    if group.add_member(&state.pool, user.id).await.is_err() {
+      tracing::error!("Failed to add new user to default group {}", group.slug);
+    }
+  }
+
+  let Ok(invitation) = Invitation::create(&state.pool, user.id, None).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  ApiResponse::Ok(SignupConfirmResponse {
+    registration_token: RegistrationClaims::new(&user, &invitation).to_token(&state),
+    user,
+  })
+}
@@ -0,0 +1,440 @@
+// TOTP (RFC 6238) as an alternative to passkeys, for users on devices where
+// passkeys are blocked. Secrets are encrypted at rest with AES-256-GCM. Whether
+// a user may use TOTP is decided by the totp_policy of their groups.
+
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{Nonce, aead::Aead};
+use axum::{Json, extract::State};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::PgPool;
+
+use crate::{
+  AppState,
+  auth::{
+    identity::{IdentityAccessClaims, IdentityRefreshClaims, TOTP_METHOD},
+    session::UserSession,
+  },
+  group::IdentityGroup,
+  ratelimit::{IpRateLimit, LoginRoutes, RateLimit, check_rate_limit},
+  response::{ApiErr, ApiResponse},
+  user::User,
+  util::RequestMetadata,
+};
+
+/// Group members may use TOTP, unless another of their groups forbids it
+pub const TOTP_POLICY_ALLOWED: &str = "allowed";
+/// Group members may never use TOTP
+pub const TOTP_POLICY_FORBIDDEN: &str = "forbidden";
+
+const TOTP_STEP: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// How many steps before and after the current one are accepted, to allow for
+/// clock drift
+const TOTP_ALLOWED_DRIFT: u64 = 1;
+const TOTP_SECRET_LENGTH: usize = 20;
+const TOTP_NONCE_LENGTH: usize = 12;
+
+/// Attempts are counted per username, since six digits are easy to guess
+const TOTP_LOGIN_RATE_LIMIT: RateLimit = RateLimit {
+  limit: 5,
+  window: 300,
+};
+
+fn now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs() as i64
+}
+
+/// Users can use TOTP if at least one of their groups allows it and none of
+/// them forbid it
+pub fn is_totp_allowed(groups: &[IdentityGroup]) -> bool {
+  let policies = groups
+    .iter()
+    .filter_map(|x| x.totp_policy.as_deref())
+    .collect::<Vec<&str>>();
+  policies.contains(&TOTP_POLICY_ALLOWED) && !policies.contains(&TOTP_POLICY_FORBIDDEN)
+}
+
+pub fn generate_secret() -> Vec<u8> {
+  let mut secret = vec![0u8; TOTP_SECRET_LENGTH];
+  rand::thread_rng().fill_bytes(&mut secret);
+  secret
+}
+
+/// The base32 form authenticator apps expect
+pub fn encode_secret(secret: &[u8]) -> String {
+  BASE32_NOPAD.encode(secret)
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+  let mut mac =
+    <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC can take keys of any size");
+  mac.update(&counter.to_be_bytes());
+  let result = mac.finalize().into_bytes();
+
+  // dynamic truncation (RFC 4226 section 5.3)
+  let offset = (result[result.len() - 1] & 0x0f) as usize;
+  let code = u32::from_be_bytes([
+    result[offset] & 0x7f,
+    result[offset + 1],
+    result[offset + 2],
+    result[offset + 3],
+  ]);
+  code % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Returns the time step `code` is valid for, if any
+pub fn verify_code(secret: &[u8], code: &str) -> Option<i64> {
+  verify_code_at_step(secret, code, now() as u64 / TOTP_STEP)
+}
+
+fn verify_code_at_step(secret: &[u8], code: &str, current_step: u64) -> Option<i64> {
+  let code = code.trim();
+  if code.len() != TOTP_DIGITS as usize {
+    return None;
+  }
+  let code = code.parse::<u32>().ok()?;
+
+  (current_step.saturating_sub(TOTP_ALLOWED_DRIFT)..=current_step + TOTP_ALLOWED_DRIFT)
+    .find(|step| hotp(secret, *step) == code)
+    .map(|step| step as i64)
+}
+
+pub fn encrypt_secret(state: &AppState, secret: &[u8]) -> Result<String, Box<dyn Error>> {
+  let mut nonce = [0u8; TOTP_NONCE_LENGTH];
+  rand::thread_rng().fill_bytes(&mut nonce);
+
+  let ciphertext = state
+    .private_keys
+    .totp_cipher
+    .encrypt(&Nonce::from(nonce), secret)
+    .map_err(|_| "failed to encrypt TOTP secret")?;
+
+  let mut encrypted = nonce.to_vec();
+  encrypted.extend(ciphertext);
+  Ok(BASE64_STANDARD.encode(encrypted))
+}
+
+pub fn decrypt_secret(state: &AppState, encrypted: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+  let encrypted = BASE64_STANDARD.decode(encrypted)?;
+  if encrypted.len() <= TOTP_NONCE_LENGTH {
+    return Err("encrypted TOTP secret is too short".into());
+  }
+
+  let (nonce, ciphertext) = encrypted.split_at(TOTP_NONCE_LENGTH);
+  let nonce: [u8; TOTP_NONCE_LENGTH] = nonce.try_into()?;
+  let secret = state
+    .private_keys
+    .totp_cipher
+    .decrypt(&Nonce::from(nonce), ciphertext)
+    .map_err(|_| "failed to decrypt TOTP secret")?;
+  Ok(secret)
+}
+
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TotpCredential {
+  pub user_id: i32,
+  #[serde(skip)]
+  pub encrypted_secret: String,
+  pub is_confirmed: bool,
+  pub created_at: i64,
+  #[serde(skip)]
+  pub last_used_step: Option<i64>,
+}
+
+impl TotpCredential {
+  pub async fn from_user_id(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<Option<TotpCredential>, Box<dyn Error>> {
+    let credential = sqlx::query_as!(
+      TotpCredential,
+      r#"
+        SELECT user_id, encrypted_secret, is_confirmed, created_at, last_used_step
+        FROM user_totp_credentials WHERE user_id = $1
+      "#,
+      user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(credential)
+  }
+
+  /// Saves a new, unconfirmed secret for the user, replacing any previous one
+  pub async fn save_pending(
+    pool: &PgPool,
+    user_id: i32,
+    encrypted_secret: String,
+  ) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        INSERT INTO user_totp_credentials(user_id, encrypted_secret, is_confirmed, created_at) VALUES ($1, $2, FALSE, $3)
+        ON CONFLICT (user_id) DO UPDATE SET encrypted_secret = EXCLUDED.encrypted_secret, is_confirmed = FALSE, created_at = EXCLUDED.created_at, last_used_step = NULL
+      "#,
+      user_id,
+      encrypted_secret,
+      now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn confirm(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        UPDATE user_totp_credentials SET is_confirmed = TRUE WHERE user_id = $1
+      "#,
+      self.user_id
+    )
+    .execute(pool)
+    .await?;
+    self.is_confirmed = true;
+    Ok(())
+  }
+
+  /// Marks `step` as used. Returns false if a code for this or a later step was
+  /// already accepted, which means the code is being replayed.
+  pub async fn record_step(&mut self, pool: &PgPool, step: i64) -> Result<bool, Box<dyn Error>> {
+    let result = sqlx::query!(
+      r#"
+        UPDATE user_totp_credentials SET last_used_step = $1
+        WHERE user_id = $2 AND (last_used_step IS NULL OR last_used_step < $1)
+      "#,
+      step,
+      self.user_id
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+      return Ok(false);
+    }
+
+    self.last_used_step = Some(step);
+    Ok(true)
+  }
+
+  /// Also signs out every session that was started with TOTP
+  pub async fn delete(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+      r#"
+        DELETE FROM user_sessions WHERE user_id = $1 AND method = $2
+      "#,
+      self.user_id,
+      TOTP_METHOD
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+      r#"
+        DELETE FROM user_totp_credentials WHERE user_id = $1
+      "#,
+      self.user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+  }
+
+  /// Checks `code` against this secret and uses up its time step
+  pub async fn verify_and_record(
+    &mut self,
+    state: &AppState,
+    code: &str,
+  ) -> Result<bool, Box<dyn Error>> {
+    let secret = decrypt_secret(state, &self.encrypted_secret)?;
+    let Some(step) = verify_code(&secret, code) else {
+      return Ok(false);
+    };
+    self.record_step(&state.pool, step).await
+  }
+}
+
+#[derive(Deserialize)]
+pub struct TotpLoginRequest {
+  pub username: String,
+  pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpLoginResponse {
+  pub access_token: String,
+  pub refresh_token: String,
+  pub session: UserSession,
+  pub user: User,
+}
+
+pub async fn login_with_totp(
+  State(state): State<AppState>,
+  _: IpRateLimit<LoginRoutes>,
+  metadata: RequestMetadata,
+  Json(payload): Json<TotpLoginRequest>,
+) -> ApiResponse<TotpLoginResponse> {
+  // unknown users get the same error, so this can't be used to find usernames
+  let invalid_code = ApiErr::Other(
+    "invalid_totp_code".to_string(),
+    "This code is not valid. Check the time on your device and try again.".to_string(),
+  );
+
+  // unknown usernames are counted the same way
+  let rate_limit_key = format!("totp_login:user:{}", payload.username.to_lowercase());
+  if let Err(err) = check_rate_limit(&state, &rate_limit_key, &TOTP_LOGIN_RATE_LIMIT).await {
+    return ApiResponse::Err(err);
+  }
+
+  let Ok(user) = User::from_username(&state.pool, payload.username).await else {
+    return ApiResponse::Err(invalid_code);
+  };
+
+  let Ok(credential) = TotpCredential::from_user_id(&state.pool, user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(mut credential) = credential.filter(|x| x.is_confirmed) else {
+    return ApiResponse::Err(invalid_code);
+  };
+
+  match credential.verify_and_record(&state, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => return ApiResponse::Err(invalid_code),
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
+  // checked after the code, so only the user finds out about these
+  if user.is_suspended {
+    return ApiResponse::Err(ApiErr::UserSuspended);
+  }
+
+  let Ok(groups) = user.get_groups(&state.pool).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  if !is_totp_allowed(&groups) {
+    return ApiResponse::Err(totp_not_allowed());
+  }
+
+  let Ok((refresh_token, session)) =
+    UserSession::create_session(&state.pool, user.id, 0, TOTP_METHOD, metadata).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let access_claims = IdentityAccessClaims::create_for_session(&user, &session);
+
+  let refresh_claims = IdentityRefreshClaims {
+    session_id: session.session_id,
+    refresh_token,
+    generation: session.refresh_generation,
+  };
+
+  ApiResponse::Ok(TotpLoginResponse {
+    access_token: access_claims.to_token(&state),
+    refresh_token: refresh_claims.to_jwt(&state),
+    session,
+    user,
+  })
+}
+
+pub fn totp_not_allowed() -> ApiErr {
+  ApiErr::Other(
+    "totp_not_allowed".to_string(),
+    "Your account isn't allowed to sign in with an authenticator app. Please use a passkey."
+      .to_string(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RFC_4226_SECRET: &[u8] = b"12345678901234567890";
+
+  fn group(totp_policy: Option<&str>) -> IdentityGroup {
+    IdentityGroup {
+      id: 1,
+      slug: "group".to_string(),
+      name: "Group".to_string(),
+      description: String::new(),
+      is_managed: false,
+      totp_policy: totp_policy.map(|x| x.to_string()),
+      allowed_aaguids: vec![],
+      denied_aaguids: vec![],
+      signup_default: false,
+    }
+  }
+
+  #[test]
+  fn hotp_matches_rfc_4226_test_vectors() {
+    let expected = [
+      755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+    for (counter, code) in expected.into_iter().enumerate() {
+      assert_eq!(
+        hotp(RFC_4226_SECRET, counter as u64),
+        code,
+        "counter {}",
+        counter
+      );
+    }
+  }
+
+  #[test]
+  fn accepts_codes_within_the_drift_window() {
+    let code = |step: u64| format!("{:06}", hotp(RFC_4226_SECRET, step));
+    assert_eq!(
+      verify_code_at_step(RFC_4226_SECRET, &code(100), 100),
+      Some(100)
+    );
+    assert_eq!(
+      verify_code_at_step(RFC_4226_SECRET, &code(99), 100),
+      Some(99)
+    );
+    assert_eq!(
+      verify_code_at_step(RFC_4226_SECRET, &code(101), 100),
+      Some(101)
+    );
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, &code(98), 100), None);
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, &code(102), 100), None);
+  }
+
+  #[test]
+  fn drift_window_starts_at_step_zero() {
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, "755224", 0), Some(0));
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, "287082", 0), Some(1));
+  }
+
+  #[test]
+  fn only_accepts_six_digit_codes() {
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, " 162583 ", 7), Some(7));
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, "0162583", 7), None);
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, "16258", 7), None);
+    assert_eq!(verify_code_at_step(RFC_4226_SECRET, "16258a", 7), None);
+  }
+
+  #[test]
+  fn totp_needs_an_allowing_group_and_no_forbidding_one() {
+    let allowed = || group(Some(TOTP_POLICY_ALLOWED));
+    let forbidden = || group(Some(TOTP_POLICY_FORBIDDEN));
+
+    assert!(!is_totp_allowed(&[]));
+    assert!(!is_totp_allowed(&[group(None)]));
+    assert!(is_totp_allowed(&[allowed()]));
+    assert!(is_totp_allowed(&[allowed(), group(None)]));
+    assert!(!is_totp_allowed(&[allowed(), forbidden()]));
+    assert!(!is_totp_allowed(&[forbidden()]));
+  }
+}
@@ -2,7 +2,7 @@ use std::io::{self, Write};
 
 use sqlx::types::Uuid;
 
-use crate::{AppState, user::User};
+use crate::{AppState, user::{User, invitations::Invitation}};
 
 fn read_line(
   user_input: &mut String
@@ -21,13 +21,18 @@ pub async fn handle_email_setup(
 ) {
   let mut user_input = String::new();
 
+  let Ok(invitation) = Invitation::create(&state.pool, user.id, None).await else {
+    println!("Whoops! An error occurred while trying to create an invitation. Make sure postgres is available and try again!");
+    return;
+  };
+
   // If the user has SMTP configured, we will let them setup SMTP.
   if state.mailer.is_some() {
     loop {
       print!("It looks like you have SMTP configured! Would you like to receive a setup link through your email or directly through the cli (type \"email\" or \"cli\"): ");
       read_line(&mut user_input);
       if user_input.eq_ignore_ascii_case("email") {
-        let Ok(join_handle_opt) = user.send_registration_mail(&state).await else {
+        let Ok(join_handle_opt) = user.send_registration_mail(state, &invitation).await else {
           println!("Looks like we encountered an error with that! Let's try this again...");
           continue;
         };
@@ -50,7 +55,7 @@ pub async fn handle_email_setup(
     }
   }
 
-  let registration_link = user.get_registration_link(&state);
+  let registration_link = user.get_registration_link(state, &invitation);
   println!("Here's a link to setup {}'s account: {}", user.username.clone(), registration_link);
 }
 
@@ -79,7 +84,8 @@ pub async fn handle_setup_cli(
     name: "whatever".to_string(),
     is_suspended: false,
     is_admin: true,
-    credential_uuid: Uuid::new_v4()
+    credential_uuid: Uuid::new_v4(),
+    email_verified: false
   };
   println!();
 
@@ -121,7 +127,7 @@ pub async fn handle_setup_cli(
   println!("Your account has been created! Let's move on to getting you registered...");
   println!();
 
-  handle_email_setup(&state, &admin_user).await
+  handle_email_setup(state, &admin_user).await
 }
 
 pub async fn handle_email_cli(
@@ -139,5 +145,5 @@ pub async fn handle_email_cli(
     return;
   };
   println!();
-  handle_email_setup(&state, &user).await;
+  handle_email_setup(state, &user).await;
 }
\ No newline at end of file
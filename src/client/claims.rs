@@ -0,0 +1,354 @@
+// Per-client claim mappings, applied to the standard claims when building id
+// tokens and userinfo responses. A mapping writes a value to `claim`, which can
+// be nested with dots (e.g. realm_access.roles). Claims that look like URIs
+// (e.g. https://app/roles) are never split.
+
+use std::{collections::HashMap, error::Error};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::PgPool;
+
+/// Copies one of the standard claims
+pub const CLAIM_SOURCE: &str = "claim";
+/// Copies a user attribute, skipped for users that don't have it
+pub const ATTRIBUTE_SOURCE: &str = "attribute";
+/// Adds a fixed value, parsed as JSON if possible and used as a string otherwise
+pub const STATIC_SOURCE: &str = "static";
+
+/// Claims that token validation depends on. Mappings can read these, but can't
+/// overwrite or remove them.
+const PROTECTED_CLAIMS: [&str; 8] = [
+  "iss",
+  "sub",
+  "aud",
+  "exp",
+  "iat",
+  "auth_time",
+  "acr",
+  "nonce",
+];
+
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ClientClaimMapping {
+  /// Taken from the path when mappings are updated
+  #[serde(default)]
+  pub client_id: String,
+  pub claim: String,
+  pub source_type: String,
+  pub source: String,
+  /// Only keep string values starting with this prefix. Arrays are filtered
+  /// item by item, and other values are dropped entirely.
+  pub filter_prefix: Option<String>,
+  /// Removes the source claim, turning the mapping into a rename
+  #[serde(default)]
+  pub remove_source: bool,
+}
+
+fn claim_path(claim: &str) -> Vec<&str> {
+  if claim.contains(':') {
+    return vec![claim];
+  }
+  claim.split('.').collect()
+}
+
+fn set_claim(claims: &mut Map<String, Value>, path: &[&str], value: Value) {
+  let Some((last, parents)) = path.split_last() else {
+    return;
+  };
+
+  let mut current = claims;
+  for segment in parents {
+    let entry = current
+      .entry(segment.to_string())
+      .or_insert_with(|| Value::Object(Map::new()));
+    if !entry.is_object() {
+      *entry = Value::Object(Map::new());
+    }
+    current = entry
+      .as_object_mut()
+      .expect("claim was just turned into an object");
+  }
+
+  current.insert(last.to_string(), value);
+}
+
+fn filter_value(value: Value, prefix: &str) -> Option<Value> {
+  match value {
+    Value::Array(items) => Some(Value::Array(
+      items
+        .into_iter()
+        .filter(|x| x.as_str().is_some_and(|x| x.starts_with(prefix)))
+        .collect(),
+    )),
+    Value::String(x) if x.starts_with(prefix) => Some(Value::String(x)),
+    _ => None,
+  }
+}
+
+impl ClientClaimMapping {
+  /// Returns a description of the problem if this mapping can't be saved
+  pub fn validate(&self) -> Option<&'static str> {
+    let path = claim_path(&self.claim);
+    if path.iter().any(|x| x.is_empty()) {
+      return Some("Claim names cannot be empty.");
+    }
+
+    if PROTECTED_CLAIMS.contains(&path[0]) {
+      return Some(
+        "Mappings cannot overwrite the iss, sub, aud, exp, iat, auth_time, acr or nonce claims.",
+      );
+    }
+
+    match self.source_type.as_str() {
+      CLAIM_SOURCE | ATTRIBUTE_SOURCE | STATIC_SOURCE => {}
+      _ => return Some("The source type must be claim, attribute or static."),
+    }
+
+    if self.remove_source
+      && (self.source_type != CLAIM_SOURCE || PROTECTED_CLAIMS.contains(&self.source.as_str()))
+    {
+      return Some("Only non-protected claims can be removed by a mapping.");
+    }
+
+    None
+  }
+
+  pub async fn get_mappings_for_client(
+    pool: &PgPool,
+    client_id: String,
+  ) -> Result<Vec<ClientClaimMapping>, Box<dyn Error>> {
+    let mappings = sqlx::query_as!(
+      ClientClaimMapping,
+      r#"
+        SELECT client_id, claim, source_type, source, filter_prefix, remove_source
+        FROM client_claim_mappings WHERE client_id = $1 ORDER BY claim
+      "#,
+      client_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(mappings)
+  }
+
+  pub async fn set_mappings_for_client(
+    pool: &PgPool,
+    mappings: &[ClientClaimMapping],
+    client_id: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+      r#"
+        DELETE FROM client_claim_mappings WHERE client_id = $1
+      "#,
+      client_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    for mapping in mappings {
+      sqlx::query!(
+        r#"
+          INSERT INTO client_claim_mappings(client_id, claim, source_type, source, filter_prefix, remove_source) VALUES ($1, $2, $3, $4, $5, $6)
+          ON CONFLICT (client_id, claim) DO UPDATE SET source_type = EXCLUDED.source_type, source = EXCLUDED.source, filter_prefix = EXCLUDED.filter_prefix, remove_source = EXCLUDED.remove_source
+        "#,
+        client_id,
+        mapping.claim,
+        mapping.source_type,
+        mapping.source,
+        mapping.filter_prefix,
+        mapping.remove_source
+      ).execute(&mut *transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+  }
+}
+
+/// Applies `mappings` to `claims`. Mappings always read the claims as they were
+/// before any mapping ran, so their order doesn't matter.
+pub fn apply_claim_mappings(
+  claims: &mut Map<String, Value>,
+  mappings: &[ClientClaimMapping],
+  attributes: &HashMap<String, String>,
+) {
+  let original = claims.clone();
+
+  // removals go first so a claim can still be mapped onto itself (e.g. to filter it)
+  for mapping in mappings {
+    if mapping.remove_source
+      && mapping.source_type == CLAIM_SOURCE
+      && !PROTECTED_CLAIMS.contains(&mapping.source.as_str())
+    {
+      claims.remove(&mapping.source);
+    }
+  }
+
+  for mapping in mappings {
+    let path = claim_path(&mapping.claim);
+    if path.is_empty() || PROTECTED_CLAIMS.contains(&path[0]) {
+      continue;
+    }
+
+    let value = match mapping.source_type.as_str() {
+      CLAIM_SOURCE => original.get(&mapping.source).cloned(),
+      ATTRIBUTE_SOURCE => attributes
+        .get(&mapping.source)
+        .map(|x| Value::String(x.clone())),
+      STATIC_SOURCE => Some(
+        serde_json::from_str::<Value>(&mapping.source)
+          .unwrap_or_else(|_| Value::String(mapping.source.clone())),
+      ),
+      _ => None,
+    };
+
+    let value = match (&mapping.filter_prefix, value) {
+      (Some(prefix), Some(value)) => filter_value(value, prefix),
+      (None, value) => value,
+      (_, None) => None,
+    };
+
+    if let Some(value) = value {
+      set_claim(claims, &path, value);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn mapping(claim: &str, source_type: &str, source: &str) -> ClientClaimMapping {
+    ClientClaimMapping {
+      client_id: String::new(),
+      claim: claim.to_string(),
+      source_type: source_type.to_string(),
+      source: source.to_string(),
+      filter_prefix: None,
+      remove_source: false,
+    }
+  }
+
+  fn apply(claims: Value, mappings: &[ClientClaimMapping]) -> Value {
+    let attributes = HashMap::from([("department".to_string(), "sales".to_string())]);
+    let Value::Object(mut claims) = claims else {
+      panic!("claims must be an object");
+    };
+    apply_claim_mappings(&mut claims, mappings, &attributes);
+    Value::Object(claims)
+  }
+
+  #[test]
+  fn copies_claims_attributes_and_static_values() {
+    let claims = apply(
+      json!({ "sub": "1", "groups": ["admins"] }),
+      &[
+        mapping("roles", CLAIM_SOURCE, "groups"),
+        mapping("department", ATTRIBUTE_SOURCE, "department"),
+        mapping("missing", ATTRIBUTE_SOURCE, "cost_center"),
+        mapping("level", STATIC_SOURCE, "3"),
+        mapping("tenant", STATIC_SOURCE, "acme"),
+      ],
+    );
+    assert_eq!(
+      claims,
+      json!({
+        "sub": "1",
+        "groups": ["admins"],
+        "roles": ["admins"],
+        "department": "sales",
+        "level": 3,
+        "tenant": "acme",
+      })
+    );
+  }
+
+  #[test]
+  fn nests_claims_unless_they_look_like_uris() {
+    let claims = apply(
+      json!({ "groups": ["admins"], "realm_access": "replaced" }),
+      &[
+        mapping("realm_access.roles", CLAIM_SOURCE, "groups"),
+        mapping("https://app.example/roles", CLAIM_SOURCE, "groups"),
+      ],
+    );
+    assert_eq!(
+      claims,
+      json!({
+        "groups": ["admins"],
+        "realm_access": { "roles": ["admins"] },
+        "https://app.example/roles": ["admins"],
+      })
+    );
+  }
+
+  #[test]
+  fn filters_by_prefix() {
+    let mut roles = mapping("roles", CLAIM_SOURCE, "groups");
+    roles.filter_prefix = Some("app-".to_string());
+    let mut name = mapping("app_name", CLAIM_SOURCE, "name");
+    name.filter_prefix = Some("app-".to_string());
+    let mut count = mapping("count", STATIC_SOURCE, "3");
+    count.filter_prefix = Some("app-".to_string());
+
+    let claims = apply(
+      json!({ "groups": ["app-admins", "staff", "app-users"], "name": "Alice" }),
+      &[roles, name, count],
+    );
+    assert_eq!(
+      claims,
+      json!({
+        "groups": ["app-admins", "staff", "app-users"],
+        "name": "Alice",
+        "roles": ["app-admins", "app-users"],
+      })
+    );
+  }
+
+  #[test]
+  fn renames_and_filters_in_place() {
+    let mut rename = mapping("roles", CLAIM_SOURCE, "groups");
+    rename.remove_source = true;
+    let mut filter = mapping("email", CLAIM_SOURCE, "email");
+    filter.remove_source = true;
+    filter.filter_prefix = Some("admin@".to_string());
+
+    let claims = apply(
+      json!({ "groups": ["admins"], "email": "alice@example.com" }),
+      &[rename, filter],
+    );
+    assert_eq!(claims, json!({ "roles": ["admins"] }));
+  }
+
+  #[test]
+  fn reads_claims_from_before_any_mapping() {
+    let claims = apply(
+      json!({ "a": "1", "b": "2" }),
+      &[
+        mapping("a", CLAIM_SOURCE, "b"),
+        mapping("b", CLAIM_SOURCE, "a"),
+      ],
+    );
+    assert_eq!(claims, json!({ "a": "2", "b": "1" }));
+  }
+
+  #[test]
+  fn never_touches_protected_claims() {
+    let mut remove = mapping("subject", CLAIM_SOURCE, "sub");
+    remove.remove_source = true;
+
+    let claims = apply(
+      json!({ "sub": "1", "aud": "client" }),
+      &[
+        remove,
+        mapping("aud", STATIC_SOURCE, "other"),
+        mapping("nonce.value", STATIC_SOURCE, "x"),
+      ],
+    );
+    assert_eq!(
+      claims,
+      json!({ "sub": "1", "aud": "client", "subject": "1" })
+    );
+  }
+}
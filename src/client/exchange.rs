@@ -0,0 +1,80 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Allows `client_id` to trade a user's access token for one issued to
+/// `target_client_id` through the token exchange grant.
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TokenExchangePolicy {
+  pub client_id: String,
+  pub target_client_id: String,
+}
+
+impl TokenExchangePolicy {
+  pub async fn is_exchange_allowed(
+    pool: &PgPool,
+    client_id: String,
+    target_client_id: String,
+  ) -> Result<bool, Box<dyn Error>> {
+    let allowed = sqlx::query_scalar!(
+      r#"
+        SELECT EXISTS(
+          SELECT 1 FROM client_token_exchange_policies WHERE client_id = $1 AND target_client_id = $2
+        ) AS "allowed!"
+      "#,
+      client_id,
+      target_client_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(allowed)
+  }
+
+  pub async fn get_policies_for_client(
+    pool: &PgPool,
+    client_id: String,
+  ) -> Result<Vec<TokenExchangePolicy>, Box<dyn Error>> {
+    let policies = sqlx::query_as!(
+      TokenExchangePolicy,
+      r#"
+        SELECT client_id, target_client_id FROM client_token_exchange_policies WHERE client_id = $1
+      "#,
+      client_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(policies)
+  }
+
+  pub async fn upsert_policy(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        INSERT INTO client_token_exchange_policies(client_id, target_client_id) VALUES ($1, $2)
+        ON CONFLICT (client_id, target_client_id) DO NOTHING
+      "#,
+      self.client_id,
+      self.target_client_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn remove_policy(
+    pool: &PgPool,
+    client_id: String,
+    target_client_id: String,
+  ) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        DELETE FROM client_token_exchange_policies WHERE client_id = $1 AND target_client_id = $2
+      "#,
+      client_id,
+      target_client_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+}
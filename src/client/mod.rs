@@ -2,14 +2,14 @@ use std::error::Error;
 
 use axum::{
   Router,
-  routing::{get, patch, post},
+  routing::{get, patch, post, put},
 };
 use rand::distributions::{Alphanumeric, DistString};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
 use crate::{
-  AppState,
+  AppState, AppTokenLifetimes,
   client::{
     permissions::{GroupPermissionOverride, UserPermissionOverride},
     roles::{GroupAppRoleOverride, UserAppRoleOverride},
@@ -18,7 +18,10 @@ use crate::{
   user::User,
 };
 
+pub mod claims;
+pub mod exchange;
 pub mod permissions;
+pub mod redirect;
 pub mod roles;
 pub mod routes;
 
@@ -35,15 +38,38 @@ pub struct IdentityClient {
   pub default_allowed: bool,
   pub allow_explicit_flow: bool,
   pub allow_implicit_flow: bool,
+  /// Rejects token requests without a DPoP proof so every token issued to this
+  /// client is sender-constrained.
+  pub require_dpop: bool,
+  /// Native apps use loopback (any port) or private-use scheme redirect URIs
+  /// instead of exact https ones.
+  pub is_native_app: bool,
+  // token lifetimes in seconds, None uses the server-wide default
+  pub access_token_lifetime: Option<i32>,
+  pub id_token_lifetime: Option<i32>,
+  pub refresh_token_idle_lifetime: Option<i32>,
+  pub refresh_token_absolute_lifetime: Option<i32>,
+  /// Which of the user's groups end up in the groups claim, see [`GROUPS_CLAIM_MODES`]
+  pub groups_claim_mode: String,
+  /// Group slugs for the "list" mode, or slug prefixes for the "prefix" mode
+  pub groups_claim_filter: Vec<String>,
 }
 
+/// - all: every group the user is in
+/// - none: the groups claim is left out entirely
+/// - list: only groups whose slug is in `groups_claim_filter`
+/// - prefix: only groups whose slug starts with one of `groups_claim_filter`
+/// - referenced: only groups with a permission or role override on this client
+pub const GROUPS_CLAIM_MODES: [&str; 5] = ["all", "none", "list", "prefix", "referenced"];
+
 impl IdentityClient {
   pub async fn fetch_all_clients(pool: &PgPool) -> Result<Vec<IdentityClient>, Box<dyn Error>> {
     let clients = sqlx::query_as!(
       IdentityClient,
       r#"
         SELECT 
-          client_id, client_secret, app_name, app_description, redirect_uris, is_managed, is_disabled, default_allowed, allow_explicit_flow, allow_implicit_flow
+          client_id, client_secret, app_name, app_description, redirect_uris, is_managed, is_disabled, default_allowed, allow_explicit_flow, allow_implicit_flow, require_dpop, is_native_app,
+          access_token_lifetime, id_token_lifetime, refresh_token_idle_lifetime, refresh_token_absolute_lifetime, groups_claim_mode, groups_claim_filter
         FROM clients
       "#
     ).fetch_all(pool).await?;
@@ -58,7 +84,8 @@ impl IdentityClient {
       IdentityClient,
       r#"
         SELECT 
-          client_id, client_secret, app_name, app_description, redirect_uris, is_managed, is_disabled, default_allowed, allow_explicit_flow, allow_implicit_flow
+          client_id, client_secret, app_name, app_description, redirect_uris, is_managed, is_disabled, default_allowed, allow_explicit_flow, allow_implicit_flow, require_dpop, is_native_app,
+          access_token_lifetime, id_token_lifetime, refresh_token_idle_lifetime, refresh_token_absolute_lifetime, groups_claim_mode, groups_claim_filter
         FROM clients WHERE client_id = $1
       "#,
       client_id
@@ -76,10 +103,12 @@ impl IdentityClient {
 
     sqlx::query!(
       r#"
-        INSERT INTO clients(client_id, client_secret, app_name, app_description, redirect_uris, is_managed, is_disabled, default_allowed, allow_explicit_flow, allow_implicit_flow) VALUES 
-          ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        INSERT INTO clients(client_id, client_secret, app_name, app_description, redirect_uris, is_managed, is_disabled, default_allowed, allow_explicit_flow, allow_implicit_flow, require_dpop, is_native_app,
+          access_token_lifetime, id_token_lifetime, refresh_token_idle_lifetime, refresh_token_absolute_lifetime, groups_claim_mode, groups_claim_filter) VALUES 
+          ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
       "#,
-      self.client_id, self.client_secret, self.app_name, self.app_description, self.redirect_uris.as_slice(), self.is_managed, self.is_disabled, self.default_allowed, self.allow_explicit_flow, self.allow_implicit_flow
+      self.client_id, self.client_secret, self.app_name, self.app_description, self.redirect_uris.as_slice(), self.is_managed, self.is_disabled, self.default_allowed, self.allow_explicit_flow, self.allow_implicit_flow, self.require_dpop, self.is_native_app,
+      self.access_token_lifetime, self.id_token_lifetime, self.refresh_token_idle_lifetime, self.refresh_token_absolute_lifetime, self.groups_claim_mode, self.groups_claim_filter.as_slice()
     ).execute(pool).await?;
 
     Ok(self)
@@ -88,10 +117,14 @@ impl IdentityClient {
   pub async fn update(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
     sqlx::query!(
       r#"
-        UPDATE clients SET client_secret=$1, app_name=$2, app_description=$3, redirect_uris=$4, is_managed=$5, is_disabled=$6, default_allowed=$7, allow_implicit_flow=$8, allow_explicit_flow=$9
-        WHERE client_id=$10
+        UPDATE clients SET client_secret=$1, app_name=$2, app_description=$3, redirect_uris=$4, is_managed=$5, is_disabled=$6, default_allowed=$7, allow_implicit_flow=$8, allow_explicit_flow=$9, require_dpop=$10, is_native_app=$11,
+          access_token_lifetime=$12, id_token_lifetime=$13, refresh_token_idle_lifetime=$14, refresh_token_absolute_lifetime=$15,
+          groups_claim_mode=$16, groups_claim_filter=$17
+        WHERE client_id=$18
       "#,
-      self.client_secret, self.app_name, self.app_description, self.redirect_uris.as_slice(), self.is_managed, self.is_disabled, self.default_allowed, self.allow_implicit_flow, self.allow_explicit_flow, self.client_id
+      self.client_secret, self.app_name, self.app_description, self.redirect_uris.as_slice(), self.is_managed, self.is_disabled, self.default_allowed, self.allow_implicit_flow, self.allow_explicit_flow, self.require_dpop, self.is_native_app,
+      self.access_token_lifetime, self.id_token_lifetime, self.refresh_token_idle_lifetime, self.refresh_token_absolute_lifetime,
+      self.groups_claim_mode, self.groups_claim_filter.as_slice(), self.client_id
     ).execute(pool).await?;
     Ok(())
   }
@@ -100,14 +133,29 @@ impl IdentityClient {
     let client_secret = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
     self.client_secret = client_secret;
 
-    return self.update(&pool).await;
+    return self.update(pool).await;
+  }
+
+  /// Applies this client's lifetime overrides on top of the server-wide defaults
+  pub fn get_token_lifetimes(&self, defaults: &AppTokenLifetimes) -> AppTokenLifetimes {
+    let resolve = |lifetime: Option<i32>, default: u64| lifetime.map_or(default, |x| x as u64);
+    AppTokenLifetimes {
+      authorization_code: defaults.authorization_code,
+      access_token: resolve(self.access_token_lifetime, defaults.access_token),
+      id_token: resolve(self.id_token_lifetime, defaults.id_token),
+      refresh_token_idle: resolve(self.refresh_token_idle_lifetime, defaults.refresh_token_idle),
+      refresh_token_absolute: resolve(
+        self.refresh_token_absolute_lifetime,
+        defaults.refresh_token_absolute,
+      ),
+    }
   }
 
   pub async fn is_user_allowed(
     &self,
     pool: &PgPool,
     user: &User,
-    groups: &Vec<IdentityGroup>,
+    groups: &[IdentityGroup],
   ) -> Result<bool, Box<dyn Error>> {
     let mut allow = self.default_allowed;
 
@@ -141,11 +189,57 @@ impl IdentityClient {
     Ok(allow)
   }
 
+  /// The group slugs to put in the groups claim for this client, or None if the
+  /// claim should be left out.
+  pub async fn get_groups_claim(
+    &self,
+    pool: &PgPool,
+    groups: &[IdentityGroup],
+  ) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let groups = match self.groups_claim_mode.as_str() {
+      "none" => return Ok(None),
+      "list" => groups
+        .iter()
+        .filter(|x| self.groups_claim_filter.contains(&x.slug))
+        .collect::<Vec<&IdentityGroup>>(),
+      "prefix" => groups
+        .iter()
+        .filter(|x| {
+          self
+            .groups_claim_filter
+            .iter()
+            .any(|prefix| x.slug.starts_with(prefix))
+        })
+        .collect::<Vec<&IdentityGroup>>(),
+      "referenced" => {
+        let mut referenced_ids =
+          GroupPermissionOverride::fetch_group_permissions_for_client(pool, self.client_id.clone())
+            .await?
+            .iter()
+            .map(|x| x.group_id)
+            .collect::<Vec<i32>>();
+        referenced_ids.extend(
+          GroupAppRoleOverride::fetch_group_role_overrides_for_client(pool, self.client_id.clone())
+            .await?
+            .iter()
+            .map(|x| x.group_id),
+        );
+        groups
+          .iter()
+          .filter(|x| referenced_ids.contains(&x.id))
+          .collect::<Vec<&IdentityGroup>>()
+      }
+      _ => groups.iter().collect::<Vec<&IdentityGroup>>(),
+    };
+
+    Ok(Some(groups.iter().map(|x| x.slug.clone()).collect()))
+  }
+
   pub async fn get_user_roles(
     &self,
     pool: &PgPool,
     user: &User,
-    groups: &Vec<IdentityGroup>,
+    groups: &[IdentityGroup],
   ) -> Result<Vec<String>, Box<dyn Error>> {
     let mut roles = Vec::new();
 
@@ -162,9 +256,9 @@ impl IdentityClient {
         continue;
       }
 
-      if *&role_override.granted && !roles.contains(&role_override.role) {
+      if role_override.granted && !roles.contains(&role_override.role) {
         roles.push(role_override.role.clone());
-      } else if !*&role_override.granted && roles.contains(&role_override.role) {
+      } else if !role_override.granted && roles.contains(&role_override.role) {
         roles.retain(|x| *x != role_override.role)
       }
     }
@@ -178,9 +272,9 @@ impl IdentityClient {
     .await?;
 
     for role_override in &user_overrides {
-      if *&role_override.granted && !roles.contains(&role_override.role) {
+      if role_override.granted && !roles.contains(&role_override.role) {
         roles.push(role_override.role.clone());
-      } else if !*&role_override.granted && roles.contains(&role_override.role) {
+      } else if !role_override.granted && roles.contains(&role_override.role) {
         roles.retain(|x| *x != role_override.role)
       }
     }
@@ -220,4 +314,16 @@ pub fn router() -> Router<AppState> {
       "/v1/clients/{client_id}/user-overrides/{user_id}/roles/{role}",
       patch(routes::update_user_role_override).delete(routes::delete_user_role_override),
     )
+    .route(
+      "/v1/clients/{client_id}/token-exchange/{target_client_id}",
+      put(routes::add_token_exchange_policy).delete(routes::delete_token_exchange_policy),
+    )
+    .route(
+      "/v1/clients/{client_id}/claim-mappings",
+      patch(routes::update_claim_mappings),
+    )
+    .route(
+      "/v1/clients/{client_id}/claim-mappings/preview/{user_id}",
+      get(routes::preview_claims),
+    )
 }
@@ -0,0 +1,184 @@
+// Redirect URI rules for web and native apps. Web apps must use exact https
+// redirect URIs, while native apps follow RFC 8252: loopback IP redirects match
+// any port, and apps can claim private-use schemes like com.example.app:/cb.
+
+use std::net::IpAddr;
+
+use webauthn_rs::prelude::Url;
+
+fn is_loopback_ip(url: &Url) -> bool {
+  let Some(host) = url.host_str() else {
+    return false;
+  };
+
+  // ipv6 hosts are wrapped in brackets
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn is_loopback_host(url: &Url) -> bool {
+  is_loopback_ip(url) || url.host_str() == Some("localhost")
+}
+
+/// Private-use schemes have to be based on a domain name the app controls, so
+/// we require the reverse domain notation from RFC 8252 section 7.1.
+fn is_private_use_scheme(url: &Url) -> bool {
+  url.scheme().contains('.')
+}
+
+/// Whether a redirect URI can be registered on an app. Native apps can only use
+/// http loopback IPs (not "localhost") or private-use schemes. Web apps need https,
+/// though http is still allowed on loopback hosts for local development.
+pub fn is_registrable_redirect_uri(is_native_app: bool, redirect_uri: &str) -> bool {
+  let Ok(url) = Url::parse(redirect_uri) else {
+    return false;
+  };
+
+  if url.fragment().is_some() {
+    return false;
+  }
+
+  if is_native_app {
+    return (url.scheme() == "http" && is_loopback_ip(&url)) || is_private_use_scheme(&url);
+  }
+
+  url.scheme() == "https" || (url.scheme() == "http" && is_loopback_host(&url))
+}
+
+/// Whether a redirect URI sent in an authorization request matches a registered
+/// one. Native apps bind an ephemeral port, so the port is ignored for loopback
+/// IP redirects; everything else must match exactly.
+pub fn redirect_uri_matches(is_native_app: bool, registered: &str, requested: &str) -> bool {
+  if registered == requested {
+    return true;
+  }
+
+  if !is_native_app {
+    return false;
+  }
+
+  let (Ok(mut registered), Ok(mut requested)) = (Url::parse(registered), Url::parse(requested))
+  else {
+    return false;
+  };
+
+  if requested.scheme() != "http" || !is_loopback_ip(&requested) {
+    return false;
+  }
+
+  // this can only fail for URLs that can't have ports, which loopback ones can
+  if registered.set_port(None).is_err() || requested.set_port(None).is_err() {
+    return false;
+  }
+
+  registered == requested
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exact_match_always_matches() {
+    assert!(redirect_uri_matches(
+      false,
+      "https://app.example/cb",
+      "https://app.example/cb"
+    ));
+    assert!(redirect_uri_matches(
+      true,
+      "com.example.app:/cb",
+      "com.example.app:/cb"
+    ));
+  }
+
+  #[test]
+  fn web_apps_need_the_exact_port() {
+    assert!(!redirect_uri_matches(
+      false,
+      "http://127.0.0.1:8080/cb",
+      "http://127.0.0.1:9090/cb"
+    ));
+    assert!(!redirect_uri_matches(
+      false,
+      "http://127.0.0.1/cb",
+      "http://127.0.0.1:9090/cb"
+    ));
+  }
+
+  #[test]
+  fn native_loopback_ips_match_any_port() {
+    assert!(redirect_uri_matches(
+      true,
+      "http://127.0.0.1/cb",
+      "http://127.0.0.1:51004/cb"
+    ));
+    assert!(redirect_uri_matches(
+      true,
+      "http://127.0.0.1:8080/cb",
+      "http://127.0.0.1:9090/cb"
+    ));
+    assert!(redirect_uri_matches(
+      true,
+      "http://[::1]/cb",
+      "http://[::1]:51004/cb"
+    ));
+  }
+
+  #[test]
+  fn native_loopback_ips_still_match_the_rest() {
+    assert!(!redirect_uri_matches(
+      true,
+      "http://127.0.0.1/cb",
+      "http://127.0.0.1:51004/other"
+    ));
+    assert!(!redirect_uri_matches(
+      true,
+      "http://127.0.0.1/cb",
+      "http://127.0.0.2:51004/cb"
+    ));
+    assert!(!redirect_uri_matches(
+      true,
+      "http://127.0.0.1/cb",
+      "http://[::1]:51004/cb"
+    ));
+    assert!(!redirect_uri_matches(
+      true,
+      "http://127.0.0.1/cb?a=1",
+      "http://127.0.0.1:51004/cb"
+    ));
+  }
+
+  #[test]
+  fn native_ports_are_only_ignored_for_loopback_ips() {
+    assert!(!redirect_uri_matches(
+      true,
+      "http://localhost/cb",
+      "http://localhost:51004/cb"
+    ));
+    assert!(!redirect_uri_matches(
+      true,
+      "https://127.0.0.1/cb",
+      "https://127.0.0.1:51004/cb"
+    ));
+    assert!(!redirect_uri_matches(
+      true,
+      "http://app.example/cb",
+      "http://app.example:51004/cb"
+    ));
+  }
+
+  #[test]
+  fn invalid_uris_never_match() {
+    assert!(!redirect_uri_matches(
+      true,
+      "not a uri",
+      "http://127.0.0.1:51004/cb"
+    ));
+    assert!(!redirect_uri_matches(
+      true,
+      "http://127.0.0.1/cb",
+      "not a uri"
+    ));
+  }
+}
@@ -3,16 +3,21 @@ use axum::{
   extract::{Path, State},
 };
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 use crate::{
   AppState,
   client::{
-    IdentityClient,
+    GROUPS_CLAIM_MODES, IdentityClient,
+    claims::ClientClaimMapping,
+    exchange::TokenExchangePolicy,
     permissions::{GroupPermissionOverride, UserPermissionOverride},
+    redirect::is_registrable_redirect_uri,
     roles::{GroupAppRoleOverride, UserAppRoleOverride},
   },
+  oauth::{authorization::UserAppAuthorization, get_id_token_claims},
   response::{ApiErr, ApiResponse, EmptyResponse},
-  user::AdminCtx,
+  user::{AdminCtx, SudoCtx, User},
 };
 
 #[derive(Deserialize)]
@@ -24,6 +29,22 @@ pub struct PartialClient {
   pub default_allowed: bool,
   pub allow_implicit_flow: bool,
   pub allow_explicit_flow: bool,
+  #[serde(default)]
+  pub require_dpop: bool,
+  #[serde(default)]
+  pub is_native_app: bool,
+  pub access_token_lifetime: Option<i32>,
+  pub id_token_lifetime: Option<i32>,
+  pub refresh_token_idle_lifetime: Option<i32>,
+  pub refresh_token_absolute_lifetime: Option<i32>,
+  #[serde(default = "default_groups_claim_mode")]
+  pub groups_claim_mode: String,
+  #[serde(default)]
+  pub groups_claim_filter: Vec<String>,
+}
+
+fn default_groups_claim_mode() -> String {
+  "all".to_string()
 }
 
 // TODO: pagination maybe?
@@ -39,6 +60,26 @@ pub struct GetClientDetailedResponse {
   pub group_permission_overrides: Vec<GroupPermissionOverride>,
   pub user_role_overrides: Vec<UserAppRoleOverride>,
   pub group_role_overrides: Vec<GroupAppRoleOverride>,
+  pub token_exchange_policies: Vec<TokenExchangePolicy>,
+  pub claim_mappings: Vec<ClientClaimMapping>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateClaimMappingsRequest {
+  pub claim_mappings: Vec<ClientClaimMapping>,
+}
+
+#[derive(Serialize)]
+pub struct UpdateClaimMappingsResponse {
+  pub client: IdentityClient,
+  pub claim_mappings: Vec<ClientClaimMapping>,
+}
+
+#[derive(Serialize)]
+pub struct PreviewClaimsResponse {
+  /// Whether the user currently passes the app's ACL checks
+  pub allowed: bool,
+  pub claims: Map<String, Value>,
 }
 
 #[derive(Deserialize)]
@@ -86,11 +127,58 @@ pub struct CreateClientResponse {
 
 type RotateClientSecretResponse = CreateClientResponse;
 
+fn validate_lifetimes(payload: &PartialClient) -> Option<ApiErr> {
+  let lifetimes = [
+    payload.access_token_lifetime,
+    payload.id_token_lifetime,
+    payload.refresh_token_idle_lifetime,
+    payload.refresh_token_absolute_lifetime,
+  ];
+
+  if lifetimes.iter().flatten().any(|x| *x <= 0) {
+    return Some(ApiErr::Other(
+      "invalid_lifetime".to_string(),
+      "Token lifetimes must be a positive number of seconds.".to_string(),
+    ));
+  }
+
+  None
+}
+
+fn validate_groups_claim(payload: &PartialClient) -> Option<ApiErr> {
+  if !GROUPS_CLAIM_MODES.contains(&payload.groups_claim_mode.as_str()) {
+    return Some(ApiErr::Other(
+      "invalid_groups_claim_mode".to_string(),
+      format!(
+        "The groups claim mode must be one of: {}.",
+        GROUPS_CLAIM_MODES.join(", ")
+      ),
+    ));
+  }
+
+  None
+}
+
+fn validate_redirect_uris(payload: &PartialClient) -> Option<ApiErr> {
+  payload
+    .redirect_uris
+    .iter()
+    .find(|x| !is_registrable_redirect_uri(payload.is_native_app, x))
+    .map(|x| ApiErr::InvalidRedirectUri(x.clone()))
+}
+
 pub async fn create_client(
   State(state): State<AppState>,
   _: AdminCtx,
   Json(payload): Json<PartialClient>,
 ) -> ApiResponse<CreateClientResponse> {
+  if let Some(err) = validate_redirect_uris(&payload)
+    .or_else(|| validate_lifetimes(&payload))
+    .or_else(|| validate_groups_claim(&payload))
+  {
+    return ApiResponse::Err(err);
+  }
+
   let mut client = IdentityClient {
     client_id: "to-be-replaced".to_string(),
     client_secret: "to-be-replaced".to_string(),
@@ -102,6 +190,14 @@ pub async fn create_client(
     default_allowed: payload.default_allowed,
     allow_implicit_flow: payload.allow_implicit_flow,
     allow_explicit_flow: payload.allow_explicit_flow,
+    require_dpop: payload.require_dpop,
+    is_native_app: payload.is_native_app,
+    access_token_lifetime: payload.access_token_lifetime,
+    id_token_lifetime: payload.id_token_lifetime,
+    refresh_token_idle_lifetime: payload.refresh_token_idle_lifetime,
+    refresh_token_absolute_lifetime: payload.refresh_token_absolute_lifetime,
+    groups_claim_mode: payload.groups_claim_mode,
+    groups_claim_filter: payload.groups_claim_filter,
   };
 
   match client.create(&state.pool).await {
@@ -148,12 +244,26 @@ pub async fn get_client_detailed(
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
+  let Ok(token_exchange_policies) =
+    TokenExchangePolicy::get_policies_for_client(&state.pool, client_id.clone()).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(claim_mappings) =
+    ClientClaimMapping::get_mappings_for_client(&state.pool, client_id.clone()).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
   ApiResponse::Ok(GetClientDetailedResponse {
     client,
     user_permission_overrides,
     group_permission_overrides,
     user_role_overrides,
     group_role_overrides,
+    token_exchange_policies,
+    claim_mappings,
   })
 }
 
@@ -171,6 +281,13 @@ pub async fn update_client(
     return ApiResponse::Err(ApiErr::ManagedObject);
   }
 
+  if let Some(err) = validate_redirect_uris(&payload)
+    .or_else(|| validate_lifetimes(&payload))
+    .or_else(|| validate_groups_claim(&payload))
+  {
+    return ApiResponse::Err(err);
+  }
+
   client.app_name = payload.app_name;
   client.app_description = payload.app_description;
   client.redirect_uris = payload.redirect_uris;
@@ -178,6 +295,14 @@ pub async fn update_client(
   client.default_allowed = payload.default_allowed;
   client.allow_explicit_flow = payload.allow_explicit_flow;
   client.allow_implicit_flow = payload.allow_implicit_flow;
+  client.require_dpop = payload.require_dpop;
+  client.is_native_app = payload.is_native_app;
+  client.access_token_lifetime = payload.access_token_lifetime;
+  client.id_token_lifetime = payload.id_token_lifetime;
+  client.refresh_token_idle_lifetime = payload.refresh_token_idle_lifetime;
+  client.refresh_token_absolute_lifetime = payload.refresh_token_absolute_lifetime;
+  client.groups_claim_mode = payload.groups_claim_mode;
+  client.groups_claim_filter = payload.groups_claim_filter;
 
   match client.update(&state.pool).await {
     Ok(_) => ApiResponse::Ok(UpdateClientResponse { client }),
@@ -187,7 +312,7 @@ pub async fn update_client(
 
 pub async fn rotate_client_secret(
   State(state): State<AppState>,
-  _: AdminCtx,
+  _: SudoCtx,
   Path(client_id): Path<String>,
 ) -> ApiResponse<RotateClientSecretResponse> {
   let Ok(mut client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
@@ -352,6 +477,133 @@ pub async fn delete_user_role_override(
   }
 }
 
+pub async fn add_token_exchange_policy(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path((client_id, target_client_id)): Path<(String, String)>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownClient);
+  };
+
+  let Ok(target_client) = IdentityClient::from_client_id(&state.pool, target_client_id).await
+  else {
+    return ApiResponse::Err(ApiErr::UnknownClient);
+  };
+
+  if client.client_id == target_client.client_id {
+    return ApiResponse::Err(ApiErr::Other(
+      "invalid_exchange_target".to_string(),
+      "An app cannot be allowed to exchange tokens for itself.".to_string(),
+    ));
+  }
+
+  let policy = TokenExchangePolicy {
+    client_id: client.client_id,
+    target_client_id: target_client.client_id,
+  };
+
+  match policy.upsert_policy(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn delete_token_exchange_policy(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path((client_id, target_client_id)): Path<(String, String)>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownClient);
+  };
+
+  match TokenExchangePolicy::remove_policy(&state.pool, client.client_id, target_client_id).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn update_claim_mappings(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path(client_id): Path<String>,
+  Json(payload): Json<UpdateClaimMappingsRequest>,
+) -> ApiResponse<UpdateClaimMappingsResponse> {
+  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownClient);
+  };
+
+  if let Some(message) = payload.claim_mappings.iter().find_map(|x| x.validate()) {
+    return ApiResponse::Err(ApiErr::Other(
+      "invalid_claim_mapping".to_string(),
+      message.to_string(),
+    ));
+  }
+
+  let Ok(_) = ClientClaimMapping::set_mappings_for_client(
+    &state.pool,
+    &payload.claim_mappings,
+    client.client_id.clone(),
+  )
+  .await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(claim_mappings) =
+    ClientClaimMapping::get_mappings_for_client(&state.pool, client.client_id.clone()).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  ApiResponse::Ok(UpdateClaimMappingsResponse {
+    client,
+    claim_mappings,
+  })
+}
+
+/// Shows the claims `user_id` would get from this app right now, without
+/// signing anything or authorizing the user for the app.
+pub async fn preview_claims(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path((client_id, user_id)): Path<(String, i32)>,
+) -> ApiResponse<PreviewClaimsResponse> {
+  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownClient);
+  };
+
+  let Ok(user) = User::from_user_id(&state.pool, user_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownUser);
+  };
+
+  let Ok(groups) = user.get_groups(&state.pool).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(allowed) = client.is_user_allowed(&state.pool, &user, &groups).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  // users who never used the app don't have a pairwise sub yet
+  let authorization =
+    UserAppAuthorization::get_authorization(&state.pool, user.id, client.client_id.clone())
+      .await
+      .unwrap_or(UserAppAuthorization {
+        user_id: user.id,
+        client_id: client.client_id.clone(),
+        sub: "".to_string(),
+        last_used: 0,
+        revoked: false,
+      });
+
+  match get_id_token_claims(&state, &user, &client, groups, None, None, &authorization).await {
+    Ok(claims) => ApiResponse::Ok(PreviewClaimsResponse { allowed, claims }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
 pub async fn list_all_clients(
   State(state): State<AppState>,
   _: AdminCtx,
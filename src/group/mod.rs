@@ -5,7 +5,7 @@ use axum::{
   routing::{get, patch, put},
 };
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgPool, types::Uuid};
 
 use crate::{AppState, user::User};
 
@@ -18,6 +18,15 @@ pub struct IdentityGroup {
   pub name: String,
   pub description: String,
   pub is_managed: bool,
+  /// Whether members may sign in with TOTP, see [`crate::auth::totp`]
+  pub totp_policy: Option<String>,
+  /// Authenticator models members may register, empty to allow any. See
+  /// [`crate::auth::attestation`]
+  pub allowed_aaguids: Vec<Uuid>,
+  /// Authenticator models members may never register
+  pub denied_aaguids: Vec<Uuid>,
+  /// Users who sign up on their own join this group, see [`crate::auth::signup`]
+  pub signup_default: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -32,7 +41,8 @@ impl IdentityGroup {
       IdentityGroup,
       r#"
         SELECT 
-          id, slug, name, description, is_managed
+          id, slug, name, description, is_managed, totp_policy, allowed_aaguids, denied_aaguids,
+          signup_default
         FROM permission_groups
       "#
     )
@@ -41,12 +51,28 @@ impl IdentityGroup {
     Ok(groups)
   }
 
+  pub async fn get_signup_defaults(pool: &PgPool) -> Result<Vec<IdentityGroup>, Box<dyn Error>> {
+    let groups = sqlx::query_as!(
+      IdentityGroup,
+      r#"
+        SELECT 
+          id, slug, name, description, is_managed, totp_policy, allowed_aaguids, denied_aaguids,
+          signup_default
+        FROM permission_groups WHERE signup_default
+      "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(groups)
+  }
+
   pub async fn from_group_id(pool: &PgPool, id: i32) -> Result<IdentityGroup, Box<dyn Error>> {
     let group = sqlx::query_as!(
       IdentityGroup,
       r#"
         SELECT 
-          id, slug, name, description, is_managed
+          id, slug, name, description, is_managed, totp_policy, allowed_aaguids, denied_aaguids,
+          signup_default
         FROM permission_groups WHERE id = $1
       "#,
       id
@@ -61,7 +87,8 @@ impl IdentityGroup {
       IdentityGroup,
       r#"
         SELECT 
-          id, slug, name, description, is_managed
+          id, slug, name, description, is_managed, totp_policy, allowed_aaguids, denied_aaguids,
+          signup_default
         FROM permission_groups WHERE slug = $1
       "#,
       slug
@@ -74,13 +101,17 @@ impl IdentityGroup {
   pub async fn create(&mut self, pool: &PgPool) -> Result<&IdentityGroup, Box<dyn Error>> {
     let id = sqlx::query_scalar!(
       r#"
-        INSERT INTO permission_groups(slug, name, description, is_managed) VALUES 
-          ($1, $2, $3, $4) RETURNING id
+        INSERT INTO permission_groups(slug, name, description, is_managed, totp_policy, allowed_aaguids, denied_aaguids, signup_default) VALUES 
+          ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id
       "#,
       self.slug,
       self.name,
       self.description,
-      self.is_managed
+      self.is_managed,
+      self.totp_policy,
+      &self.allowed_aaguids,
+      &self.denied_aaguids,
+      self.signup_default
     )
     .fetch_one(pool)
     .await?;
@@ -92,13 +123,17 @@ impl IdentityGroup {
   pub async fn update(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
     sqlx::query!(
       r#"
-        UPDATE permission_groups SET slug=$1, name=$2, description=$3, is_managed=$4
-        WHERE id=$5
+        UPDATE permission_groups SET slug=$1, name=$2, description=$3, is_managed=$4, totp_policy=$5, allowed_aaguids=$6, denied_aaguids=$7, signup_default=$8
+        WHERE id=$9
       "#,
       self.slug,
       self.name,
       self.description,
       self.is_managed,
+      self.totp_policy,
+      &self.allowed_aaguids,
+      &self.denied_aaguids,
+      self.signup_default,
       self.id
     )
     .execute(pool)
@@ -3,9 +3,11 @@ use axum::{
   extract::{Path, State},
 };
 use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
 
 use crate::{
   AppState,
+  auth::totp::{TOTP_POLICY_ALLOWED, TOTP_POLICY_FORBIDDEN},
   group::IdentityGroup,
   response::{ApiErr, ApiResponse},
   user::{AdminCtx, User},
@@ -17,6 +19,27 @@ pub struct PartialGroup {
   pub slug: String,
   pub name: String,
   pub description: String,
+  /// "allowed", "forbidden", or null to leave it to the user's other groups
+  #[serde(default)]
+  pub totp_policy: Option<String>,
+  /// Empty to allow any authenticator that isn't denied
+  #[serde(default)]
+  pub allowed_aaguids: Vec<Uuid>,
+  #[serde(default)]
+  pub denied_aaguids: Vec<Uuid>,
+  /// Whether users who sign up on their own join this group
+  #[serde(default)]
+  pub signup_default: bool,
+}
+
+fn validate_totp_policy(totp_policy: &Option<String>) -> Option<ApiErr> {
+  match totp_policy.as_deref() {
+    None | Some(TOTP_POLICY_ALLOWED) | Some(TOTP_POLICY_FORBIDDEN) => None,
+    Some(_) => Some(ApiErr::Other(
+      "invalid_totp_policy".to_string(),
+      "The TOTP policy must be allowed, forbidden or null.".to_string(),
+    )),
+  }
 }
 
 // TODO: pagination maybe?
@@ -51,12 +74,20 @@ pub async fn create_group(
   _: AdminCtx,
   Json(payload): Json<PartialGroup>,
 ) -> ApiResponse<CreateGroupResponse> {
+  if let Some(err) = validate_totp_policy(&payload.totp_policy) {
+    return ApiResponse::Err(err);
+  }
+
   let mut group = IdentityGroup {
     id: 0,
     slug: payload.slug,
     name: payload.name,
     description: payload.description,
     is_managed: false,
+    totp_policy: payload.totp_policy,
+    allowed_aaguids: payload.allowed_aaguids,
+    denied_aaguids: payload.denied_aaguids,
+    signup_default: payload.signup_default,
   };
 
   match group.create(&state.pool).await {
@@ -77,6 +108,10 @@ pub async fn update_group(
   Path(group_id): Path<i32>,
   Json(payload): Json<PartialGroup>,
 ) -> ApiResponse<UpdateGroupResponse> {
+  if let Some(err) = validate_totp_policy(&payload.totp_policy) {
+    return ApiResponse::Err(err);
+  }
+
   let Ok(mut group) = IdentityGroup::from_group_id(&state.pool, group_id).await else {
     return ApiResponse::Err(ApiErr::UnknownGroup);
   };
@@ -88,6 +123,10 @@ pub async fn update_group(
   group.name = payload.name;
   group.description = payload.description;
   group.slug = payload.slug;
+  group.totp_policy = payload.totp_policy;
+  group.allowed_aaguids = payload.allowed_aaguids;
+  group.denied_aaguids = payload.denied_aaguids;
+  group.signup_default = payload.signup_default;
 
   match group.update(&state.pool).await {
     Ok(_) => ApiResponse::Ok(UpdateGroupResponse { group }),
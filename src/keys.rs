@@ -5,6 +5,7 @@ use std::{
   time::UNIX_EPOCH,
 };
 
+use aes_gcm::{Aes256Gcm, aead::KeyInit};
 use base64::prelude::*;
 use rand::RngCore;
 use rsa::{
@@ -40,6 +41,16 @@ fn read_or_gen_hs256_key(out_file: PathBuf) -> Result<String, Box<dyn Error>> {
   }
 }
 
+/// The key is stored base64 encoded like the HS256 keys, and has to be 32 bytes
+fn load_aes256_key(key: String) -> Result<Aes256Gcm, Box<dyn Error>> {
+  let key = BASE64_URL_SAFE
+    .decode(key)
+    .map_err(|_| "TOTP encryption key is not valid base64!")?;
+  let cipher =
+    Aes256Gcm::new_from_slice(&key).map_err(|_| "TOTP encryption key must be 32 bytes!")?;
+  Ok(cipher)
+}
+
 pub fn create_keys(key_dir: String) -> Result<crate::AppPrivateKeys, Box<dyn Error>> {
   tracing::info!(
     "Creating key directory and generating new keys... (this invalidated any pre-existing keys!)"
@@ -65,6 +76,7 @@ pub fn create_keys(key_dir: String) -> Result<crate::AppPrivateKeys, Box<dyn Err
     identity_access_jwt_key: generate_hs256_key(key_path.join("identity_access.key"))?,
     identity_refresh_jwt_key: generate_hs256_key(key_path.join("identity_refresh.key"))?,
     registration_jwt_key: generate_hs256_key(key_path.join("registration.key"))?,
+    totp_cipher: load_aes256_key(generate_hs256_key(key_path.join("totp_encryption.key"))?)?,
   })
 }
 
@@ -113,5 +125,7 @@ pub fn load_keys(key_dir: String) -> Result<crate::AppPrivateKeys, Box<dyn Error
     identity_access_jwt_key: read_or_gen_hs256_key(key_path.join("identity_access.key"))?,
     identity_refresh_jwt_key: read_or_gen_hs256_key(key_path.join("identity_refresh.key"))?,
     registration_jwt_key: read_or_gen_hs256_key(key_path.join("registration.key"))?,
+    // losing this key makes every enrolled TOTP secret unreadable
+    totp_cipher: load_aes256_key(read_or_gen_hs256_key(key_path.join("totp_encryption.key"))?)?,
   })
 }
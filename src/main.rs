@@ -1,14 +1,27 @@
-use std::{collections::HashMap, env, error::Error, path::Path, sync::Arc};
-
+use std::{
+  collections::HashMap,
+  env,
+  error::Error,
+  net::{IpAddr, SocketAddr},
+  path::Path,
+  sync::Arc,
+};
+
+use aes_gcm::Aes256Gcm;
 use axum::Router;
 use http::Method;
 use lettre::{AsyncSmtpTransport, transport::smtp::authentication::Credentials};
 use redis::aio::MultiplexedConnection;
 use rsa::RsaPrivateKey;
 use sqlx::postgres::PgPoolOptions;
-use webauthn_rs::{Webauthn, WebauthnBuilder, prelude::Url};
+use webauthn_rs::{Webauthn, WebauthnBuilder, prelude::{Url, Uuid}};
 
-use crate::{cli::{handle_email_cli, handle_setup_cli}, keys::load_keys};
+use crate::{
+  auth::{attestation::AuthenticatorMetadata, session::purge_expired_sessions_job},
+  cli::{handle_email_cli, handle_setup_cli},
+  keys::load_keys,
+  ratelimit::RateLimit,
+};
 
 pub mod auth;
 pub mod client;
@@ -18,6 +31,7 @@ pub mod group;
 pub mod keys;
 pub mod middleware;
 pub mod oauth;
+pub mod ratelimit;
 pub mod response;
 pub mod smtp;
 pub mod user;
@@ -31,6 +45,8 @@ pub struct AppPrivateKeys {
   pub identity_access_jwt_key: String,
   pub identity_refresh_jwt_key: String,
   pub registration_jwt_key: String,
+  /// Encrypts TOTP secrets, the key is checked when it's loaded
+  pub totp_cipher: Aes256Gcm,
 }
 
 #[derive(Clone)]
@@ -39,6 +55,76 @@ pub struct AppMailer {
   pub sender: String,
 }
 
+/// Lifetimes (in seconds) of everything issued by the OAuth endpoints. These are
+/// the server-wide defaults, clients can override most of them.
+#[derive(Clone)]
+pub struct AppTokenLifetimes {
+  pub authorization_code: u64,
+  pub access_token: u64,
+  pub id_token: u64,
+  /// How long a refresh token can go unused before it expires
+  pub refresh_token_idle: u64,
+  /// How long refresh tokens can keep being rotated after the user authorized
+  pub refresh_token_absolute: u64,
+}
+
+/// Lifetimes (in seconds) of identity sessions, i.e. logins to this server
+#[derive(Clone)]
+pub struct AppSessionLifetimes {
+  /// How long a session can go without being refreshed before it expires
+  pub idle: u64,
+  /// How long a session can be kept alive by refreshing it
+  pub absolute: u64,
+  /// Like absolute, but for sessions handed off to another device with a QR
+  /// code. These are meant for shared devices, so they should be short.
+  pub handoff: u64,
+  /// Like absolute, but for sessions where an admin acts as a user, see
+  /// [`crate::user::impersonation`]
+  pub impersonation: u64,
+  /// How recently the user must have used a passkey for sensitive admin
+  /// actions, see [`crate::user::SudoCtx`]
+  pub sudo: u64,
+}
+
+/// Restrictions on which authenticators can be registered, see
+/// [`crate::auth::attestation`]
+#[derive(Clone)]
+pub struct AppAuthenticatorConfig {
+  /// Loaded from a FIDO MDS blob, empty if none is configured
+  pub metadata: Arc<AuthenticatorMetadata>,
+  /// These apply to admins on top of their groups' lists
+  pub admin_allowed_aaguids: Vec<Uuid>,
+  pub admin_denied_aaguids: Vec<Uuid>,
+}
+
+/// Self-service signup, see [`crate::auth::signup`]
+#[derive(Clone)]
+pub struct AppSignupConfig {
+  pub enabled: bool,
+  /// Anyone with an email on one of these domains may sign up without an
+  /// invite code. Lowercase, without the @.
+  pub allowed_email_domains: Vec<String>,
+}
+
+/// Rate limits, see [`crate::ratelimit`]
+#[derive(Clone)]
+pub struct AppRateLimits {
+  /// Per IP for all login methods, and failed passkey checks per user and IP
+  pub login: RateLimit,
+  /// Per IP for refreshing identity sessions
+  pub refresh: RateLimit,
+  /// Per IP for anything that registers a passkey
+  pub registration: RateLimit,
+  /// Per client for the OAuth token endpoint
+  pub oauth_token: RateLimit,
+  /// Per IP for the OAuth endpoints clients authenticate to
+  pub client_auth: RateLimit,
+  /// How many wrong secrets one IP can send a client before it's locked out
+  pub client_secret_failures: RateLimit,
+  /// How long (in seconds) a client stays locked out
+  pub client_lockout: u64,
+}
+
 #[derive(Clone)]
 pub struct AppState {
   pub pool: sqlx::PgPool,
@@ -47,6 +133,17 @@ pub struct AppState {
   pub mailer: Option<AppMailer>,
   pub oidc_issuer_uri: String,
   pub redis_connection: MultiplexedConnection,
+  pub token_lifetimes: AppTokenLifetimes,
+  pub session_lifetimes: AppSessionLifetimes,
+  /// Whether users are emailed when a session is terminated because its
+  /// refresh token was reused
+  pub notify_session_reuse: bool,
+  /// Addresses of the reverse proxies in front of the server. X-Forwarded-For
+  /// is ignored on requests that don't come from one of these.
+  pub trusted_proxies: Vec<IpAddr>,
+  pub authenticators: AppAuthenticatorConfig,
+  pub rate_limits: AppRateLimits,
+  pub signup: AppSignupConfig,
 }
 
 fn extract_from_env(key: &'static str, default: &'static str) -> String {
@@ -63,6 +160,41 @@ fn extract_from_env(key: &'static str, default: &'static str) -> String {
   }
 }
 
+fn extract_seconds_from_env(key: &'static str, default: &'static str) -> u64 {
+  extract_from_env(key, default)
+    .parse::<u64>()
+    .unwrap_or_else(|_| panic!("{} must be a number of seconds", key))
+}
+
+fn extract_rate_limit_from_env(key: &'static str, default: &'static str) -> RateLimit {
+  RateLimit::parse(&extract_from_env(key, default))
+    .unwrap_or_else(|| panic!("{} must be a rate limit like \"10/60\" (requests/seconds)", key))
+}
+
+fn extract_ips_from_env(key: &'static str) -> Vec<IpAddr> {
+  extract_from_env(key, "")
+    .split(',')
+    .map(|x| x.trim())
+    .filter(|x| !x.is_empty())
+    .map(|x| {
+      x.parse::<IpAddr>()
+        .unwrap_or_else(|_| panic!("{} must be a comma separated list of IP addresses", key))
+    })
+    .collect()
+}
+
+fn extract_aaguids_from_env(key: &'static str) -> Vec<Uuid> {
+  extract_from_env(key, "")
+    .split(',')
+    .map(|x| x.trim())
+    .filter(|x| !x.is_empty())
+    .map(|x| {
+      Uuid::parse_str(x)
+        .unwrap_or_else(|_| panic!("{} must be a comma separated list of AAGUIDs", key))
+    })
+    .collect()
+}
+
 async fn shutdown_signal() {
   let ctrl_c = async {
     tokio::signal::ctrl_c()
@@ -116,6 +248,56 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
   let redis_url = extract_from_env("REDIS_URL", "redis://valkey:6379/");
 
+  let session_lifetimes = AppSessionLifetimes {
+    idle: extract_seconds_from_env("SESSION_IDLE_LIFETIME", "2592000"),
+    absolute: extract_seconds_from_env("SESSION_ABSOLUTE_LIFETIME", "7776000"),
+    handoff: extract_seconds_from_env("SESSION_HANDOFF_LIFETIME", "3600"),
+    impersonation: extract_seconds_from_env("SESSION_IMPERSONATION_LIFETIME", "1800"),
+    sudo: extract_seconds_from_env("SESSION_SUDO_LIFETIME", "600"),
+  };
+
+  let trusted_proxies = extract_ips_from_env("TRUSTED_PROXIES");
+
+  let fido_mds_path = extract_from_env("FIDO_MDS_PATH", "");
+  let authenticators = AppAuthenticatorConfig {
+    metadata: Arc::new(match fido_mds_path.as_str() {
+      "" => AuthenticatorMetadata::default(),
+      path => AuthenticatorMetadata::from_file(path).expect("Failed to load FIDO MDS blob!"),
+    }),
+    admin_allowed_aaguids: extract_aaguids_from_env("ADMIN_ALLOWED_AAGUIDS"),
+    admin_denied_aaguids: extract_aaguids_from_env("ADMIN_DENIED_AAGUIDS"),
+  };
+
+  let signup = AppSignupConfig {
+    enabled: extract_from_env("SIGNUP_ENABLED", "0") != "0",
+    allowed_email_domains: extract_from_env("SIGNUP_ALLOWED_EMAIL_DOMAINS", "")
+      .split(',')
+      .map(|x| x.trim().trim_start_matches('@').to_lowercase())
+      .filter(|x| !x.is_empty())
+      .collect(),
+  };
+
+  let rate_limits = AppRateLimits {
+    login: extract_rate_limit_from_env("RATE_LIMIT_LOGIN", "10/60"),
+    refresh: extract_rate_limit_from_env("RATE_LIMIT_REFRESH", "30/60"),
+    registration: extract_rate_limit_from_env("RATE_LIMIT_REGISTRATION", "10/300"),
+    oauth_token: extract_rate_limit_from_env("RATE_LIMIT_OAUTH_TOKEN", "60/60"),
+    client_auth: extract_rate_limit_from_env("RATE_LIMIT_CLIENT_AUTH", "60/60"),
+    client_secret_failures: extract_rate_limit_from_env("CLIENT_SECRET_FAILURE_LIMIT", "5/300"),
+    client_lockout: extract_seconds_from_env("CLIENT_LOCKOUT_DURATION", "900"),
+  };
+
+  let token_lifetimes = AppTokenLifetimes {
+    authorization_code: extract_seconds_from_env("OAUTH_CODE_LIFETIME", "300"),
+    access_token: extract_seconds_from_env("OAUTH_ACCESS_TOKEN_LIFETIME", "3600"),
+    id_token: extract_seconds_from_env("OAUTH_ID_TOKEN_LIFETIME", "3600"),
+    refresh_token_idle: extract_seconds_from_env("OAUTH_REFRESH_TOKEN_IDLE_LIFETIME", "1209600"),
+    refresh_token_absolute: extract_seconds_from_env(
+      "OAUTH_REFRESH_TOKEN_ABSOLUTE_LIFETIME",
+      "7776000",
+    ),
+  };
+
   let frontend_dir = Path::new(&frontend_str);
 
   let postgres_url = format!(
@@ -164,10 +346,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
     mailer,
     oidc_issuer_uri,
     redis_connection,
+    token_lifetimes,
+    session_lifetimes,
+    notify_session_reuse: extract_from_env("SESSION_REUSE_NOTIFY", "1") != "0",
+    trusted_proxies,
+    authenticators,
+    rate_limits,
+    signup,
   };
 
   let cli_args: Vec<String> = env::args().collect();
-  if cli_args.len() == 0 {
+  if cli_args.is_empty() {
     panic!("No command line arguments provided! Valid options: serve, setup, send-login-link");
   }
 
@@ -186,6 +375,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
   }
 
+  tokio::spawn(purge_expired_sessions_job(state.clone()));
+
   let cors_origin = extract_from_env("CORS_ORIGIN", "");
   let cors = if cors_origin.is_empty() {
     tower_http::cors::CorsLayer::new()
@@ -226,7 +417,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
   let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
   tracing::info!("Listening on port 3000");
-  axum::serve(listener, app)
+  axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
     .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
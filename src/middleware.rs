@@ -1,11 +1,16 @@
 use axum::{
-  extract::{Request, State},
+  extract::{FromRequestParts, Request, State},
   http::StatusCode,
   middleware::Next,
   response::Response,
 };
 
-use crate::{AppState, auth};
+use crate::{
+  AppState,
+  auth::{self, identity::IdentityAccessClaims},
+  user::impersonation::ImpersonationAction,
+  util::RequestMetadata,
+};
 
 fn process_auth_header(state: &AppState, request: &mut Request) {
   let auth_header = match request.headers().get("authorization") {
@@ -36,5 +41,37 @@ pub async fn identity_auth(
   next: Next,
 ) -> Result<Response, StatusCode> {
   process_auth_header(&state, &mut request);
-  Ok(next.run(request).await)
+
+  let Some(claims) = request
+    .extensions()
+    .get::<IdentityAccessClaims>()
+    .filter(|x| x.is_impersonated())
+    .cloned()
+  else {
+    return Ok(next.run(request).await);
+  };
+
+  // everything done while impersonating someone is recorded, and nothing is
+  // done if it can't be
+  let method = request.method().to_string();
+  let path = request.uri().path().to_string();
+  let (mut parts, body) = request.into_parts();
+  let Ok(metadata) = RequestMetadata::from_request_parts(&mut parts, &state).await;
+  let Ok(action_id) =
+    ImpersonationAction::record(&state.pool, &claims, &method, &path, &metadata).await
+  else {
+    tracing::error!("Failed to record impersonation action, refusing the request");
+    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+  };
+
+  let response = next.run(Request::from_parts(parts, body)).await;
+
+  if ImpersonationAction::record_status(&state.pool, action_id, response.status().as_u16())
+    .await
+    .is_err()
+  {
+    tracing::error!("Failed to record impersonation action status");
+  }
+
+  Ok(response)
 }
@@ -43,6 +43,36 @@ impl UserAppAuthorization {
     Ok(())
   }
 
+  /// Creates the authorization if the user doesn't have one for the client yet,
+  /// and returns the stored one either way. Unlike
+  /// [`UserAppAuthorization::authorize_for_user`], this never undoes a
+  /// revocation made by the user.
+  pub async fn create_if_missing(
+    pool: &PgPool,
+    user_id: i32,
+    client_id: String,
+  ) -> Result<UserAppAuthorization, Box<dyn Error>> {
+    let timestamp = std::time::SystemTime::now()
+      .duration_since(std::time::SystemTime::UNIX_EPOCH)
+      .expect("time has somehow gone backwards...")
+      .as_secs();
+
+    sqlx::query!(
+      r#"
+        INSERT INTO user_app_authorizations(user_id, client_id, sub, last_used, revoked) VALUES ($1, $2, $3, $4, FALSE)
+        ON CONFLICT (user_id, client_id) DO NOTHING
+      "#,
+      user_id,
+      client_id,
+      Alphanumeric.sample_string(&mut rand::thread_rng(), 64),
+      timestamp as i64
+    )
+    .execute(pool)
+    .await?;
+
+    UserAppAuthorization::get_authorization(pool, user_id, client_id).await
+  }
+
   pub async fn revoke_app_authorization(
     pool: &PgPool,
     user_id: i32,
@@ -0,0 +1,88 @@
+// Everything the authorization server supports. Handlers check requests against
+// these, and the discovery documents are generated from them, so the two can't
+// drift apart. Only add something here once it is actually implemented!
+
+use jsonwebtoken::Algorithm;
+
+use crate::{
+  auth::identity::{PASSKEY_METHOD, TOTP_METHOD},
+  oauth::{dpop::DPOP_SIGNING_ALGS, exchange::TOKEN_EXCHANGE_GRANT_TYPE},
+};
+
+pub const AUTHORIZATION_ENDPOINT: &str = "/v1/oauth/authorize";
+pub const TOKEN_ENDPOINT: &str = "/v1/oauth/token";
+pub const USERINFO_ENDPOINT: &str = "/v1/oauth/userinfo";
+pub const INTROSPECTION_ENDPOINT: &str = "/v1/oauth/introspect";
+pub const END_SESSION_ENDPOINT: &str = "/v1/oauth/logout";
+pub const JWKS_ENDPOINT: &str = "/.well-known/jwks";
+
+/// The frontend page that renders the consent screen for authorization requests
+pub const AUTHORIZATION_PAGE: &str = "/oauth/authorize";
+/// The frontend page that asks the user to confirm signing out for a client
+pub const END_SESSION_PAGE: &str = "/oauth/logout";
+
+pub const SCOPES_SUPPORTED: [&str; 3] = ["openid", "profile", "email"];
+
+/// Individual response types, any combination of these can be requested
+pub const RESPONSE_TYPES_SUPPORTED: [&str; 3] = ["code", "id_token", "token"];
+pub const RESPONSE_MODES_SUPPORTED: [&str; 2] = ["query", "fragment"];
+
+/// Implicit flows are the `token` and `id_token` response types, so this is
+/// advertised but never accepted by the token endpoint
+pub const IMPLICIT_GRANT_TYPE: &str = "implicit";
+
+pub const GRANT_TYPES_SUPPORTED: [&str; 4] = [
+  "authorization_code",
+  IMPLICIT_GRANT_TYPE,
+  "refresh_token",
+  TOKEN_EXCHANGE_GRANT_TYPE,
+];
+
+/// Every client gets its own randomly generated sub for a user
+pub const SUBJECT_TYPES_SUPPORTED: [&str; 1] = ["pairwise"];
+
+pub const ID_TOKEN_SIGNING_ALGS_SUPPORTED: [Algorithm; 1] = [Algorithm::RS256];
+pub const USERINFO_SIGNING_ALGS_SUPPORTED: [Algorithm; 1] = [Algorithm::RS256];
+pub const DPOP_SIGNING_ALGS_SUPPORTED: [Algorithm; 4] = DPOP_SIGNING_ALGS;
+
+/// Used for the token and introspection endpoints
+pub const CLIENT_AUTH_METHODS_SUPPORTED: [&str; 2] = ["client_secret_basic", "client_secret_post"];
+
+/// Claims that can show up in id tokens and userinfo responses
+pub const CLAIMS_SUPPORTED: [&str; 14] = [
+  "iss",
+  "sub",
+  "aud",
+  "exp",
+  "iat",
+  "auth_time",
+  "acr",
+  "nonce",
+  "name",
+  "preferred_username",
+  "email",
+  "email_verified",
+  "groups",
+  "roles",
+];
+
+/// Sign-in methods, which show up as the acr claim and can be required through
+/// the acr_values authorization parameter
+pub const ACR_VALUES_SUPPORTED: [&str; 2] = [PASSKEY_METHOD, TOTP_METHOD];
+
+/// Every combination of [`RESPONSE_TYPES_SUPPORTED`], in the order they are
+/// usually written in (e.g. "code id_token token").
+pub fn response_type_combinations() -> Vec<String> {
+  let mut combinations = Vec::new();
+  for mask in 1..(1u32 << RESPONSE_TYPES_SUPPORTED.len()) {
+    let combination = RESPONSE_TYPES_SUPPORTED
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| mask & (1 << i) != 0)
+      .map(|(_, response_type)| *response_type)
+      .collect::<Vec<&str>>();
+    combinations.push(combination.join(" "));
+  }
+  combinations.sort_by_key(|x| x.split_whitespace().count());
+  combinations
+}
@@ -12,6 +12,9 @@ pub struct OauthCodeData {
   pub client_id: String,
   pub nonce: Option<String>,
   pub redirect_uri: String,
+  /// How the user signed in when approving, see [`crate::oauth::OidcIdTokenClaims`]
+  #[serde(default)]
+  pub acr: Option<String>,
 }
 
 impl OauthCodeData {
@@ -37,7 +40,7 @@ impl OauthCodeData {
     let _: () = state
       .redis_connection
       .clone()
-      .set_ex(key, value, 300)
+      .set_ex(key, value, state.token_lifetimes.authorization_code)
       .await?;
     Ok(oauth_code)
   }
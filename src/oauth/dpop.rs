@@ -0,0 +1,281 @@
+// DPoP (RFC 9449) proof validation. Tokens issued alongside a valid proof are
+// bound to the thumbprint of the proof's key (cnf.jkt), and can only be used
+// together with a fresh proof signed by that same key.
+
+use std::{
+  collections::HashSet,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+  Json,
+  response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use jsonwebtoken::{
+  Algorithm, DecodingKey, Validation,
+  jwk::{AlgorithmParameters, ThumbprintHash},
+};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use webauthn_rs::prelude::Url;
+
+use crate::{AppState, oauth::routes::get_oauth_error};
+
+pub const DPOP_PROOF_TYPE: &str = "dpop+jwt";
+pub const DPOP_SIGNING_ALGS: [Algorithm; 4] = [
+  Algorithm::ES256,
+  Algorithm::ES384,
+  Algorithm::RS256,
+  Algorithm::PS256,
+];
+
+/// How old (in seconds) a proof's `iat` may be before it is rejected
+const DPOP_MAX_AGE: u64 = 300;
+/// How far (in seconds) a proof's `iat` may be in the future to allow for clock skew
+const DPOP_MAX_SKEW: u64 = 60;
+
+#[derive(Deserialize)]
+pub struct DpopProofClaims {
+  pub jti: String,
+  pub htm: String,
+  pub htu: String,
+  pub iat: u64,
+  pub ath: Option<String>,
+}
+
+pub enum DpopError {
+  /// The access token was presented with the wrong authorization scheme
+  InvalidToken,
+  InvalidProof(&'static str),
+  InternalServerError,
+}
+
+impl DpopError {
+  /// Error response for the token endpoint
+  pub fn into_token_response(self) -> Response {
+    match self {
+      DpopError::InternalServerError => (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(get_oauth_error(
+          "internal_server_error",
+          "Something went wrong!",
+        )),
+      )
+        .into_response(),
+      DpopError::InvalidToken => (
+        StatusCode::BAD_REQUEST,
+        Json(get_oauth_error(
+          "invalid_dpop_proof",
+          "DPoP proof not valid",
+        )),
+      )
+        .into_response(),
+      DpopError::InvalidProof(description) => (
+        StatusCode::BAD_REQUEST,
+        Json(get_oauth_error("invalid_dpop_proof", description)),
+      )
+        .into_response(),
+    }
+  }
+
+  /// Error response for endpoints that accept access tokens (e.g. userinfo)
+  pub fn into_resource_response(self) -> Response {
+    let challenge = match self {
+      DpopError::InternalServerError => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+      DpopError::InvalidToken => "DPoP error=\"invalid_token\"".to_string(),
+      DpopError::InvalidProof(description) => format!(
+        "DPoP error=\"invalid_dpop_proof\", error_description=\"{}\"",
+        description
+      ),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      "www-authenticate",
+      HeaderValue::from_str(&challenge).expect("invalid www-authenticate header"),
+    );
+    (StatusCode::UNAUTHORIZED, headers).into_response()
+  }
+}
+
+/// Returns the DPoP proof sent with a request, if any. Sending more than one
+/// proof is not allowed.
+pub fn get_dpop_proof(headers: &HeaderMap) -> Result<Option<String>, DpopError> {
+  let mut proofs = headers.get_all("dpop").iter();
+  let Some(proof) = proofs.next() else {
+    return Ok(None);
+  };
+
+  if proofs.next().is_some() {
+    return Err(DpopError::InvalidProof(
+      "Only one DPoP proof may be provided",
+    ));
+  }
+
+  match proof.to_str() {
+    Ok(proof) => Ok(Some(proof.to_string())),
+    Err(_) => Err(DpopError::InvalidProof("DPoP proof is not a valid JWT")),
+  }
+}
+
+/// The `ath` claim value for an access token
+pub fn access_token_hash(access_token: &str) -> String {
+  BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes()))
+}
+
+fn htu_matches(htu: &str, expected: &str) -> bool {
+  let (Ok(mut htu), Ok(mut expected)) = (Url::parse(htu), Url::parse(expected)) else {
+    return false;
+  };
+
+  // query and fragment are ignored when comparing htu
+  for url in [&mut htu, &mut expected] {
+    url.set_query(None);
+    url.set_fragment(None);
+  }
+
+  htu == expected
+}
+
+/// Validates a DPoP proof for a request to `htu` with method `htm`, returning
+/// the JWK thumbprint of the key that signed it. `access_token` must be set when
+/// the proof is presented alongside an access token so `ath` can be checked.
+pub async fn validate_dpop_proof(
+  state: &AppState,
+  proof: &str,
+  htm: &str,
+  htu: &str,
+  access_token: Option<&str>,
+) -> Result<String, DpopError> {
+  let Ok(header) = jsonwebtoken::decode_header(proof) else {
+    return Err(DpopError::InvalidProof("DPoP proof is not a valid JWT"));
+  };
+
+  if header.typ.as_deref() != Some(DPOP_PROOF_TYPE) {
+    return Err(DpopError::InvalidProof("DPoP proof must have typ dpop+jwt"));
+  }
+
+  if !DPOP_SIGNING_ALGS.contains(&header.alg) {
+    return Err(DpopError::InvalidProof(
+      "DPoP proof uses an unsupported alg",
+    ));
+  }
+
+  let Some(jwk) = header.jwk else {
+    return Err(DpopError::InvalidProof("DPoP proof is missing its jwk"));
+  };
+
+  // only asymmetric keys make sense here, and we don't support EdDSA
+  if !matches!(
+    jwk.algorithm,
+    AlgorithmParameters::RSA(_) | AlgorithmParameters::EllipticCurve(_)
+  ) {
+    return Err(DpopError::InvalidProof(
+      "DPoP proof uses an unsupported jwk",
+    ));
+  }
+
+  let Ok(decoding_key) = DecodingKey::from_jwk(&jwk) else {
+    return Err(DpopError::InvalidProof(
+      "DPoP proof uses an unsupported jwk",
+    ));
+  };
+
+  // proofs don't carry exp, freshness is checked against iat below
+  let mut validation = Validation::new(header.alg);
+  validation.validate_exp = false;
+  validation.required_spec_claims = HashSet::new();
+
+  let Ok(decoded_proof) =
+    jsonwebtoken::decode::<DpopProofClaims>(proof, &decoding_key, &validation)
+  else {
+    return Err(DpopError::InvalidProof("DPoP proof signature is not valid"));
+  };
+  let claims = decoded_proof.claims;
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs();
+
+  if claims.iat + DPOP_MAX_AGE < now || claims.iat > now + DPOP_MAX_SKEW {
+    return Err(DpopError::InvalidProof("DPoP proof has expired"));
+  }
+
+  if !claims.htm.eq_ignore_ascii_case(htm) || !htu_matches(&claims.htu, htu) {
+    return Err(DpopError::InvalidProof(
+      "DPoP proof was not created for this request",
+    ));
+  }
+
+  if let Some(access_token) = access_token
+    && claims.ath.as_deref() != Some(access_token_hash(access_token).as_str())
+  {
+    return Err(DpopError::InvalidProof(
+      "DPoP proof was not created for this access token",
+    ));
+  }
+
+  let jkt = jwk.thumbprint(ThumbprintHash::SHA256);
+
+  // a proof can only be used once while it is fresh, so we keep its jti around
+  // for at least as long as it would be accepted.
+  let key = format!("dpop_jti:{}:{}", jkt, claims.jti);
+  let options = SetOptions::default()
+    .conditional_set(ExistenceCheck::NX)
+    .with_expiration(SetExpiry::EX(DPOP_MAX_AGE + DPOP_MAX_SKEW));
+  let Ok(inserted): Result<Option<String>, _> = state
+    .redis_connection
+    .clone()
+    .set_options(key, 1, options)
+    .await
+  else {
+    return Err(DpopError::InternalServerError);
+  };
+
+  if inserted.is_none() {
+    return Err(DpopError::InvalidProof("DPoP proof has already been used"));
+  }
+
+  Ok(jkt)
+}
+
+/// Checks that an access token was presented correctly for its binding: unbound
+/// tokens must use the Bearer scheme, and bound tokens must use the DPoP scheme
+/// with a proof from the key they are bound to.
+pub async fn verify_token_binding(
+  state: &AppState,
+  headers: &HeaderMap,
+  scheme: &str,
+  access_token: &str,
+  jkt: &Option<String>,
+  htm: &str,
+  htu: &str,
+) -> Result<(), DpopError> {
+  let Some(jkt) = jkt else {
+    if !scheme.eq_ignore_ascii_case("bearer") {
+      return Err(DpopError::InvalidToken);
+    }
+    return Ok(());
+  };
+
+  if !scheme.eq_ignore_ascii_case("dpop") {
+    return Err(DpopError::InvalidToken);
+  }
+
+  let Some(proof) = get_dpop_proof(headers)? else {
+    return Err(DpopError::InvalidProof("A DPoP proof is required"));
+  };
+
+  let proof_jkt = validate_dpop_proof(state, &proof, htm, htu, Some(access_token)).await?;
+  if &proof_jkt != jkt {
+    return Err(DpopError::InvalidProof(
+      "DPoP proof was not signed by the key this token is bound to",
+    ));
+  }
+
+  Ok(())
+}
@@ -0,0 +1,230 @@
+// Token exchange (RFC 8693), used by gateways to trade a user's access token for
+// one scoped to a downstream service while keeping the user as the subject.
+
+use axum::{
+  Json,
+  response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use serde::Serialize;
+
+use crate::{
+  AppState,
+  client::{IdentityClient, exchange::TokenExchangePolicy},
+  oauth::{
+    authorization::UserAppAuthorization,
+    routes::{OauthTokenRequest, get_oauth_error},
+    token::{OauthAccessTokenData, OauthTokenActor},
+  },
+  user::User,
+};
+
+pub const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+#[derive(Serialize)]
+pub struct OauthTokenExchangeResponse {
+  pub access_token: String,
+  pub issued_token_type: String,
+  pub token_type: String,
+  pub expires_in: u64,
+}
+
+fn bad_request(name: &'static str, description: &'static str) -> Response {
+  (
+    StatusCode::BAD_REQUEST,
+    Json(get_oauth_error(name, description)),
+  )
+    .into_response()
+}
+
+fn internal_server_error() -> Response {
+  (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    Json(get_oauth_error(
+      "internal_server_error",
+      "Something went wrong!",
+    )),
+  )
+    .into_response()
+}
+
+/// Handles the token exchange grant for an already authenticated `client`. The
+/// subject token must have been issued to `client`, and the exchange must be
+/// allowed by an admin-configured [`TokenExchangePolicy`].
+pub async fn exchange_token(
+  state: &AppState,
+  client: &IdentityClient,
+  payload: OauthTokenRequest,
+  jkt: Option<String>,
+) -> Response {
+  let (Some(subject_token), Some(subject_token_type), Some(audience)) = (
+    payload.subject_token,
+    payload.subject_token_type,
+    payload.audience,
+  ) else {
+    return bad_request(
+      "invalid_request",
+      "subject_token, subject_token_type and audience are required for token exchange",
+    );
+  };
+
+  if subject_token_type != ACCESS_TOKEN_TYPE {
+    return bad_request(
+      "invalid_request",
+      "Only access tokens are supported as subject_token_type",
+    );
+  }
+
+  if let Some(requested_token_type) = payload.requested_token_type
+    && requested_token_type != ACCESS_TOKEN_TYPE
+  {
+    return bad_request(
+      "invalid_request",
+      "Only access tokens can be requested through token exchange",
+    );
+  }
+
+  let Ok(allowed) = TokenExchangePolicy::is_exchange_allowed(
+    &state.pool,
+    client.client_id.clone(),
+    audience.clone(),
+  )
+  .await
+  else {
+    return internal_server_error();
+  };
+
+  if !allowed {
+    return bad_request(
+      "invalid_target",
+      "This client is not allowed to exchange tokens for the requested audience",
+    );
+  }
+
+  let Ok(subject_opt) = OauthAccessTokenData::from_token(state, subject_token).await else {
+    return internal_server_error();
+  };
+
+  let subject_not_valid = bad_request("invalid_grant", "Subject token not valid");
+
+  let Some(subject) = subject_opt else {
+    return subject_not_valid;
+  };
+
+  if subject.client_id != client.client_id {
+    return subject_not_valid;
+  }
+
+  // a bound subject token can only be exchanged by the holder of its key, or
+  // anyone holding a stolen one could trade it for an unbound token
+  if subject.jkt.is_some() && subject.jkt != jkt {
+    return bad_request(
+      "invalid_dpop_proof",
+      "The subject token is DPoP-bound, a proof with its key is required",
+    );
+  }
+
+  let Ok(user) = User::from_user_id(&state.pool, subject.user_id).await else {
+    return subject_not_valid;
+  };
+
+  if user.is_suspended {
+    return subject_not_valid;
+  }
+
+  let Ok(subject_auth) =
+    UserAppAuthorization::get_authorization(&state.pool, user.id, client.client_id.clone()).await
+  else {
+    return subject_not_valid;
+  };
+
+  if subject_auth.revoked {
+    return subject_not_valid;
+  }
+
+  let Ok(target_client) = IdentityClient::from_client_id(&state.pool, audience).await else {
+    return bad_request("invalid_target", "The requested audience does not exist");
+  };
+
+  if target_client.is_disabled {
+    return bad_request("invalid_target", "The requested audience is disabled");
+  }
+
+  if target_client.require_dpop && jkt.is_none() {
+    return bad_request(
+      "invalid_dpop_proof",
+      "The requested audience requires a DPoP proof when requesting tokens",
+    );
+  }
+
+  let Ok(groups) = user.get_groups(&state.pool).await else {
+    return internal_server_error();
+  };
+
+  let Ok(user_permission) = target_client
+    .is_user_allowed(&state.pool, &user, &groups)
+    .await
+  else {
+    return internal_server_error();
+  };
+
+  if !user_permission {
+    return bad_request(
+      "invalid_grant",
+      "The user is not allowed to access the requested audience",
+    );
+  }
+
+  // the exchanged token needs a pairwise sub for the target. we only create an
+  // authorization if the user has never used the target directly, and never
+  // undo a revocation made by the user.
+  let Ok(target_auth) =
+    UserAppAuthorization::create_if_missing(&state.pool, user.id, target_client.client_id.clone())
+      .await
+  else {
+    return internal_server_error();
+  };
+
+  if target_auth.revoked {
+    return bad_request(
+      "invalid_grant",
+      "The user has revoked access to the requested audience",
+    );
+  }
+
+  let access_token_data = OauthAccessTokenData {
+    user_id: user.id,
+    client_id: target_client.client_id.clone(),
+    nonce: None,
+    act: Some(OauthTokenActor {
+      client_id: client.client_id.clone(),
+      act: subject.act.map(Box::new),
+    }),
+    jkt: jkt.clone(),
+    acr: subject.acr,
+  };
+
+  // the exchanged token is used against the target, so its lifetime applies
+  let lifetimes = target_client.get_token_lifetimes(&state.token_lifetimes);
+  let Ok(access_token) = access_token_data
+    .save_to_token(state, lifetimes.access_token)
+    .await
+  else {
+    return internal_server_error();
+  };
+
+  (
+    StatusCode::OK,
+    Json(OauthTokenExchangeResponse {
+      access_token,
+      issued_token_type: ACCESS_TOKEN_TYPE.to_string(),
+      token_type: match jkt {
+        Some(_) => "DPoP".to_string(),
+        None => "Bearer".to_string(),
+      },
+      expires_in: lifetimes.access_token,
+    }),
+  )
+    .into_response()
+}
@@ -0,0 +1,175 @@
+// RP-initiated logout (OpenID Connect RP-Initiated Logout 1.0). Like
+// authorization requests, the advertised endpoint hands the user off to the
+// frontend, which asks them to confirm before their session is ended here.
+// Clients can only send the user back to one of their registered redirect URIs.
+
+use axum::{
+  Extension, Json,
+  extract::{RawQuery, State},
+  response::Redirect,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use webauthn_rs::prelude::Url;
+
+use crate::{
+  AppState,
+  auth::{identity::IdentityAccessClaims, session::UserSession},
+  client::{IdentityClient, redirect::redirect_uri_matches},
+  oauth::{authorization::UserAppAuthorization, capabilities::END_SESSION_PAGE},
+  response::{ApiErr, ApiResponse},
+};
+
+#[derive(Deserialize)]
+struct IdTokenHintClaims {
+  sub: String,
+  aud: String,
+}
+
+#[derive(Deserialize)]
+pub struct EndSessionRequest {
+  pub id_token_hint: Option<String>,
+  pub client_id: Option<String>,
+  pub post_logout_redirect_uri: Option<String>,
+  pub state: Option<String>,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+pub struct EndSessionResponse {
+  /// Where to send the user afterwards, if the client asked for it
+  pub redirect_to: Option<String>,
+}
+
+fn invalid_id_token_hint() -> ApiErr {
+  ApiErr::Other(
+    "invalid_id_token_hint".to_string(),
+    "The app that sent you here didn't identify itself correctly.".to_string(),
+  )
+}
+
+/// Only id tokens issued by this server are accepted. They don't have to be
+/// valid anymore, since the hint usually outlives the token.
+fn decode_id_token_hint(state: &AppState, token: &str) -> Option<IdTokenHintClaims> {
+  let header = jsonwebtoken::decode_header(token).ok()?;
+  let kid = header.kid?.parse::<u64>().ok()?;
+  let private_key = state.private_keys.oidc_jwt_keys.get(&kid)?;
+  let decoding_key = DecodingKey::from_rsa_raw_components(
+    &private_key.n().to_bytes_be(),
+    &private_key.e().to_bytes_be(),
+  );
+
+  // the audience is checked against the client instead
+  let mut validation = Validation::new(Algorithm::RS256);
+  validation.validate_exp = false;
+  validation.validate_aud = false;
+  validation.set_issuer(&[&state.oidc_issuer_uri]);
+
+  let decoded_token =
+    jsonwebtoken::decode::<IdTokenHintClaims>(token, &decoding_key, &validation).ok()?;
+  Some(decoded_token.claims)
+}
+
+/// Checks the request and returns where to send the user afterwards. If the
+/// user is signed in, the hint has to be for them.
+async fn validate_end_session(
+  state: &AppState,
+  claims: Option<&IdentityAccessClaims>,
+  payload: &EndSessionRequest,
+) -> Result<Option<String>, ApiErr> {
+  let hint = match &payload.id_token_hint {
+    Some(token) => Some(decode_id_token_hint(state, token).ok_or_else(invalid_id_token_hint)?),
+    None => None,
+  };
+
+  let client_id = match (&payload.client_id, &hint) {
+    (Some(client_id), Some(hint)) if *client_id != hint.aud => {
+      return Err(invalid_id_token_hint());
+    }
+    (Some(client_id), _) => Some(client_id.clone()),
+    (None, Some(hint)) => Some(hint.aud.clone()),
+    (None, None) => None,
+  };
+
+  if let (Some(hint), Some(claims)) = (&hint, claims) {
+    let Ok(authorization) =
+      UserAppAuthorization::get_authorization(&state.pool, claims.user_id, hint.aud.clone()).await
+    else {
+      return Err(invalid_id_token_hint());
+    };
+    if authorization.sub != hint.sub {
+      return Err(invalid_id_token_hint());
+    }
+  }
+
+  let Some(post_logout_redirect_uri) = &payload.post_logout_redirect_uri else {
+    return Ok(None);
+  };
+
+  // without a client there's nothing to check the redirect against
+  let Some(client_id) = client_id else {
+    return Err(ApiErr::InvalidRedirectUri(post_logout_redirect_uri.clone()));
+  };
+
+  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
+    return Err(ApiErr::UnknownClient);
+  };
+
+  if !client
+    .redirect_uris
+    .iter()
+    .any(|x| redirect_uri_matches(client.is_native_app, x, post_logout_redirect_uri))
+  {
+    return Err(ApiErr::InvalidRedirectUri(post_logout_redirect_uri.clone()));
+  }
+
+  let Ok(mut redirect_to) = Url::parse(post_logout_redirect_uri) else {
+    return Err(ApiErr::InvalidRedirectUri(post_logout_redirect_uri.clone()));
+  };
+
+  if let Some(state) = &payload.state {
+    redirect_to.query_pairs_mut().append_pair("state", state);
+  }
+
+  Ok(Some(redirect_to.to_string()))
+}
+
+/// The advertised end session endpoint, which hands off to the frontend
+pub async fn oauth_end_session(RawQuery(query): RawQuery) -> Redirect {
+  match query {
+    Some(query) => Redirect::to(&format!("{}?{}", END_SESSION_PAGE, query)),
+    None => Redirect::to(END_SESSION_PAGE),
+  }
+}
+
+/// Called by the frontend once the user confirmed. Users that aren't signed in
+/// anymore are just sent back to the app.
+pub async fn oauth_end_session_confirm(
+  State(state): State<AppState>,
+  claims: Option<Extension<IdentityAccessClaims>>,
+  Json(payload): Json<EndSessionRequest>,
+) -> ApiResponse<EndSessionResponse> {
+  let claims = claims.map(|Extension(claims)| claims);
+
+  let redirect_to = match validate_end_session(&state, claims.as_ref(), &payload).await {
+    Ok(redirect_to) => redirect_to,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  let session = match claims {
+    Some(claims) => UserSession::from_session_id(&state.pool, claims.session_id)
+      .await
+      .ok(),
+    None => None,
+  };
+
+  if let Some(mut session) = session
+    && session.delete_session(&state.pool).await.is_err()
+  {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  ApiResponse::Ok(EndSessionResponse { redirect_to })
+}
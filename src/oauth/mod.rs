@@ -7,15 +7,33 @@ use axum::{
 use jsonwebtoken::{EncodingKey, Header};
 use rsa::pkcs8::EncodePrivateKey;
 use serde::Serialize;
+use serde_json::{Map, Value};
 use serde_with::skip_serializing_none;
 
 use crate::{
-  AppState, client::IdentityClient, group::IdentityGroup,
-  oauth::authorization::UserAppAuthorization, user::User,
+  AppState,
+  client::{
+    IdentityClient,
+    claims::{ClientClaimMapping, apply_claim_mappings},
+  },
+  group::IdentityGroup,
+  oauth::{
+    authorization::UserAppAuthorization,
+    capabilities::{
+      AUTHORIZATION_ENDPOINT, END_SESSION_ENDPOINT, INTROSPECTION_ENDPOINT, JWKS_ENDPOINT,
+      TOKEN_ENDPOINT, USERINFO_ENDPOINT,
+    },
+  },
+  user::{User, attributes::UserAttribute},
 };
 
 pub mod authorization;
+pub mod capabilities;
 pub mod code;
+pub mod dpop;
+pub mod exchange;
+pub mod logout;
+pub mod refresh;
 pub mod routes;
 pub mod token;
 pub mod wellknown;
@@ -30,54 +48,83 @@ pub struct OidcIdTokenClaims {
   pub iat: u64,
   // TODO: this will eventually return something that is not a fake value!
   pub auth_time: u64,
+  /// The method the user signed in with when authorizing (passkey or totp), so
+  /// apps can insist on passkeys
+  pub acr: Option<String>,
   pub nonce: Option<String>,
   pub name: String,
   pub preferred_username: String,
   pub email: String,
   pub email_verified: bool,
-  pub groups: Vec<String>,
+  pub groups: Option<Vec<String>>,
   pub roles: Vec<String>,
 }
 
-pub async fn create_id_token(
+/// Builds the claims for an id token or userinfo response, with the client's
+/// claim mappings applied.
+pub async fn get_id_token_claims(
   state: &AppState,
   user: &User,
   client: &IdentityClient,
   groups: Vec<IdentityGroup>,
   nonce: Option<String>,
+  acr: Option<String>,
   authorization: &UserAppAuthorization,
-) -> Result<String, Box<dyn Error>> {
-  let Some(kid) = state.private_keys.oidc_jwt_keys.keys().max() else {
-    panic!("No JWT keys are loaded!");
-  };
-
+) -> Result<Map<String, Value>, Box<dyn Error>> {
   let iat = std::time::SystemTime::now()
     .duration_since(std::time::SystemTime::UNIX_EPOCH)
     .expect("time has somehow gone backwards...")
     .as_secs();
 
   let roles = client.get_user_roles(&state.pool, user, &groups).await?;
-  let groups = groups
-    .iter()
-    .map(|x| x.slug.clone())
-    .collect::<Vec<String>>();
+  let groups = client.get_groups_claim(&state.pool, &groups).await?;
 
   let claims = OidcIdTokenClaims {
     iss: state.oidc_issuer_uri.clone(),
     sub: authorization.sub.clone(),
     aud: client.client_id.clone(),
     iat,
-    exp: iat + 3600,
+    exp: iat + client.get_token_lifetimes(&state.token_lifetimes).id_token,
     auth_time: iat,
+    acr,
     nonce,
     name: user.name.clone(),
     preferred_username: user.username.clone(),
     email: user.email.clone(),
-    email_verified: true,
+    email_verified: user.email_verified,
     groups,
     roles,
   };
 
+  let Value::Object(mut claims) = serde_json::to_value(claims)? else {
+    panic!("id token claims did not serialize to an object");
+  };
+
+  let mappings =
+    ClientClaimMapping::get_mappings_for_client(&state.pool, client.client_id.clone()).await?;
+  if !mappings.is_empty() {
+    let attributes = UserAttribute::get_attribute_map_for_user(&state.pool, user.id).await?;
+    apply_claim_mappings(&mut claims, &mappings, &attributes);
+  }
+
+  Ok(claims)
+}
+
+pub async fn create_id_token(
+  state: &AppState,
+  user: &User,
+  client: &IdentityClient,
+  groups: Vec<IdentityGroup>,
+  nonce: Option<String>,
+  acr: Option<String>,
+  authorization: &UserAppAuthorization,
+) -> Result<String, Box<dyn Error>> {
+  let Some(kid) = state.private_keys.oidc_jwt_keys.keys().max() else {
+    panic!("No JWT keys are loaded!");
+  };
+
+  let claims = get_id_token_claims(state, user, client, groups, nonce, acr, authorization).await?;
+
   let private_key = state.private_keys.oidc_jwt_keys.get(kid).unwrap();
   let private_key_pem = private_key
     .to_pkcs8_pem(rsa::pkcs8::LineEnding::LF)
@@ -102,11 +149,22 @@ pub fn router() -> Router<AppState> {
       "/v1/oauth/authorize/approve",
       post(routes::oauth_authorize_approve),
     )
-    .route("/v1/oauth/token", post(routes::oauth_token))
-    .route("/v1/oauth/userinfo", get(routes::oauth_userinfo))
+    .route(AUTHORIZATION_ENDPOINT, get(routes::oauth_authorize))
+    .route(TOKEN_ENDPOINT, post(routes::oauth_token))
+    .route(USERINFO_ENDPOINT, get(routes::oauth_userinfo))
+    .route(INTROSPECTION_ENDPOINT, post(routes::oauth_introspect))
+    .route(END_SESSION_ENDPOINT, get(logout::oauth_end_session))
+    .route(
+      "/v1/oauth/logout/confirm",
+      post(logout::oauth_end_session_confirm),
+    )
     .route(
       "/.well-known/openid-configuration",
       get(wellknown::openid_configuration),
     )
-    .route("/.well-known/jwks", get(wellknown::jwks))
+    .route(
+      "/.well-known/oauth-authorization-server",
+      get(wellknown::oauth_authorization_server),
+    )
+    .route(JWKS_ENDPOINT, get(wellknown::jwks))
 }
@@ -0,0 +1,187 @@
+// The refresh_token grant. Refresh tokens are rotated on every use: the old one
+// is deleted and a new one is issued, which expires after the client's idle
+// lifetime but never outlives the absolute lifetime of the original login.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+  Json,
+  response::{IntoResponse, Response},
+};
+use http::StatusCode;
+
+use crate::{
+  AppState,
+  client::IdentityClient,
+  oauth::{
+    authorization::UserAppAuthorization,
+    capabilities::SCOPES_SUPPORTED,
+    create_id_token,
+    routes::{OauthTokenRequest, OauthTokenResponse, get_oauth_error},
+    token::{OauthAccessTokenData, OauthRefreshTokenData},
+  },
+  user::User,
+};
+
+fn bad_request(name: &'static str, description: &'static str) -> Response {
+  (
+    StatusCode::BAD_REQUEST,
+    Json(get_oauth_error(name, description)),
+  )
+    .into_response()
+}
+
+fn internal_server_error() -> Response {
+  (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    Json(get_oauth_error(
+      "internal_server_error",
+      "Something went wrong!",
+    )),
+  )
+    .into_response()
+}
+
+/// Handles the refresh_token grant for an already authenticated `client`. Tokens
+/// bound to a DPoP key can only be refreshed with a proof from that same key.
+pub async fn refresh_token(
+  state: &AppState,
+  client: &IdentityClient,
+  payload: OauthTokenRequest,
+  jkt: Option<String>,
+) -> Response {
+  let Some(refresh_token) = payload.refresh_token else {
+    return bad_request(
+      "invalid_request",
+      "refresh_token parameter required when using refresh_token",
+    );
+  };
+
+  let refresh_token_not_valid = bad_request("invalid_grant", "Refresh token not valid");
+
+  let Ok(refresh_opt) = OauthRefreshTokenData::from_token(state, refresh_token.clone()).await
+  else {
+    return internal_server_error();
+  };
+
+  let Some(refresh_data) = refresh_opt else {
+    return refresh_token_not_valid;
+  };
+
+  // checked before the token is taken, so other clients can't burn it
+  if refresh_data.client_id != client.client_id || refresh_data.jkt != jkt {
+    return refresh_token_not_valid;
+  }
+
+  // someone else might have redeemed it in the meantime
+  let Ok(Some(refresh_data)) = OauthRefreshTokenData::take_from_token(state, refresh_token).await
+  else {
+    return refresh_token_not_valid;
+  };
+
+  let lifetimes = client.get_token_lifetimes(&state.token_lifetimes);
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs();
+
+  let absolute_expiry = refresh_data.auth_time + lifetimes.refresh_token_absolute;
+  if absolute_expiry <= now {
+    return refresh_token_not_valid;
+  }
+
+  let Ok(user) = User::from_user_id(&state.pool, refresh_data.user_id).await else {
+    return refresh_token_not_valid;
+  };
+
+  if user.is_suspended {
+    return refresh_token_not_valid;
+  }
+
+  let Ok(user_app_auth) =
+    UserAppAuthorization::get_authorization(&state.pool, user.id, client.client_id.clone()).await
+  else {
+    return refresh_token_not_valid;
+  };
+
+  if user_app_auth.revoked {
+    return refresh_token_not_valid;
+  }
+
+  let Ok(groups) = user.get_groups(&state.pool).await else {
+    return internal_server_error();
+  };
+
+  let Ok(user_permission) = client.is_user_allowed(&state.pool, &user, &groups).await else {
+    return internal_server_error();
+  };
+
+  if !user_permission {
+    return refresh_token_not_valid;
+  }
+
+  let Ok(id_token) = create_id_token(
+    state,
+    &user,
+    client,
+    groups,
+    None,
+    refresh_data.acr.clone(),
+    &user_app_auth,
+  )
+  .await
+  else {
+    return internal_server_error();
+  };
+
+  let access_token_data = OauthAccessTokenData {
+    user_id: user.id,
+    client_id: client.client_id.clone(),
+    nonce: None,
+    act: None,
+    jkt: jkt.clone(),
+    acr: refresh_data.acr.clone(),
+  };
+
+  let Ok(access_token) = access_token_data
+    .save_to_token(state, lifetimes.access_token)
+    .await
+  else {
+    return internal_server_error();
+  };
+
+  let new_refresh_data = OauthRefreshTokenData {
+    user_id: user.id,
+    client_id: client.client_id.clone(),
+    nonce: None,
+    jkt: jkt.clone(),
+    acr: refresh_data.acr,
+    auth_time: refresh_data.auth_time,
+  };
+
+  let Ok(refresh_token) = new_refresh_data
+    .save_to_token(
+      state,
+      lifetimes.refresh_token_idle.min(absolute_expiry - now),
+    )
+    .await
+  else {
+    return internal_server_error();
+  };
+
+  (
+    StatusCode::OK,
+    Json(OauthTokenResponse {
+      access_token,
+      token_type: match jkt {
+        Some(_) => "DPoP".to_string(),
+        None => "Bearer".to_string(),
+      },
+      expires_in: lifetimes.access_token,
+      scope: SCOPES_SUPPORTED.join(" "),
+      refresh_token,
+      id_token,
+    }),
+  )
+    .into_response()
+}
@@ -1,28 +1,41 @@
-use std::collections::HashMap;
+use std::{
+  collections::HashMap,
+  time::{SystemTime, UNIX_EPOCH},
+};
 
 use axum::{
-  Form, Json,
-  extract::State,
-  response::{IntoResponse, Response},
+  Extension, Form, Json,
+  extract::{RawQuery, State},
+  response::{IntoResponse, Redirect, Response},
 };
-use axum_auth::AuthBearer;
-use http::{HeaderMap, StatusCode};
+use http::{HeaderMap, StatusCode, header};
 use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use subtle::ConstantTimeEq;
 use webauthn_rs::prelude::Url;
 
 use crate::{
   AppState,
-  client::IdentityClient,
+  auth::identity::IdentityAccessClaims,
+  client::{IdentityClient, redirect::redirect_uri_matches},
   group::IdentityGroup,
   oauth::{
     authorization::UserAppAuthorization,
+    capabilities::{
+      AUTHORIZATION_PAGE, GRANT_TYPES_SUPPORTED, IMPLICIT_GRANT_TYPE, RESPONSE_MODES_SUPPORTED,
+      RESPONSE_TYPES_SUPPORTED, SCOPES_SUPPORTED, TOKEN_ENDPOINT, USERINFO_ENDPOINT,
+    },
     code::OauthCodeData,
     create_id_token,
-    token::{OauthAccessTokenData, OauthRefreshTokenData},
+    dpop::{get_dpop_proof, validate_dpop_proof, verify_token_binding},
+    exchange::{TOKEN_EXCHANGE_GRANT_TYPE, exchange_token},
+    refresh::refresh_token,
+    token::{OauthAccessTokenData, OauthRefreshTokenData, OauthTokenActor},
   },
+  ratelimit::{ClientAuthRoutes, IpRateLimit, get_lockout, hit, lock_out},
   response::{ApiErr, ApiResponse},
-  user::User,
-  util::get_basic_auth_from_header,
+  user::{User, impersonation::impersonation_not_allowed},
+  util::{RequestMetadata, get_basic_auth_from_header, get_token_auth_from_header},
 };
 
 #[derive(Clone, Deserialize)]
@@ -34,15 +47,23 @@ pub struct OauthAuthorizeRequest {
   pub state: Option<String>,
   pub response_mode: Option<String>,
   pub nonce: Option<String>,
+  /// Space separated sign-in methods the client accepts, e.g. "passkey"
+  pub acr_values: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OauthTokenRequest {
   pub grant_type: String,
   pub code: Option<String>,
-  pub redirect_uri: String,
+  pub redirect_uri: Option<String>,
   pub client_id: Option<String>,
   pub client_secret: Option<String>,
+  // token exchange (RFC 8693) parameters
+  pub subject_token: Option<String>,
+  pub subject_token_type: Option<String>,
+  pub requested_token_type: Option<String>,
+  pub audience: Option<String>,
+  pub refresh_token: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -55,6 +76,30 @@ pub struct OauthTokenResponse {
   pub id_token: String,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct OauthIntrospectionRequest {
+  pub token: String,
+  pub token_type_hint: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OauthConfirmationClaim {
+  pub jkt: String,
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+pub struct OauthIntrospectionResponse {
+  pub active: bool,
+  pub client_id: Option<String>,
+  pub sub: Option<String>,
+  pub token_type: Option<String>,
+  pub cnf: Option<OauthConfirmationClaim>,
+  pub act: Option<OauthTokenActor>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OauthTokenErrorResponse {
   pub error: String,
@@ -71,7 +116,7 @@ pub struct OauthAuthorizeApproveResponse {
   pub redirect_to: String,
 }
 
-fn get_oauth_error(name: &'static str, description: &'static str) -> OauthTokenErrorResponse {
+pub fn get_oauth_error(name: &'static str, description: &'static str) -> OauthTokenErrorResponse {
   tracing::info!("encountered oauth error {} ({})", name, description);
   OauthTokenErrorResponse {
     error: name.to_string(),
@@ -79,23 +124,66 @@ fn get_oauth_error(name: &'static str, description: &'static str) -> OauthTokenE
   }
 }
 
+/// 429 with a Retry-After header, in the OAuth error format
+fn get_oauth_rate_limit_response(retry_after: u64, description: &'static str) -> Response {
+  (
+    StatusCode::TOO_MANY_REQUESTS,
+    [(header::RETRY_AFTER, retry_after.to_string())],
+    Json(get_oauth_error("temporarily_unavailable", description)),
+  )
+    .into_response()
+}
+
+fn get_oauth_internal_error() -> Response {
+  (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    Json(get_oauth_error(
+      "internal_server_error",
+      "Something went wrong!",
+    )),
+  )
+    .into_response()
+}
+
 pub async fn validate_oauth_authorization(
   state: &AppState,
   user: &User,
   payload: &OauthAuthorizeRequest,
   client: &IdentityClient,
-  groups: &Vec<IdentityGroup>,
+  groups: &[IdentityGroup],
+  acr: &str,
 ) -> Option<ApiErr> {
   if client.is_disabled {
     return Some(ApiErr::AppDisabled);
   }
 
+  // the user can't do anything about this besides signing in another way
+  if let Some(acr_values) = &payload.acr_values
+    && !acr_values.split_whitespace().any(|x| x == acr)
+  {
+    return Some(ApiErr::Other(
+      "acr_not_satisfied".to_string(),
+      format!(
+        "{} requires you to sign in with one of: {}. Sign out and sign in again to continue.",
+        client.app_name,
+        acr_values
+          .split_whitespace()
+          .collect::<Vec<&str>>()
+          .join(", ")
+      ),
+    ));
+  }
+
   let mut valid_response_types = vec![];
   if client.allow_explicit_flow {
     valid_response_types.push("code");
   }
   if client.allow_implicit_flow {
-    valid_response_types.push("token");
+    // access tokens issued from the authorization endpoint can't be bound to a
+    // DPoP key, so only id tokens are allowed for clients requiring DPoP.
+    if !client.require_dpop {
+      valid_response_types.push("token");
+    }
     valid_response_types.push("id_token");
   }
   let mut response_types: Vec<&str> = payload.response_type.split_whitespace().collect();
@@ -105,7 +193,14 @@ pub async fn validate_oauth_authorization(
   }
 
   for response_type in &response_types {
-    if !valid_response_types.contains(&response_type) {
+    if !RESPONSE_TYPES_SUPPORTED.contains(response_type) {
+      return Some(ApiErr::Other(
+        "invalid_response_type".to_string(),
+        format!("Response type {} is not supported.", response_type),
+      ));
+    }
+
+    if !valid_response_types.contains(response_type) {
       return Some(ApiErr::Other(
         "invalid_response_type".to_string(),
         format!(
@@ -124,7 +219,11 @@ pub async fn validate_oauth_authorization(
     return Some(ApiErr::InvalidRedirectUri(payload.redirect_uri.clone()));
   }
 
-  if !client.redirect_uris.contains(&payload.redirect_uri) {
+  if !client
+    .redirect_uris
+    .iter()
+    .any(|x| redirect_uri_matches(client.is_native_app, x, &payload.redirect_uri))
+  {
     return Some(ApiErr::InvalidRedirectUri(payload.redirect_uri.clone()));
   }
 
@@ -136,24 +235,34 @@ pub async fn validate_oauth_authorization(
     return Some(ApiErr::OauthAclDenied(client.app_name.clone()));
   }
 
-  if let Some(response_mode) = &payload.response_mode {
-    if response_mode != "query" && response_mode != "fragment" {
-      return Some(ApiErr::Other(
-        "invalid_response_mode".to_string(),
-        format!(
-          "Response mode {} is not supported. Valid values: query, fragment",
-          response_mode
-        ),
-      ));
-    }
+  if let Some(response_mode) = &payload.response_mode
+    && !RESPONSE_MODES_SUPPORTED.contains(&response_mode.as_str())
+  {
+    return Some(ApiErr::Other(
+      "invalid_response_mode".to_string(),
+      format!(
+        "Response mode {} is not supported. Valid values: query, fragment",
+        response_mode
+      ),
+    ));
   }
 
   None
 }
 
+/// The advertised authorization endpoint. Authorization requests need the user
+/// to sign in and consent, so we hand them off to the frontend.
+pub async fn oauth_authorize(RawQuery(query): RawQuery) -> Redirect {
+  match query {
+    Some(query) => Redirect::to(&format!("{}?{}", AUTHORIZATION_PAGE, query)),
+    None => Redirect::to(AUTHORIZATION_PAGE),
+  }
+}
+
 pub async fn oauth_authorize_preview(
   State(state): State<AppState>,
   user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
   Json(payload): Json<OauthAuthorizeRequest>,
 ) -> ApiResponse<OauthAuthorizePreviewResponse> {
   let Ok(client) = IdentityClient::from_client_id(&state.pool, payload.client_id.clone()).await
@@ -165,8 +274,15 @@ pub async fn oauth_authorize_preview(
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
-  if let Some(err) =
-    validate_oauth_authorization(&state, &user, &payload, &client, &user_groups).await
+  if let Some(err) = validate_oauth_authorization(
+    &state,
+    &user,
+    &payload,
+    &client,
+    &user_groups,
+    &claims.method,
+  )
+  .await
   {
     return ApiResponse::Err(err);
   }
@@ -177,8 +293,14 @@ pub async fn oauth_authorize_preview(
 pub async fn oauth_authorize_approve(
   State(state): State<AppState>,
   user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
   Json(payload): Json<OauthAuthorizeRequest>,
 ) -> ApiResponse<OauthAuthorizeApproveResponse> {
+  // previewing is fine, that's what impersonation is for
+  if claims.is_impersonated() {
+    return ApiResponse::Err(impersonation_not_allowed());
+  }
+
   let Ok(client) = IdentityClient::from_client_id(&state.pool, payload.client_id.clone()).await
   else {
     return ApiResponse::Err(ApiErr::UnknownClient);
@@ -188,8 +310,15 @@ pub async fn oauth_authorize_approve(
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
-  if let Some(err) =
-    validate_oauth_authorization(&state, &user, &payload, &client, &user_groups).await
+  if let Some(err) = validate_oauth_authorization(
+    &state,
+    &user,
+    &payload,
+    &client,
+    &user_groups,
+    &claims.method,
+  )
+  .await
   {
     return ApiResponse::Err(err);
   }
@@ -212,7 +341,7 @@ pub async fn oauth_authorize_approve(
     revoked: false,
   };
 
-  if let Err(_) = authorization.authorize_for_user(&state.pool).await {
+  if authorization.authorize_for_user(&state.pool).await.is_err() {
     return ApiResponse::Err(ApiErr::InternalServerError);
   }
 
@@ -225,6 +354,7 @@ pub async fn oauth_authorize_approve(
       client_id: client.client_id.clone(),
       nonce: payload.nonce.clone(),
       redirect_uri: payload.redirect_uri.clone(),
+      acr: Some(claims.method.clone()),
     };
     let Ok(code) = oauth_code_data.save_to_code(&state).await else {
       return ApiResponse::Err(ApiErr::InternalServerError);
@@ -237,13 +367,20 @@ pub async fn oauth_authorize_approve(
       user_id: user.id,
       client_id: client.client_id.clone(),
       nonce: payload.nonce.clone(),
+      act: None,
+      jkt: None,
+      acr: Some(claims.method.clone()),
     };
-    let Ok(token) = oauth_access_token_data.save_to_token(&state).await else {
+    let lifetimes = client.get_token_lifetimes(&state.token_lifetimes);
+    let Ok(token) = oauth_access_token_data
+      .save_to_token(&state, lifetimes.access_token)
+      .await
+    else {
       return ApiResponse::Err(ApiErr::InternalServerError);
     };
     callback_params.insert("access_token", token);
     callback_params.insert("token_type", "bearer".to_string());
-    callback_params.insert("expires_in", "3600".to_string());
+    callback_params.insert("expires_in", lifetimes.access_token.to_string());
   }
 
   if response_types.contains(&"id_token") {
@@ -253,6 +390,7 @@ pub async fn oauth_authorize_approve(
       &client,
       user_groups,
       payload.nonce.clone(),
+      Some(claims.method.clone()),
       &authorization,
     )
     .await
@@ -283,58 +421,191 @@ pub async fn oauth_authorize_approve(
   })
 }
 
-pub async fn oauth_token(
-  State(state): State<AppState>,
-  headers: HeaderMap,
-  Form(payload): Form<OauthTokenRequest>,
-) -> Response {
-  let (client_id, client_secret) = match get_basic_auth_from_header(&headers) {
+/// Authenticates a client calling one of the back-channel endpoints, using either
+/// HTTP basic auth or the client_id and client_secret form parameters.
+pub async fn authenticate_client(
+  state: &AppState,
+  headers: &HeaderMap,
+  metadata: &RequestMetadata,
+  client_id: Option<String>,
+  client_secret: Option<String>,
+) -> Result<IdentityClient, Response> {
+  let (client_id, client_secret) = match get_basic_auth_from_header(headers) {
     Some((client_id, client_secret)) => (client_id, client_secret),
-    None => match payload.client_id {
-      Some(client_id) => match payload.client_secret {
+    None => match client_id {
+      Some(client_id) => match client_secret {
         Some(client_secret) => (client_id, client_secret),
         None => {
-          return (
+          return Err(
+            (
+              StatusCode::BAD_REQUEST,
+              Json(get_oauth_error(
+                "invalid_request",
+                "client_secret is required (PKCE authentication is not yet implemented).",
+              )),
+            )
+              .into_response(),
+          );
+        }
+      },
+      None => {
+        return Err(
+          (
             StatusCode::BAD_REQUEST,
             Json(get_oauth_error(
               "invalid_request",
-              "client_secret is required (PKCE authentication is not yet implemented).",
+              "client_id must be provided",
             )),
           )
-            .into_response();
-        }
-      },
-      None => {
-        return (
-          StatusCode::BAD_REQUEST,
-          Json(get_oauth_error(
-            "invalid_request",
-            "client_id must be provided",
-          )),
-        )
-          .into_response();
+            .into_response(),
+        );
       }
     },
   };
 
-  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id).await else {
+  // checked before the secret, so a locked out IP can't keep guessing. Other
+  // IPs can still authenticate as the client.
+  let ip_address = metadata.ip_address.as_deref().unwrap_or("unknown");
+  let lockout_key = format!("client:{}:ip:{}", client_id, ip_address);
+  match get_lockout(state, &lockout_key).await {
+    Ok(None) => {}
+    Ok(Some(retry_after)) => {
+      return Err(get_oauth_rate_limit_response(
+        retry_after,
+        "Too many invalid client secrets were sent, try again later.",
+      ));
+    }
+    Err(_) => return Err(get_oauth_internal_error()),
+  }
+
+  let Ok(client) = IdentityClient::from_client_id(&state.pool, client_id.clone()).await else {
+    return Err(
+      (
+        StatusCode::BAD_REQUEST,
+        Json(get_oauth_error(
+          "invalid_client",
+          "Client could not be found or has invalid secret",
+        )),
+      )
+        .into_response(),
+    );
+  };
+
+  let secret_matches: bool = client
+    .client_secret
+    .as_bytes()
+    .ct_eq(client_secret.as_bytes())
+    .into();
+
+  if !secret_matches {
+    let failures_key = format!("client_secret_failures:{}:ip:{}", client_id, ip_address);
+    let Ok(retry_after) = hit(
+      state,
+      &failures_key,
+      &state.rate_limits.client_secret_failures,
+    )
+    .await
+    else {
+      return Err(get_oauth_internal_error());
+    };
+    if retry_after.is_some() {
+      tracing::warn!(
+        "Locking out client {} after repeated invalid secrets",
+        client_id
+      );
+      if lock_out(state, &lockout_key, state.rate_limits.client_lockout)
+        .await
+        .is_err()
+      {
+        return Err(get_oauth_internal_error());
+      }
+    }
+  }
+
+  if !secret_matches || client.is_disabled {
+    return Err(
+      (
+        StatusCode::BAD_REQUEST,
+        Json(get_oauth_error(
+          "invalid_client",
+          "Client could not be found or has invalid secret",
+        )),
+      )
+        .into_response(),
+    );
+  }
+
+  Ok(client)
+}
+
+pub async fn oauth_token(
+  State(state): State<AppState>,
+  _: IpRateLimit<ClientAuthRoutes>,
+  headers: HeaderMap,
+  metadata: RequestMetadata,
+  Form(payload): Form<OauthTokenRequest>,
+) -> Response {
+  let client = match authenticate_client(
+    &state,
+    &headers,
+    &metadata,
+    payload.client_id.clone(),
+    payload.client_secret.clone(),
+  )
+  .await
+  {
+    Ok(client) => client,
+    Err(response) => return response,
+  };
+
+  let rate_limit_key = format!("oauth_token:client:{}", client.client_id);
+  match hit(&state, &rate_limit_key, &state.rate_limits.oauth_token).await {
+    Ok(None) => {}
+    Ok(Some(retry_after)) => {
+      return get_oauth_rate_limit_response(
+        retry_after,
+        "This client is requesting tokens too often, try again later.",
+      );
+    }
+    Err(_) => return get_oauth_internal_error(),
+  }
+
+  let jkt = match get_dpop_proof(&headers) {
+    Ok(Some(proof)) => {
+      let htu = format!("{}{}", state.oidc_issuer_uri, TOKEN_ENDPOINT);
+      match validate_dpop_proof(&state, &proof, "POST", &htu, None).await {
+        Ok(jkt) => Some(jkt),
+        Err(err) => return err.into_token_response(),
+      }
+    }
+    Ok(None) => None,
+    Err(err) => return err.into_token_response(),
+  };
+
+  if client.require_dpop && jkt.is_none() {
     return (
       StatusCode::BAD_REQUEST,
       Json(get_oauth_error(
-        "invalid_client",
-        "Client could not be found or has invalid secret",
+        "invalid_dpop_proof",
+        "This client requires a DPoP proof when requesting tokens",
       )),
     )
       .into_response();
+  }
+
+  let token_type = match jkt {
+    Some(_) => "DPoP",
+    None => "Bearer",
   };
 
-  // maybe do some fancy xor constant time bullshit in the future
-  if client.client_secret != client_secret || client.is_disabled {
+  if payload.grant_type == IMPLICIT_GRANT_TYPE
+    || !GRANT_TYPES_SUPPORTED.contains(&payload.grant_type.as_str())
+  {
     return (
       StatusCode::BAD_REQUEST,
       Json(get_oauth_error(
-        "invalid_client",
-        "Client could not be found or has invalid secret",
+        "unsupported_grant_type",
+        "Grant type not supported by server!",
       )),
     )
       .into_response();
@@ -374,7 +645,9 @@ pub async fn oauth_token(
         return code_not_valid;
       };
 
-      if code_data.redirect_uri != payload.redirect_uri || code_data.client_id != client.client_id {
+      if payload.redirect_uri.as_ref() != Some(&code_data.redirect_uri)
+        || code_data.client_id != client.client_id
+      {
         return code_not_valid;
       }
 
@@ -425,6 +698,7 @@ pub async fn oauth_token(
         &client,
         groups,
         code_data.nonce.clone(),
+        code_data.acr.clone(),
         &user_app_auth,
       )
       .await
@@ -443,9 +717,16 @@ pub async fn oauth_token(
         user_id: user.id,
         client_id: client.client_id.clone(),
         nonce: code_data.nonce.clone(),
+        act: None,
+        jkt: jkt.clone(),
+        acr: code_data.acr.clone(),
       };
 
-      let Ok(access_token) = access_token_data.save_to_token(&state).await else {
+      let lifetimes = client.get_token_lifetimes(&state.token_lifetimes);
+      let Ok(access_token) = access_token_data
+        .save_to_token(&state, lifetimes.access_token)
+        .await
+      else {
         return (
           StatusCode::INTERNAL_SERVER_ERROR,
           Json(get_oauth_error(
@@ -460,9 +741,24 @@ pub async fn oauth_token(
         user_id: user.id,
         client_id: client.client_id.clone(),
         nonce: code_data.nonce,
+        jkt,
+        acr: code_data.acr,
+        auth_time: SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .expect("Time went backwards lol")
+          .as_secs(),
       };
 
-      let Ok(refresh_token) = refresh_token_data.save_to_token(&state).await else {
+      // the absolute lifetime can be shorter than the idle one
+      let Ok(refresh_token) = refresh_token_data
+        .save_to_token(
+          &state,
+          lifetimes
+            .refresh_token_idle
+            .min(lifetimes.refresh_token_absolute),
+        )
+        .await
+      else {
         return (
           StatusCode::INTERNAL_SERVER_ERROR,
           Json(get_oauth_error(
@@ -473,19 +769,21 @@ pub async fn oauth_token(
           .into_response();
       };
 
-      return (
+      (
         StatusCode::OK,
         Json(OauthTokenResponse {
           access_token,
-          token_type: "Bearer".to_string(),
-          expires_in: 3600,
-          scope: "openid profile email".to_string(),
+          token_type: token_type.to_string(),
+          expires_in: lifetimes.access_token,
+          scope: SCOPES_SUPPORTED.join(" "),
           refresh_token,
           id_token,
         }),
       )
-        .into_response();
+        .into_response()
     }
+    "refresh_token" => refresh_token(&state, &client, payload, jkt).await,
+    TOKEN_EXCHANGE_GRANT_TYPE => exchange_token(&state, &client, payload, jkt).await,
     _ => (
       StatusCode::BAD_REQUEST,
       Json(get_oauth_error(
@@ -497,17 +795,19 @@ pub async fn oauth_token(
   }
 }
 
-pub async fn oauth_userinfo(
-  State(state): State<AppState>,
-  AuthBearer(access_token): AuthBearer,
-) -> Response {
+pub async fn oauth_userinfo(State(state): State<AppState>, headers: HeaderMap) -> Response {
   let mut invalid_token_headers = HeaderMap::new();
   invalid_token_headers.insert(
     "www-authenticate",
     "Bearer error=\"invalid_token\"".parse().unwrap(),
   );
 
-  let Ok(access_token_opt) = OauthAccessTokenData::from_token(&state, access_token).await else {
+  let Some((scheme, access_token)) = get_token_auth_from_header(&headers) else {
+    return (StatusCode::UNAUTHORIZED, invalid_token_headers).into_response();
+  };
+
+  let Ok(access_token_opt) = OauthAccessTokenData::from_token(&state, access_token.clone()).await
+  else {
     return StatusCode::INTERNAL_SERVER_ERROR.into_response();
   };
 
@@ -515,6 +815,21 @@ pub async fn oauth_userinfo(
     return (StatusCode::UNAUTHORIZED, invalid_token_headers).into_response();
   };
 
+  let htu = format!("{}{}", state.oidc_issuer_uri, USERINFO_ENDPOINT);
+  if let Err(err) = verify_token_binding(
+    &state,
+    &headers,
+    &scheme,
+    &access_token,
+    &access_token_data.jkt,
+    "GET",
+    &htu,
+  )
+  .await
+  {
+    return err.into_resource_response();
+  }
+
   let Ok(user_app_auth) = UserAppAuthorization::get_authorization(
     &state.pool,
     access_token_data.user_id,
@@ -560,6 +875,7 @@ pub async fn oauth_userinfo(
     &client,
     groups,
     access_token_data.nonce.clone(),
+    access_token_data.acr.clone(),
     &user_app_auth,
   )
   .await
@@ -572,3 +888,92 @@ pub async fn oauth_userinfo(
 
   (ok_resp_headers, id_token).into_response()
 }
+
+/// Token introspection (RFC 7662). Clients can only introspect tokens that were
+/// issued to them. DPoP-bound tokens include `cnf.jkt` so the caller can check
+/// the proof sent alongside the token against it.
+pub async fn oauth_introspect(
+  State(state): State<AppState>,
+  _: IpRateLimit<ClientAuthRoutes>,
+  headers: HeaderMap,
+  metadata: RequestMetadata,
+  Form(payload): Form<OauthIntrospectionRequest>,
+) -> Response {
+  let client = match authenticate_client(
+    &state,
+    &headers,
+    &metadata,
+    payload.client_id.clone(),
+    payload.client_secret.clone(),
+  )
+  .await
+  {
+    Ok(client) => client,
+    Err(response) => return response,
+  };
+
+  let inactive = Json(OauthIntrospectionResponse {
+    active: false,
+    client_id: None,
+    sub: None,
+    token_type: None,
+    cnf: None,
+    act: None,
+  })
+  .into_response();
+
+  // only access tokens can be introspected for now
+  if payload
+    .token_type_hint
+    .is_some_and(|hint| hint != "access_token")
+  {
+    return inactive;
+  }
+
+  let Ok(access_token_opt) = OauthAccessTokenData::from_token(&state, payload.token).await else {
+    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+  };
+
+  let Some(access_token_data) = access_token_opt else {
+    return inactive;
+  };
+
+  if access_token_data.client_id != client.client_id {
+    return inactive;
+  }
+
+  let Ok(user) = User::from_user_id(&state.pool, access_token_data.user_id).await else {
+    return inactive;
+  };
+
+  if user.is_suspended {
+    return inactive;
+  }
+
+  let Ok(user_app_auth) =
+    UserAppAuthorization::get_authorization(&state.pool, user.id, client.client_id.clone()).await
+  else {
+    return inactive;
+  };
+
+  if user_app_auth.revoked {
+    return inactive;
+  }
+
+  let token_type = match access_token_data.jkt {
+    Some(_) => "DPoP",
+    None => "Bearer",
+  };
+
+  Json(OauthIntrospectionResponse {
+    active: true,
+    client_id: Some(client.client_id),
+    sub: Some(user_app_auth.sub),
+    token_type: Some(token_type.to_string()),
+    cnf: access_token_data
+      .jkt
+      .map(|jkt| OauthConfirmationClaim { jkt }),
+    act: access_token_data.act,
+  })
+  .into_response()
+}
@@ -6,11 +6,29 @@ use serde::{Deserialize, Serialize};
 
 use crate::AppState;
 
+/// The party acting on behalf of the user (the RFC 8693 `act` claim). Exchanging
+/// a token that was itself exchanged nests the previous actor.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OauthTokenActor {
+  pub client_id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub act: Option<Box<OauthTokenActor>>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct OauthAccessTokenData {
   pub user_id: i32,
   pub client_id: String,
   pub nonce: Option<String>,
+  /// Only present on tokens issued through token exchange.
+  #[serde(default)]
+  pub act: Option<OauthTokenActor>,
+  /// JWK thumbprint of the DPoP key this token is bound to (`cnf.jkt`).
+  #[serde(default)]
+  pub jkt: Option<String>,
+  /// How the user signed in when authorizing, carried into id tokens
+  #[serde(default)]
+  pub acr: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -18,6 +36,14 @@ pub struct OauthRefreshTokenData {
   pub user_id: i32,
   pub client_id: String,
   pub nonce: Option<String>,
+  #[serde(default)]
+  pub jkt: Option<String>,
+  #[serde(default)]
+  pub acr: Option<String>,
+  /// When the user originally authorized, carried over when the token is
+  /// rotated. The absolute refresh token lifetime counts from here.
+  #[serde(default)]
+  pub auth_time: u64,
 }
 
 impl OauthAccessTokenData {
@@ -35,14 +61,18 @@ impl OauthAccessTokenData {
     }
   }
 
-  pub async fn save_to_token(&self, state: &AppState) -> Result<String, Box<dyn Error>> {
+  pub async fn save_to_token(
+    &self,
+    state: &AppState,
+    lifetime: u64,
+  ) -> Result<String, Box<dyn Error>> {
     let oauth_token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
     let key = format!("oauth_access_token:{}", oauth_token);
     let value = serde_json::to_string(self)?;
     let _: () = state
       .redis_connection
       .clone()
-      .set_ex(key, value, 3600)
+      .set_ex(key, value, lifetime)
       .await?;
     Ok(oauth_token)
   }
@@ -52,25 +82,45 @@ impl OauthRefreshTokenData {
   pub async fn from_token(
     state: &AppState,
     token: String,
-  ) -> Result<Option<OauthAccessTokenData>, Box<dyn Error>> {
+  ) -> Result<Option<OauthRefreshTokenData>, Box<dyn Error>> {
     let key = format!("oauth_refresh_token:{}", token);
     let token_data: Option<String> = state.redis_connection.clone().get(key).await?;
     match token_data {
-      Some(data) => Ok(Some(serde_json::from_str::<OauthAccessTokenData>(
+      Some(data) => Ok(Some(serde_json::from_str::<OauthRefreshTokenData>(
+        data.as_str(),
+      )?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Like [`OauthRefreshTokenData::from_token`], but deletes the token at the same
+  /// time so it can only ever be redeemed once.
+  pub async fn take_from_token(
+    state: &AppState,
+    token: String,
+  ) -> Result<Option<OauthRefreshTokenData>, Box<dyn Error>> {
+    let key = format!("oauth_refresh_token:{}", token);
+    let token_data: Option<String> = state.redis_connection.clone().get_del(key).await?;
+    match token_data {
+      Some(data) => Ok(Some(serde_json::from_str::<OauthRefreshTokenData>(
         data.as_str(),
       )?)),
       None => Ok(None),
     }
   }
 
-  pub async fn save_to_token(&self, state: &AppState) -> Result<String, Box<dyn Error>> {
+  pub async fn save_to_token(
+    &self,
+    state: &AppState,
+    lifetime: u64,
+  ) -> Result<String, Box<dyn Error>> {
     let oauth_token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
     let key = format!("oauth_refresh_token:{}", oauth_token);
     let value = serde_json::to_string(self)?;
     let _: () = state
       .redis_connection
       .clone()
-      .set_ex(key, value, 1209600)
+      .set_ex(key, value, lifetime)
       .await?;
     Ok(oauth_token)
   }
@@ -1,4 +1,6 @@
-use axum::{Json, extract::State};
+use axum::{Json, extract::State, response::IntoResponse};
+use http::header;
+use jsonwebtoken::Algorithm;
 use serde::Serialize;
 
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
@@ -8,24 +10,39 @@ use jsonwebtoken::jwk::{
 use rsa::{RsaPrivateKey, traits::PublicKeyParts};
 use std::collections::HashMap;
 
-use crate::AppState;
+use crate::{AppState, oauth::capabilities::*};
 
+/// Authorization server metadata, served both as OpenID Connect discovery and as
+/// RFC 8414 metadata. Everything in here comes from [`crate::oauth::capabilities`].
 #[derive(Serialize, Clone)]
-pub struct WellknownClaim {
+pub struct ServerMetadata {
   pub issuer: String,
   pub authorization_endpoint: String,
   pub token_endpoint: String,
   pub userinfo_endpoint: String,
+  pub introspection_endpoint: String,
+  pub end_session_endpoint: String,
   pub jwks_uri: String,
-  pub response_types_supported: Vec<&'static str>,
+  pub scopes_supported: Vec<&'static str>,
+  pub response_types_supported: Vec<String>,
   pub response_modes_supported: Vec<&'static str>,
+  pub grant_types_supported: Vec<&'static str>,
   pub subject_types_supported: Vec<&'static str>,
-  pub id_token_signing_alg_values_supported: Vec<&'static str>,
-  pub userinfo_signing_alg_values_supported: Vec<&'static str>,
+  pub id_token_signing_alg_values_supported: Vec<Algorithm>,
+  pub userinfo_signing_alg_values_supported: Vec<Algorithm>,
   pub token_endpoint_auth_methods_supported: Vec<&'static str>,
+  pub introspection_endpoint_auth_methods_supported: Vec<&'static str>,
+  pub claims_supported: Vec<&'static str>,
+  pub acr_values_supported: Vec<&'static str>,
+  pub dpop_signing_alg_values_supported: Vec<Algorithm>,
 }
 
-fn add_to_issuer(issuer: &String, path: &str) -> String {
+/// Metadata rarely changes, so clients are free to cache it for a while
+const METADATA_CACHE_CONTROL: &str = "public, max-age=3600";
+/// Keys are cached for less time so newly added keys are picked up quickly
+const JWKS_CACHE_CONTROL: &str = "public, max-age=600";
+
+fn add_to_issuer(issuer: &str, path: &str) -> String {
   format!("{}{}", issuer, path)
 }
 
@@ -58,23 +75,49 @@ pub fn generate_public_jwks(map: HashMap<u64, RsaPrivateKey>) -> JwkSet {
   JwkSet { keys }
 }
 
-pub async fn openid_configuration(State(state): State<AppState>) -> Json<WellknownClaim> {
-  let issuer = state.oidc_issuer_uri;
-  Json(WellknownClaim {
+pub fn server_metadata(state: &AppState) -> ServerMetadata {
+  let issuer = &state.oidc_issuer_uri;
+  ServerMetadata {
     issuer: issuer.clone(),
-    authorization_endpoint: add_to_issuer(&issuer, "/oauth/authorize"),
-    token_endpoint: add_to_issuer(&issuer, "/v1/oauth/token"),
-    userinfo_endpoint: add_to_issuer(&issuer, "/v1/oauth/userinfo"),
-    jwks_uri: add_to_issuer(&issuer, "/.well-known/jwks"),
-    response_types_supported: vec!["code", "id_token", "id_token token", "code id_token token"],
-    response_modes_supported: vec!["query", "fragment"],
-    subject_types_supported: vec!["pairwise", "public"],
-    id_token_signing_alg_values_supported: vec!["RS256"],
-    userinfo_signing_alg_values_supported: vec!["RS256"],
-    token_endpoint_auth_methods_supported: vec!["client_secret_post", "client_secret_basic"],
-  })
+    authorization_endpoint: add_to_issuer(issuer, AUTHORIZATION_ENDPOINT),
+    token_endpoint: add_to_issuer(issuer, TOKEN_ENDPOINT),
+    userinfo_endpoint: add_to_issuer(issuer, USERINFO_ENDPOINT),
+    introspection_endpoint: add_to_issuer(issuer, INTROSPECTION_ENDPOINT),
+    end_session_endpoint: add_to_issuer(issuer, END_SESSION_ENDPOINT),
+    jwks_uri: add_to_issuer(issuer, JWKS_ENDPOINT),
+    scopes_supported: SCOPES_SUPPORTED.to_vec(),
+    response_types_supported: response_type_combinations(),
+    response_modes_supported: RESPONSE_MODES_SUPPORTED.to_vec(),
+    grant_types_supported: GRANT_TYPES_SUPPORTED.to_vec(),
+    subject_types_supported: SUBJECT_TYPES_SUPPORTED.to_vec(),
+    id_token_signing_alg_values_supported: ID_TOKEN_SIGNING_ALGS_SUPPORTED.to_vec(),
+    userinfo_signing_alg_values_supported: USERINFO_SIGNING_ALGS_SUPPORTED.to_vec(),
+    token_endpoint_auth_methods_supported: CLIENT_AUTH_METHODS_SUPPORTED.to_vec(),
+    introspection_endpoint_auth_methods_supported: CLIENT_AUTH_METHODS_SUPPORTED.to_vec(),
+    claims_supported: CLAIMS_SUPPORTED.to_vec(),
+    acr_values_supported: ACR_VALUES_SUPPORTED.to_vec(),
+    dpop_signing_alg_values_supported: DPOP_SIGNING_ALGS_SUPPORTED.to_vec(),
+  }
+}
+
+pub async fn openid_configuration(State(state): State<AppState>) -> impl IntoResponse {
+  (
+    [(header::CACHE_CONTROL, METADATA_CACHE_CONTROL)],
+    Json(server_metadata(&state)),
+  )
+}
+
+/// RFC 8414 metadata, which is the same document as OpenID Connect discovery
+pub async fn oauth_authorization_server(State(state): State<AppState>) -> impl IntoResponse {
+  (
+    [(header::CACHE_CONTROL, METADATA_CACHE_CONTROL)],
+    Json(server_metadata(&state)),
+  )
 }
 
-pub async fn jwks(State(state): State<AppState>) -> Json<JwkSet> {
-  Json(generate_public_jwks(state.private_keys.oidc_jwt_keys))
+pub async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+  (
+    [(header::CACHE_CONTROL, JWKS_CACHE_CONTROL)],
+    Json(generate_public_jwks(state.private_keys.oidc_jwt_keys)),
+  )
 }
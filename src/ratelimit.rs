@@ -0,0 +1,237 @@
+// Sliding window rate limits backed by Redis. Every limited key keeps the
+// times of its recent requests in a sorted set, so unlike fixed windows a burst
+// at the end of one window can't be followed by another one right after.
+//
+// Routes are usually limited per IP with the [`IpRateLimit`] extractor, and
+// handlers can add their own limits (e.g. per user or client) with
+// [`check_rate_limit`].
+
+use std::{
+  error::Error,
+  marker::PhantomData,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::extract::{FromRef, FromRequestParts};
+use rand::distributions::{Alphanumeric, DistString};
+use redis::{AsyncCommands, Script};
+
+use crate::{
+  AppRateLimits, AppState,
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  util::RequestMetadata,
+};
+
+/// Drops requests that left the window, then either records this one or
+/// returns how many milliseconds are left until the oldest one leaves it.
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+  local key = KEYS[1]
+  local now = tonumber(ARGV[1])
+  local window = tonumber(ARGV[2])
+  local limit = tonumber(ARGV[3])
+
+  redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+  if redis.call('ZCARD', key) < limit then
+    redis.call('ZADD', key, now, ARGV[4])
+    redis.call('PEXPIRE', key, window)
+    return 0
+  end
+
+  local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+  return tonumber(oldest[2]) + window - now
+"#;
+
+/// At most `limit` requests per `window` seconds
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+  pub limit: u64,
+  pub window: u64,
+}
+
+impl RateLimit {
+  /// Parses limits written as "requests/seconds", e.g. "10/60"
+  pub fn parse(value: &str) -> Option<RateLimit> {
+    let (limit, window) = value.split_once('/')?;
+    Some(RateLimit {
+      limit: limit.trim().parse().ok()?,
+      window: window.trim().parse().ok()?,
+    })
+  }
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_millis() as u64
+}
+
+/// Counts a request for `key`. Returns how many seconds to wait if the limit
+/// was already reached, in which case the request isn't counted.
+pub async fn hit(
+  state: &AppState,
+  key: &str,
+  limit: &RateLimit,
+) -> Result<Option<u64>, Box<dyn Error>> {
+  let member = format!(
+    "{}-{}",
+    now_millis(),
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+  );
+  let wait_millis: u64 = Script::new(SLIDING_WINDOW_SCRIPT)
+    .key(format!("rate_limit:{}", key))
+    .arg(now_millis())
+    .arg(limit.window * 1000)
+    .arg(limit.limit)
+    .arg(member)
+    .invoke_async(&mut state.redis_connection.clone())
+    .await?;
+
+  match wait_millis {
+    0 => Ok(None),
+    millis => Ok(Some(millis.div_ceil(1000))),
+  }
+}
+
+/// Like [`hit`], but gives the error to respond with
+pub async fn check_rate_limit(
+  state: &AppState,
+  key: &str,
+  limit: &RateLimit,
+) -> Result<(), ApiErr> {
+  match hit(state, key, limit).await {
+    Ok(None) => Ok(()),
+    Ok(Some(retry_after)) => Err(ApiErr::RateLimited(retry_after)),
+    Err(_) => Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Locks `key` for `duration` seconds, see [`get_lockout`]
+pub async fn lock_out(state: &AppState, key: &str, duration: u64) -> Result<(), Box<dyn Error>> {
+  let _: () = state
+    .redis_connection
+    .clone()
+    .set_ex(format!("lockout:{}", key), 1, duration)
+    .await?;
+  Ok(())
+}
+
+/// Returns how many seconds are left if `key` is locked out
+pub async fn get_lockout(state: &AppState, key: &str) -> Result<Option<u64>, Box<dyn Error>> {
+  let ttl: i64 = state
+    .redis_connection
+    .clone()
+    .ttl(format!("lockout:{}", key))
+    .await?;
+  // the ttl is negative if the key doesn't exist (or never expires, which it can't)
+  Ok((ttl > 0).then_some(ttl as u64))
+}
+
+/// A group of routes sharing a configurable per-IP limit
+pub trait RateLimitGroup {
+  const NAME: &'static str;
+
+  fn limit(limits: &AppRateLimits) -> &RateLimit;
+}
+
+/// Passkey, username, TOTP and recovery code logins
+pub struct LoginRoutes;
+
+impl RateLimitGroup for LoginRoutes {
+  const NAME: &'static str = "login";
+
+  fn limit(limits: &AppRateLimits) -> &RateLimit {
+    &limits.login
+  }
+}
+
+/// Refreshing identity sessions
+pub struct RefreshRoutes;
+
+impl RateLimitGroup for RefreshRoutes {
+  const NAME: &'static str = "refresh";
+
+  fn limit(limits: &AppRateLimits) -> &RateLimit {
+    &limits.refresh
+  }
+}
+
+/// The OAuth endpoints clients authenticate to with their secret
+pub struct ClientAuthRoutes;
+
+impl RateLimitGroup for ClientAuthRoutes {
+  const NAME: &'static str = "client_auth";
+
+  fn limit(limits: &AppRateLimits) -> &RateLimit {
+    &limits.client_auth
+  }
+}
+
+/// Everything that registers a passkey, i.e. registration links, account
+/// recovery and adding passkeys
+pub struct RegistrationRoutes;
+
+impl RateLimitGroup for RegistrationRoutes {
+  const NAME: &'static str = "registration";
+
+  fn limit(limits: &AppRateLimits) -> &RateLimit {
+    &limits.registration
+  }
+}
+
+/// Add this to a handler's arguments to limit how often one IP can call it.
+/// Requests without a known IP aren't limited.
+pub struct IpRateLimit<G: RateLimitGroup>(PhantomData<G>);
+
+impl<S, G> FromRequestParts<S> for IpRateLimit<G>
+where
+  AppState: FromRef<S>,
+  S: Send + Sync,
+  G: RateLimitGroup,
+{
+  type Rejection = ApiResponse<EmptyResponse>;
+
+  async fn from_request_parts(
+    parts: &mut http::request::Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let app_state = AppState::from_ref(state);
+    let Ok(metadata) = RequestMetadata::from_request_parts(parts, state).await;
+
+    if let Some(ip_address) = metadata.ip_address {
+      let key = format!("{}:ip:{}", G::NAME, ip_address);
+      if let Err(err) = check_rate_limit(&app_state, &key, G::limit(&app_state.rate_limits)).await {
+        return Err(ApiResponse::Err(err));
+      }
+    }
+
+    Ok(IpRateLimit(PhantomData))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_requests_per_seconds() {
+    let limit = RateLimit::parse("10/60").unwrap();
+    assert_eq!((limit.limit, limit.window), (10, 60));
+
+    let limit = RateLimit::parse(" 5 / 1 ").unwrap();
+    assert_eq!((limit.limit, limit.window), (5, 1));
+  }
+
+  #[test]
+  fn rejects_invalid_limits() {
+    for value in [
+      "", "10", "10/", "/60", "a/60", "10/b", "-1/60", "10/60/5", "1.5/60",
+    ] {
+      assert!(
+        RateLimit::parse(value).is_none(),
+        "{} should be rejected",
+        value
+      );
+    }
+  }
+}
@@ -1,4 +1,8 @@
-use axum::{Json, http::StatusCode, response::IntoResponse};
+use axum::{
+  Json,
+  http::{StatusCode, header},
+  response::IntoResponse,
+};
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -31,6 +35,7 @@ pub enum ApiErr {
   InvalidChallenge,
   ExpiredRegistration,
   InvalidCredential,
+  CredentialLocked,
   UserDeleted,
   UserSuspended,
   InternalServerError,
@@ -45,6 +50,11 @@ pub enum ApiErr {
   EmailExists,
   AppDisabled,
   ManagedObject,
+  /// Seconds until the request can be retried
+  RateLimited(u64),
+  RestrictedSession,
+  /// The action needs a recent passkey assertion, see [`crate::user::SudoCtx`]
+  ReauthenticationRequired,
   GenericError,
   OauthAclDenied(String),
   InvalidRedirectUri(String),
@@ -82,6 +92,10 @@ impl ApiErr {
         "invalid_credential",
         "This passkey is not valid or has been removed from the account you are trying to sign into.",
       ),
+      ApiErr::CredentialLocked => error_msg(
+        "credential_locked",
+        "This passkey has been locked because it may have been copied to another device. Please sign in with a different passkey or contact an administrator.",
+      ),
       ApiErr::UserDeleted => error_msg(
         "user_deleted",
         "It looks like this account has been deleted or no longer exists.",
@@ -147,6 +161,18 @@ impl ApiErr {
           redirect_uri
         ),
       },
+      ApiErr::RestrictedSession => error_msg(
+        "restricted_session",
+        "This session can only be used to add a new passkey. Add one, then sign in with it.",
+      ),
+      ApiErr::ReauthenticationRequired => error_msg(
+        "reauthentication_required",
+        "Please confirm it's you with your passkey before doing this.",
+      ),
+      ApiErr::RateLimited(_) => error_msg(
+        "rate_limited",
+        "You're doing that too often. Please wait a while and try again.",
+      ),
       ApiErr::Other(code, message) => ErrorMessage { code, message },
       _ => error_msg("unknown_error", "An error occurred."),
     }
@@ -157,9 +183,13 @@ impl ApiErr {
       ApiErr::InvalidChallenge => StatusCode::FORBIDDEN,
       ApiErr::ExpiredRegistration => StatusCode::FORBIDDEN,
       ApiErr::UserSuspended => StatusCode::FORBIDDEN,
+      ApiErr::CredentialLocked => StatusCode::FORBIDDEN,
       ApiErr::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
       ApiErr::LoginRequired => StatusCode::UNAUTHORIZED,
       ApiErr::AdminRequired => StatusCode::FORBIDDEN,
+      ApiErr::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+      ApiErr::RestrictedSession => StatusCode::FORBIDDEN,
+      ApiErr::ReauthenticationRequired => StatusCode::FORBIDDEN,
       ApiErr::Other(_, _) => StatusCode::BAD_REQUEST,
       _ => StatusCode::BAD_REQUEST,
     }
@@ -173,6 +203,14 @@ where
   fn into_response(self) -> axum::response::Response {
     match self {
       ApiResponse::Ok(data) => Json(SuccessBody { data }).into_response(),
+      ApiResponse::Err(ApiErr::RateLimited(retry_after)) => (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        Json(ErrorBody {
+          error: ApiErr::RateLimited(retry_after).serialize(),
+        }),
+      )
+        .into_response(),
       ApiResponse::Err(err) => (
         err.status(),
         Json(ErrorBody {
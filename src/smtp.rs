@@ -5,6 +5,7 @@ use lettre::{
   message::{MultiPart, SinglePart, header},
 };
 use tokio::task::JoinHandle;
+use webauthn_rs::prelude::Url;
 
 use crate::{AppState, user::User};
 
@@ -29,25 +30,33 @@ macro_rules! html_template {
   };
 }
 
-fn replace_body(body: String, variable_name: &str, variable_value: &String) -> String {
-  body.replace(
-    &format!("{{{{{}}}}}", variable_name),
-    variable_value.as_str(),
-  )
+fn replace_body(body: String, variable_name: &str, variable_value: &str) -> String {
+  body.replace(&format!("{{{{{}}}}}", variable_name), variable_value)
 }
 
 fn complete_template(template: &mut MessageTemplate, variables: &HashMap<&str, String>) {
   for (name, val) in variables {
-    template.text = replace_body(template.text.clone(), *name, val);
-    match template.html.clone() {
-      Some(html_body) => {
-        template.html = Some(replace_body(html_body, *name, val));
-      }
-      None => {}
+    template.text = replace_body(template.text.clone(), name, val);
+    if let Some(html_body) = template.html.clone() {
+      template.html = Some(replace_body(html_body, name, val));
     };
   }
 }
 
+/// The host name mails refer to the server by
+pub fn get_origin(state: &AppState) -> String {
+  Url::parse(&state.oidc_issuer_uri)
+    .ok()
+    .and_then(|x| x.host_str().map(|x| x.to_string()))
+    .unwrap_or(state.oidc_issuer_uri.clone())
+}
+
+/// Links to a page of the frontend, e.g. "/auth/recover?t=..." for emails
+pub fn get_frontend_link(state: &AppState, path: &str) -> String {
+  // TODO: use webauthn instead of OIDC issuer uri
+  format!("{}{}", state.oidc_issuer_uri, path)
+}
+
 pub fn new_registration_message(
   user: &User,
   registration_link: String,
@@ -71,6 +80,188 @@ pub fn new_registration_message(
   }
 }
 
+pub fn new_session_reuse_message(user: &User, origin: String) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("origin", origin.clone());
+
+  let mut template = html_template!("session-reuse-detected");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: user.email.clone(),
+    subject: format!("A session on {} was signed out for your security", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+pub fn new_account_recovery_message(
+  user: &User,
+  recovery_link: String,
+  origin: String,
+) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("origin", origin.clone());
+  variables.insert("recovery_link", recovery_link);
+
+  let mut template = html_template!("account-recovery");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: user.email.clone(),
+    subject: format!("Recover your account on {}", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+pub fn new_account_recovered_message(
+  user: &User,
+  origin: String,
+  revoked_existing: bool,
+) -> MailMessage {
+  let details = match revoked_existing {
+    true => "All of your other passkeys were removed and every session was signed out.",
+    false => "Your other passkeys and sessions were left as they were.",
+  };
+
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("origin", origin.clone());
+  variables.insert("details", details.to_string());
+
+  let mut template = html_template!("account-recovered");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: user.email.clone(),
+    subject: format!("Your account on {} was recovered", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+/// Sent to the address being verified, which isn't the user's email yet when
+/// it's being changed
+pub fn new_email_verification_message(
+  user: &User,
+  email: String,
+  verification_link: String,
+  origin: String,
+) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("email", email.clone());
+  variables.insert("origin", origin.clone());
+  variables.insert("verification_link", verification_link);
+
+  let mut template = html_template!("verify-email");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: email,
+    subject: format!("Confirm your email address on {}", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+pub fn new_email_change_requested_message(
+  user: &User,
+  new_email: String,
+  origin: String,
+) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("origin", origin.clone());
+  variables.insert("new_email", new_email);
+
+  let mut template = html_template!("email-change-requested");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: user.email.clone(),
+    subject: format!("Your email address on {} is being changed", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+/// Sent to the previous address once the change was confirmed, `user` already
+/// has the new one
+pub fn new_email_changed_message(
+  user: &User,
+  previous_email: String,
+  origin: String,
+) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("origin", origin.clone());
+  variables.insert("new_email", user.email.clone());
+
+  let mut template = html_template!("email-changed");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: previous_email,
+    subject: format!("Your email address on {} was changed", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+/// Sent before the account exists, so there's no user yet
+pub fn new_signup_message(
+  email: String,
+  name: String,
+  username: String,
+  signup_link: String,
+  origin: String,
+) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", name);
+  variables.insert("username", username);
+  variables.insert("origin", origin.clone());
+  variables.insert("signup_link", signup_link);
+
+  let mut template = html_template!("confirm-signup");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: email,
+    subject: format!("Confirm your signup on {}", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
+/// Sent instead of [`new_signup_message`] when the email already belongs to an
+/// account, so signing up doesn't tell anyone else which emails are taken
+pub fn new_account_exists_message(user: &User, origin: String) -> MailMessage {
+  let mut variables = HashMap::new();
+  variables.insert("name", user.name.clone());
+  variables.insert("username", user.username.clone());
+  variables.insert("origin", origin.clone());
+
+  let mut template = html_template!("account-exists");
+  complete_template(&mut template, &variables);
+
+  MailMessage {
+    to: user.email.clone(),
+    subject: format!("You already have an account on {}", origin),
+    body: template.text,
+    body_html: template.html,
+  }
+}
+
 pub async fn send_mail(state: &AppState, message: MailMessage) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
   let Some(mailer) = &state.mailer else {
     tracing::info!("Mailing skipped due to SMTP being disabled!");
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, error::Error};
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Arbitrary admin-managed values on a user, which clients can add to their
+/// tokens through claim mappings.
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserAttribute {
+  pub user_id: i32,
+  pub name: String,
+  pub value: String,
+}
+
+impl UserAttribute {
+  pub async fn get_attributes_for_user(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<Vec<UserAttribute>, Box<dyn Error>> {
+    let attributes = sqlx::query_as!(
+      UserAttribute,
+      r#"
+        SELECT user_id, name, value FROM user_attributes WHERE user_id = $1 ORDER BY name
+      "#,
+      user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(attributes)
+  }
+
+  /// Attribute values keyed by name, for looking them up while mapping claims
+  pub async fn get_attribute_map_for_user(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let attributes = UserAttribute::get_attributes_for_user(pool, user_id).await?;
+    Ok(
+      attributes
+        .into_iter()
+        .map(|x| (x.name, x.value))
+        .collect::<HashMap<String, String>>(),
+    )
+  }
+
+  pub async fn upsert_attribute(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        INSERT INTO user_attributes(user_id, name, value) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, name) DO UPDATE SET value = EXCLUDED.value
+      "#,
+      self.user_id,
+      self.name,
+      self.value
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn remove_attribute(
+    pool: &PgPool,
+    user_id: i32,
+    name: String,
+  ) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        DELETE FROM user_attributes WHERE user_id = $1 AND name = $2
+      "#,
+      user_id,
+      name
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,315 @@
+// Email verification. A user's email is only marked verified once a link sent
+// to it was opened, and changing the email works the same way: the old address
+// stays on the account (and is notified) until the new one is confirmed.
+
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Extension, Json, extract::State};
+use lettre::Address;
+use rand::distributions::{Alphanumeric, DistString};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  AppState,
+  auth::identity::IdentityAccessClaims,
+  ratelimit::{RateLimit, check_rate_limit},
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  smtp::{
+    get_frontend_link, get_origin, new_email_change_requested_message, new_email_changed_message,
+    new_email_verification_message, send_mail,
+  },
+  user::{
+    User,
+    events::{EMAIL_CHANGED_EVENT, SecurityEvent},
+    impersonation::check_can_add_credentials,
+  },
+  util::{RequestMetadata, UniqueConstraintViolation},
+};
+
+/// How long a verification link stays valid
+const VERIFICATION_LINK_LIFETIME: u64 = 86400;
+/// How many verification links can be sent for one user per window
+const VERIFICATION_RATE_LIMIT: RateLimit = RateLimit {
+  limit: 3,
+  window: 3600,
+};
+
+#[derive(Serialize, Deserialize)]
+struct EmailVerificationData {
+  pub user_id: i32,
+  /// The account's email when the link was sent, the link stops working if it
+  /// changes in the meantime
+  pub current_email: String,
+  /// The address the link was sent to, which becomes the account's email if it
+  /// isn't already
+  pub email: String,
+  pub expires_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailRequest {
+  pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailRequest {
+  pub verification_token: String,
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs()
+}
+
+impl EmailVerificationData {
+  /// Deletes the token at the same time, so it can only ever be used once
+  async fn take_from_token(
+    state: &AppState,
+    token: &str,
+  ) -> Result<Option<EmailVerificationData>, Box<dyn Error>> {
+    let key = format!("email_verification:{}", token);
+    let token_data: Option<String> = state.redis_connection.clone().get_del(key).await?;
+    match token_data {
+      Some(data) => Ok(Some(serde_json::from_str::<EmailVerificationData>(
+        data.as_str(),
+      )?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn save_to_token(&self, state: &AppState, token: &str) -> Result<(), Box<dyn Error>> {
+    let key = format!("email_verification:{}", token);
+    let value = serde_json::to_string(self)?;
+    let _: () = state
+      .redis_connection
+      .clone()
+      .set_ex(key, value, VERIFICATION_LINK_LIFETIME)
+      .await?;
+    Ok(())
+  }
+}
+
+fn invalid_verification_link() -> ApiErr {
+  ApiErr::Other(
+    "invalid_verification_link".to_string(),
+    "This verification link has expired or was already used. Please request another one."
+      .to_string(),
+  )
+}
+
+/// Checks that a link can be sent to the user at all
+async fn check_can_send_link(state: &AppState, user: &User) -> Result<(), ApiErr> {
+  if state.mailer.is_none() {
+    return Err(ApiErr::Other(
+      "mail_disabled".to_string(),
+      "Email addresses can't be verified since this server can't send emails.".to_string(),
+    ));
+  }
+
+  let rate_limit_key = format!("email_verification:user:{}", user.id);
+  check_rate_limit(state, &rate_limit_key, &VERIFICATION_RATE_LIMIT).await
+}
+
+async fn send_verification_link(
+  state: &AppState,
+  user: &User,
+  email: String,
+) -> Result<(), ApiErr> {
+  let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+  let data = EmailVerificationData {
+    user_id: user.id,
+    current_email: user.email.clone(),
+    email: email.clone(),
+    expires_at: now() + VERIFICATION_LINK_LIFETIME,
+  };
+  if data.save_to_token(state, &token).await.is_err() {
+    return Err(ApiErr::InternalServerError);
+  }
+
+  let verification_link = get_frontend_link(state, &format!("/auth/verify-email?t={}", token));
+  let message = new_email_verification_message(user, email, verification_link, get_origin(state));
+  if send_mail(state, message).await.is_err() {
+    return Err(ApiErr::InternalServerError);
+  }
+
+  Ok(())
+}
+
+/// Checks that the user's email can be changed to `new_email` before anything
+/// else about the user is changed, and returns it trimmed. Counts towards the
+/// user's verification link limit.
+pub async fn check_email_change(
+  state: &AppState,
+  user: &User,
+  new_email: &str,
+) -> Result<String, ApiErr> {
+  let new_email = new_email.trim().to_string();
+  if new_email.parse::<Address>().is_err() {
+    return Err(ApiErr::Other(
+      "invalid_email".to_string(),
+      "Please enter a valid email address.".to_string(),
+    ));
+  }
+
+  if new_email == user.email {
+    return Err(ApiErr::Other(
+      "email_unchanged".to_string(),
+      "This already is the account's email address.".to_string(),
+    ));
+  }
+
+  // checked again when the change is confirmed, this is just to fail early
+  if User::from_email(&state.pool, new_email.clone())
+    .await
+    .is_ok_and(|x| x.id != user.id)
+  {
+    return Err(ApiErr::EmailExists);
+  }
+
+  check_can_send_link(state, user).await?;
+  Ok(new_email)
+}
+
+/// Sends a link to `new_email` that changes the user's email once it's opened,
+/// and lets the current address know about it. Used for admin changes too,
+/// `new_email` has to have passed [`check_email_change`].
+pub async fn request_email_change(
+  state: &AppState,
+  user: &User,
+  new_email: String,
+) -> Result<(), ApiErr> {
+  send_verification_link(state, user, new_email.clone()).await?;
+
+  let message = new_email_change_requested_message(user, new_email, get_origin(state));
+  if send_mail(state, message).await.is_err() {
+    tracing::error!("Failed to send email change notice");
+  }
+
+  Ok(())
+}
+
+/// Sends a verification link to the user's current email
+pub async fn send_email_verification(
+  State(state): State<AppState>,
+  current_user: User,
+) -> ApiResponse<EmptyResponse> {
+  if current_user.email_verified {
+    return ApiResponse::Err(ApiErr::Other(
+      "email_already_verified".to_string(),
+      "Your email address is already verified.".to_string(),
+    ));
+  }
+
+  if let Err(err) = check_can_send_link(&state, &current_user).await {
+    return ApiResponse::Err(err);
+  }
+
+  match send_verification_link(&state, &current_user, current_user.email.clone()).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(err) => ApiResponse::Err(err),
+  }
+}
+
+pub async fn change_email(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+  Json(payload): Json<ChangeEmailRequest>,
+) -> ApiResponse<EmptyResponse> {
+  if let Err(err) = check_can_add_credentials(&claims) {
+    return ApiResponse::Err(err);
+  }
+
+  if !claims.has_recent_passkey(state.session_lifetimes.sudo) {
+    return ApiResponse::Err(ApiErr::ReauthenticationRequired);
+  }
+
+  let new_email = match check_email_change(&state, &current_user, &payload.email).await {
+    Ok(new_email) => new_email,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  match request_email_change(&state, &current_user, new_email).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(err) => ApiResponse::Err(err),
+  }
+}
+
+/// Opened from the emailed link, which might be on a device that isn't signed
+/// in, so the token is all that's needed.
+pub async fn confirm_email(
+  State(state): State<AppState>,
+  metadata: RequestMetadata,
+  Json(payload): Json<ConfirmEmailRequest>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(data) = EmailVerificationData::take_from_token(&state, &payload.verification_token).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(data) = data else {
+    return ApiResponse::Err(invalid_verification_link());
+  };
+
+  if data.expires_at <= now() {
+    return ApiResponse::Err(invalid_verification_link());
+  }
+
+  let Ok(mut user) = User::from_user_id(&state.pool, data.user_id).await else {
+    return ApiResponse::Err(ApiErr::UserDeleted);
+  };
+
+  if user.is_suspended {
+    return ApiResponse::Err(ApiErr::UserSuspended);
+  }
+
+  if user.email != data.current_email {
+    return ApiResponse::Err(invalid_verification_link());
+  }
+
+  let previous_email = user.email.clone();
+  user.email = data.email;
+  user.email_verified = true;
+
+  if let Err(err) = user.update(&state.pool).await {
+    return match UniqueConstraintViolation::from(err) {
+      Some(violation) if violation.constraint_name == "users_email_key" => {
+        ApiResponse::Err(ApiErr::EmailExists)
+      }
+      _ => ApiResponse::Err(ApiErr::InternalServerError),
+    };
+  }
+
+  if user.email == previous_email {
+    return ApiResponse::EmptyOk;
+  }
+
+  let message = new_email_changed_message(&user, previous_email.clone(), get_origin(&state));
+  if send_mail(&state, message).await.is_err() {
+    tracing::error!("Failed to send email changed notice");
+  }
+
+  if SecurityEvent::record(
+    &state.pool,
+    user.id,
+    EMAIL_CHANGED_EVENT,
+    format!(
+      "The email address was changed from {} to {}",
+      previous_email, user.email
+    ),
+    &metadata,
+  )
+  .await
+  .is_err()
+  {
+    tracing::error!("Failed to record email change event");
+  }
+
+  ApiResponse::EmptyOk
+}
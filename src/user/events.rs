@@ -0,0 +1,88 @@
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use sqlx::PgPool;
+
+use crate::util::RequestMetadata;
+
+/// A superseded session refresh token was used, the session was terminated
+pub const REFRESH_TOKEN_REUSE_EVENT: &str = "refresh_token_reuse";
+/// A passkey's signature counter went backwards, the passkey was locked
+pub const PASSKEY_COUNTER_REGRESSION_EVENT: &str = "passkey_counter_regression";
+/// The account was recovered through an emailed recovery link
+pub const ACCOUNT_RECOVERY_EVENT: &str = "account_recovery";
+/// A recovery code was used to start a restricted session
+pub const RECOVERY_CODE_USED_EVENT: &str = "recovery_code_used";
+/// The account's email address was changed through a confirmation link
+pub const EMAIL_CHANGED_EVENT: &str = "email_changed";
+/// An admin started a session to act as the user
+pub const IMPERSONATION_STARTED_EVENT: &str = "impersonation_started";
+
+/// Something suspicious that happened on a user's account, kept for admins to
+/// look into.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SecurityEvent {
+  #[serde_as(as = "DisplayFromStr")]
+  pub id: i64,
+  pub user_id: i32,
+  pub event_type: String,
+  pub description: String,
+  pub ip_address: Option<String>,
+  pub user_agent: Option<String>,
+  pub created_at: i64,
+}
+
+impl SecurityEvent {
+  pub async fn record(
+    pool: &PgPool,
+    user_id: i32,
+    event_type: &str,
+    description: String,
+    metadata: &RequestMetadata,
+  ) -> Result<(), Box<dyn Error>> {
+    let created_at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .expect("Time went backwards lol")
+      .as_secs() as i64;
+
+    tracing::warn!("Security event for user {}: {}", user_id, description);
+
+    sqlx::query!(
+      r#"
+        INSERT INTO security_events(user_id, event_type, description, ip_address, user_agent, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+      "#,
+      user_id,
+      event_type,
+      description,
+      metadata.ip_address,
+      metadata.user_agent,
+      created_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn get_events_for_user(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<Vec<SecurityEvent>, Box<dyn Error>> {
+    let events = sqlx::query_as!(
+      SecurityEvent,
+      r#"
+        SELECT id, user_id, event_type, description, ip_address, user_agent, created_at
+        FROM security_events WHERE user_id = $1 ORDER BY created_at DESC
+      "#,
+      user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(events)
+  }
+}
@@ -0,0 +1,97 @@
+use axum::{
+  Extension,
+  extract::{Path, State},
+};
+use serde::Serialize;
+
+use crate::{
+  AppState,
+  auth::{
+    handoff::{PendingHandoff, unknown_handoff},
+    identity::{HANDOFF_METHOD, IdentityAccessClaims},
+  },
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  user::{User, impersonation::impersonation_not_allowed},
+};
+
+/// What the user is about to sign in, so they can check it's the device in
+/// front of them
+#[derive(Serialize)]
+pub struct HandoffDetailsResponse {
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+  pub created_at: u64,
+  pub expires_at: u64,
+}
+
+/// Only handoffs nobody approved yet can be looked at or approved
+async fn get_pending_handoff(state: &AppState, code: &str) -> Result<PendingHandoff, ApiErr> {
+  match PendingHandoff::from_code(state, code).await {
+    Ok(Some(handoff)) if handoff.approved_by.is_none() => Ok(handoff),
+    Ok(_) => Err(unknown_handoff()),
+    Err(_) => Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn get_handoff(
+  State(state): State<AppState>,
+  _: User,
+  Path(code): Path<String>,
+) -> ApiResponse<HandoffDetailsResponse> {
+  match get_pending_handoff(&state, &code).await {
+    Ok(handoff) => ApiResponse::Ok(HandoffDetailsResponse {
+      user_agent: handoff.user_agent,
+      ip_address: handoff.ip_address,
+      created_at: handoff.created_at,
+      expires_at: handoff.expires_at,
+    }),
+    Err(err) => ApiResponse::Err(err),
+  }
+}
+
+/// Signs the other device into the current user's account the next time it
+/// polls. Sessions that were handed off themselves can't pass it on.
+pub async fn approve_handoff(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+  Path(code): Path<String>,
+) -> ApiResponse<EmptyResponse> {
+  if claims.is_impersonated() {
+    return ApiResponse::Err(impersonation_not_allowed());
+  }
+
+  if claims.method == HANDOFF_METHOD {
+    return ApiResponse::Err(ApiErr::Other(
+      "handoff_not_allowed".to_string(),
+      "Devices signed in with a QR code can't sign in other devices.".to_string(),
+    ));
+  }
+
+  let mut handoff = match get_pending_handoff(&state, &code).await {
+    Ok(handoff) => handoff,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  handoff.approved_by = Some(current_user.id);
+  match handoff.save_to_code(&state, &code).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Cancels the handoff, e.g. if the user doesn't recognize the device
+pub async fn reject_handoff(
+  State(state): State<AppState>,
+  _: User,
+  Path(code): Path<String>,
+) -> ApiResponse<EmptyResponse> {
+  if let Err(err) = get_pending_handoff(&state, &code).await {
+    return ApiResponse::Err(err);
+  }
+
+  match PendingHandoff::delete(&state, &code).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
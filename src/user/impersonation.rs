@@ -0,0 +1,229 @@
+// Admin impersonation, so support can see what a user sees. The admin gets a
+// short-lived session for the user whose access tokens carry the admin's id,
+// and every request made with them is recorded. Admins can't be impersonated,
+// and impersonation sessions can't approve apps or add credentials.
+
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::extract::{Path, State};
+use serde::Serialize;
+use serde_with::{DisplayFromStr, serde_as};
+use sqlx::PgPool;
+
+use crate::{
+  AppState,
+  auth::{
+    identity::{HANDOFF_METHOD, IdentityAccessClaims, IdentityRefreshClaims},
+    session::UserSession,
+  },
+  response::{ApiErr, ApiResponse},
+  user::{
+    AdminCtx, SudoCtx, User,
+    events::{IMPERSONATION_STARTED_EVENT, SecurityEvent},
+  },
+  util::RequestMetadata,
+};
+
+/// A request made in an impersonation session
+#[serde_as]
+#[derive(Clone, Serialize, sqlx::FromRow)]
+pub struct ImpersonationAction {
+  #[serde_as(as = "DisplayFromStr")]
+  pub id: i64,
+  pub user_id: i32,
+  /// None if the admin was deleted since
+  pub impersonator_id: Option<i32>,
+  #[serde_as(as = "DisplayFromStr")]
+  pub session_id: i64,
+  pub method: String,
+  pub path: String,
+  /// None if the request never finished
+  pub status: Option<i32>,
+  pub ip_address: Option<String>,
+  pub user_agent: Option<String>,
+  pub created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct ImpersonationResponse {
+  pub access_token: String,
+  pub refresh_token: String,
+  pub session: UserSession,
+  pub user: User,
+}
+
+#[derive(Serialize)]
+pub struct ListImpersonationActionsResponse {
+  pub actions: Vec<ImpersonationAction>,
+}
+
+impl ImpersonationAction {
+  /// Called by [`crate::middleware::identity_auth`] before any request made
+  /// with impersonated claims is handled. Returns the action's id, see
+  /// [`ImpersonationAction::record_status`].
+  pub async fn record(
+    pool: &PgPool,
+    claims: &IdentityAccessClaims,
+    method: &str,
+    path: &str,
+    metadata: &RequestMetadata,
+  ) -> Result<i64, Box<dyn Error>> {
+    let created_at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .expect("Time went backwards lol")
+      .as_secs() as i64;
+
+    let id = sqlx::query_scalar!(
+      r#"
+        INSERT INTO impersonation_actions(user_id, impersonator_id, session_id, method, path, ip_address, user_agent, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id
+      "#,
+      claims.user_id,
+      claims.impersonator_id,
+      claims.session_id,
+      method,
+      path,
+      metadata.ip_address,
+      metadata.user_agent,
+      created_at
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(id)
+  }
+
+  /// Fills in the status once the request was handled
+  pub async fn record_status(pool: &PgPool, id: i64, status: u16) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        UPDATE impersonation_actions SET status = $1 WHERE id = $2
+      "#,
+      status as i32,
+      id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn get_actions_for_user(
+    pool: &PgPool,
+    user_id: i32,
+  ) -> Result<Vec<ImpersonationAction>, Box<dyn Error>> {
+    let actions = sqlx::query_as!(
+      ImpersonationAction,
+      r#"
+        SELECT id, user_id, impersonator_id, session_id, method, path, status, ip_address, user_agent, created_at
+        FROM impersonation_actions WHERE user_id = $1 ORDER BY created_at DESC
+      "#,
+      user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(actions)
+  }
+}
+
+/// For things an admin shouldn't do on a user's behalf
+pub fn impersonation_not_allowed() -> ApiErr {
+  ApiErr::Other(
+    "impersonation_not_allowed".to_string(),
+    "You can't do this while acting as another user.".to_string(),
+  )
+}
+
+/// For adding passkeys, TOTP, recovery codes or a new email. Neither an admin
+/// acting as the user nor a device signed in with a QR code should be able to
+/// give themselves a way back in.
+pub fn check_can_add_credentials(claims: &IdentityAccessClaims) -> Result<(), ApiErr> {
+  if claims.is_impersonated() {
+    return Err(impersonation_not_allowed());
+  }
+  if claims.method == HANDOFF_METHOD {
+    return Err(ApiErr::Other(
+      "handoff_not_allowed".to_string(),
+      "Devices signed in with a QR code can't add ways to sign in.".to_string(),
+    ));
+  }
+  Ok(())
+}
+
+/// Starts a session as the user. It's recorded on their account, and expires
+/// after SESSION_IMPERSONATION_LIFETIME no matter how it's refreshed.
+pub async fn start_impersonation(
+  State(state): State<AppState>,
+  admin: SudoCtx,
+  metadata: RequestMetadata,
+  Path(user_id): Path<i32>,
+) -> ApiResponse<ImpersonationResponse> {
+  let Ok(user) = User::from_user_id(&state.pool, user_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownUser);
+  };
+
+  if user.is_admin {
+    return ApiResponse::Err(ApiErr::Other(
+      "impersonation_not_allowed".to_string(),
+      "Administrators can't be impersonated.".to_string(),
+    ));
+  }
+
+  if user.is_suspended {
+    return ApiResponse::Err(ApiErr::UserSuspended);
+  }
+
+  let Ok((refresh_token, session)) = UserSession::create_impersonation_session(
+    &state.pool,
+    user.id,
+    admin.user.id,
+    metadata.clone(),
+  )
+  .await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  if SecurityEvent::record(
+    &state.pool,
+    user.id,
+    IMPERSONATION_STARTED_EVENT,
+    format!(
+      "Administrator {} (id {}) started acting as this user",
+      admin.user.username, admin.user.id
+    ),
+    &metadata,
+  )
+  .await
+  .is_err()
+  {
+    tracing::error!("Failed to record impersonation event");
+  }
+
+  let access_claims = IdentityAccessClaims::create_for_session(&user, &session);
+
+  let refresh_claims = IdentityRefreshClaims {
+    session_id: session.session_id,
+    refresh_token,
+    generation: session.refresh_generation,
+  };
+
+  ApiResponse::Ok(ImpersonationResponse {
+    access_token: access_claims.to_token(&state),
+    refresh_token: refresh_claims.to_jwt(&state),
+    session,
+    user,
+  })
+}
+
+pub async fn list_impersonation_actions(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path(user_id): Path<i32>,
+) -> ApiResponse<ListImpersonationActionsResponse> {
+  match ImpersonationAction::get_actions_for_user(&state.pool, user_id).await {
+    Ok(actions) => ApiResponse::Ok(ListImpersonationActionsResponse { actions }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
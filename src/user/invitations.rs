@@ -0,0 +1,267 @@
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::extract::{Path, State};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use sqlx::PgPool;
+
+use crate::{
+  AppState,
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  user::{AdminCtx, SudoCtx, User},
+};
+
+/// How long a registration link stays valid
+pub const INVITATION_LIFETIME: i64 = 86400;
+
+/// A registration link sent to a user. It is consumed by the first passkey
+/// registered with it, and can be revoked by an admin before that.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Invitation {
+  #[serde_as(as = "DisplayFromStr")]
+  pub id: i64,
+  pub user_id: i32,
+  /// None for invitations created through the CLI
+  pub created_by: Option<i32>,
+  pub created_at: i64,
+  pub expires_at: i64,
+  pub used_at: Option<i64>,
+  pub revoked_at: Option<i64>,
+}
+
+fn now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs() as i64
+}
+
+impl Invitation {
+  pub fn is_pending(&self) -> bool {
+    self.used_at.is_none() && self.revoked_at.is_none() && self.expires_at > now()
+  }
+
+  pub async fn from_id(pool: &PgPool, id: i64) -> Result<Invitation, Box<dyn Error>> {
+    let invitation = sqlx::query_as!(
+      Invitation,
+      r#"
+        SELECT id, user_id, created_by, created_at, expires_at, used_at, revoked_at
+        FROM user_invitations WHERE id = $1
+      "#,
+      id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(invitation)
+  }
+
+  pub async fn get_pending(pool: &PgPool) -> Result<Vec<Invitation>, Box<dyn Error>> {
+    let invitations = sqlx::query_as!(
+      Invitation,
+      r#"
+        SELECT id, user_id, created_by, created_at, expires_at, used_at, revoked_at
+        FROM user_invitations
+        WHERE used_at IS NULL AND revoked_at IS NULL AND expires_at > $1
+        ORDER BY created_at DESC
+      "#,
+      now()
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(invitations)
+  }
+
+  /// Creates a new invitation for `user_id`. Any invitation still pending for
+  /// the user is revoked, so only the newest link works.
+  pub async fn create(
+    pool: &PgPool,
+    user_id: i32,
+    created_by: Option<i32>,
+  ) -> Result<Invitation, Box<dyn Error>> {
+    let created_at = now();
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query!(
+      r#"
+        UPDATE user_invitations SET revoked_at = $1
+        WHERE user_id = $2 AND used_at IS NULL AND revoked_at IS NULL
+      "#,
+      created_at,
+      user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let invitation = sqlx::query_as!(
+      Invitation,
+      r#"
+        INSERT INTO user_invitations(user_id, created_by, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, user_id, created_by, created_at, expires_at, used_at, revoked_at
+      "#,
+      user_id,
+      created_by,
+      created_at,
+      created_at + INVITATION_LIFETIME
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(invitation)
+  }
+
+  /// Marks the invitation as used. Returns false if it was no longer pending,
+  /// e.g. because a concurrent registration already consumed it.
+  pub async fn consume(&mut self, pool: &PgPool) -> Result<bool, Box<dyn Error>> {
+    let used_at = now();
+    let result = sqlx::query!(
+      r#"
+        UPDATE user_invitations SET used_at = $1
+        WHERE id = $2 AND used_at IS NULL AND revoked_at IS NULL AND expires_at > $1
+      "#,
+      used_at,
+      self.id
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+      return Ok(false);
+    }
+
+    self.used_at = Some(used_at);
+    Ok(true)
+  }
+
+  /// Undoes [`Invitation::consume`], for when the registration fails afterwards
+  pub async fn release(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query!(
+      r#"
+        UPDATE user_invitations SET used_at = NULL WHERE id = $1
+      "#,
+      self.id
+    )
+    .execute(pool)
+    .await?;
+    self.used_at = None;
+    Ok(())
+  }
+
+  pub async fn revoke(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let revoked_at = now();
+    sqlx::query!(
+      r#"
+        UPDATE user_invitations SET revoked_at = $1 WHERE id = $2
+      "#,
+      revoked_at,
+      self.id
+    )
+    .execute(pool)
+    .await?;
+    self.revoked_at = Some(revoked_at);
+    Ok(())
+  }
+}
+
+#[derive(Serialize)]
+pub struct PendingInvitation {
+  #[serde(flatten)]
+  pub invitation: Invitation,
+  pub username: String,
+  pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct ListInvitationsResponse {
+  pub invitations: Vec<PendingInvitation>,
+}
+
+#[derive(Serialize)]
+pub struct InvitationResponse {
+  pub invitation: Invitation,
+}
+
+fn unknown_invitation() -> ApiErr {
+  ApiErr::Other(
+    "unknown_invitation".to_string(),
+    "Sorry, but this invitation doesn't exist or is no longer pending.".to_string(),
+  )
+}
+
+pub async fn list_invitations(
+  State(state): State<AppState>,
+  _: AdminCtx,
+) -> ApiResponse<ListInvitationsResponse> {
+  let Ok(pending) = Invitation::get_pending(&state.pool).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let mut invitations = Vec::with_capacity(pending.len());
+  for invitation in pending {
+    // the user can be deleted in the meantime, which deletes the invitation too
+    let Ok(user) = User::from_user_id(&state.pool, invitation.user_id).await else {
+      continue;
+    };
+    invitations.push(PendingInvitation {
+      invitation,
+      username: user.username,
+      email: user.email,
+    });
+  }
+
+  ApiResponse::Ok(ListInvitationsResponse { invitations })
+}
+
+/// Sends a fresh registration link, which revokes the one being resent. Needs a
+/// recent passkey like [`crate::user::routes::send_registration_link_to_user`].
+pub async fn resend_invitation(
+  State(state): State<AppState>,
+  admin: SudoCtx,
+  Path(invitation_id): Path<i64>,
+) -> ApiResponse<InvitationResponse> {
+  let Ok(invitation) = Invitation::from_id(&state.pool, invitation_id).await else {
+    return ApiResponse::Err(unknown_invitation());
+  };
+
+  // expired invitations can still be resent
+  if invitation.used_at.is_some() || invitation.revoked_at.is_some() {
+    return ApiResponse::Err(unknown_invitation());
+  }
+
+  let Ok(user) = User::from_user_id(&state.pool, invitation.user_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownUser);
+  };
+
+  let Ok(invitation) = Invitation::create(&state.pool, user.id, Some(admin.user.id)).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  match user.send_registration_mail(&state, &invitation).await {
+    Ok(_) => ApiResponse::Ok(InvitationResponse { invitation }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn revoke_invitation(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path(invitation_id): Path<i64>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(mut invitation) = Invitation::from_id(&state.pool, invitation_id).await else {
+    return ApiResponse::Err(unknown_invitation());
+  };
+
+  if !invitation.is_pending() {
+    return ApiResponse::Err(unknown_invitation());
+  }
+
+  match invitation.revoke(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
@@ -0,0 +1,261 @@
+use std::{
+  error::Error,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+  Json,
+  extract::{Path, State},
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use sqlx::PgPool;
+
+use crate::{
+  AppState,
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  user::AdminCtx,
+};
+
+/// A reusable code that lets people sign up on their own, see
+/// [`crate::auth::signup`]. Unlike invitations, these aren't tied to a user.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct InviteCode {
+  #[serde_as(as = "DisplayFromStr")]
+  pub id: i64,
+  pub code: String,
+  /// None if the code can be used any number of times
+  pub max_uses: Option<i32>,
+  pub uses: i32,
+  pub created_by: Option<i32>,
+  pub created_at: i64,
+  /// None if the code never expires
+  pub expires_at: Option<i64>,
+  pub revoked_at: Option<i64>,
+}
+
+fn now() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards lol")
+    .as_secs() as i64
+}
+
+/// Dashes, whitespace and case don't matter when codes are entered
+fn normalize_code(code: &str) -> String {
+  code
+    .chars()
+    .filter(|x| x.is_ascii_alphanumeric())
+    .map(|x| x.to_ascii_lowercase())
+    .collect()
+}
+
+impl InviteCode {
+  pub fn is_usable(&self) -> bool {
+    self.revoked_at.is_none()
+      && self.expires_at.is_none_or(|x| x > now())
+      && self.max_uses.is_none_or(|x| self.uses < x)
+  }
+
+  /// Every code that wasn't revoked, including used up and expired ones
+  pub async fn get_all(pool: &PgPool) -> Result<Vec<InviteCode>, Box<dyn Error>> {
+    let codes = sqlx::query_as!(
+      InviteCode,
+      r#"
+        SELECT id, code, max_uses, uses, created_by, created_at, expires_at, revoked_at
+        FROM signup_invite_codes WHERE revoked_at IS NULL
+        ORDER BY created_at DESC
+      "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(codes)
+  }
+
+  pub async fn from_id(pool: &PgPool, id: i64) -> Result<InviteCode, Box<dyn Error>> {
+    let code = sqlx::query_as!(
+      InviteCode,
+      r#"
+        SELECT id, code, max_uses, uses, created_by, created_at, expires_at, revoked_at
+        FROM signup_invite_codes WHERE id = $1
+      "#,
+      id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(code)
+  }
+
+  pub async fn from_code(pool: &PgPool, code: &str) -> Result<InviteCode, Box<dyn Error>> {
+    let code = sqlx::query_as!(
+      InviteCode,
+      r#"
+        SELECT id, code, max_uses, uses, created_by, created_at, expires_at, revoked_at
+        FROM signup_invite_codes WHERE code = $1
+      "#,
+      normalize_code(code)
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(code)
+  }
+
+  pub async fn create(
+    pool: &PgPool,
+    max_uses: Option<i32>,
+    expires_at: Option<i64>,
+    created_by: i32,
+  ) -> Result<InviteCode, Box<dyn Error>> {
+    let code = Alphanumeric
+      .sample_string(&mut rand::thread_rng(), 12)
+      .to_lowercase();
+    let code = sqlx::query_as!(
+      InviteCode,
+      r#"
+        INSERT INTO signup_invite_codes(code, max_uses, created_by, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, code, max_uses, uses, created_by, created_at, expires_at, revoked_at
+      "#,
+      code,
+      max_uses,
+      created_by,
+      now(),
+      expires_at
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(code)
+  }
+
+  /// Counts a use of the code. Returns false if it can't be used anymore, e.g.
+  /// because a concurrent signup took its last use.
+  pub async fn consume(&mut self, pool: &PgPool) -> Result<bool, Box<dyn Error>> {
+    let uses = sqlx::query_scalar!(
+      r#"
+        UPDATE signup_invite_codes SET uses = uses + 1
+        WHERE id = $1 AND revoked_at IS NULL
+          AND (expires_at IS NULL OR expires_at > $2)
+          AND (max_uses IS NULL OR uses < max_uses)
+        RETURNING uses
+      "#,
+      self.id,
+      now()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(uses) = uses else {
+      return Ok(false);
+    };
+
+    self.uses = uses;
+    Ok(true)
+  }
+
+  /// Undoes [`InviteCode::consume`], for when the signup fails afterwards
+  pub async fn release(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let uses = sqlx::query_scalar!(
+      r#"
+        UPDATE signup_invite_codes SET uses = GREATEST(uses - 1, 0) WHERE id = $1
+        RETURNING uses
+      "#,
+      self.id
+    )
+    .fetch_one(pool)
+    .await?;
+    self.uses = uses;
+    Ok(())
+  }
+
+  pub async fn revoke(&mut self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let revoked_at = now();
+    sqlx::query!(
+      r#"
+        UPDATE signup_invite_codes SET revoked_at = $1 WHERE id = $2
+      "#,
+      revoked_at,
+      self.id
+    )
+    .execute(pool)
+    .await?;
+    self.revoked_at = Some(revoked_at);
+    Ok(())
+  }
+}
+
+#[derive(Deserialize)]
+pub struct CreateInviteCodeRequest {
+  /// Leave out to allow any number of signups
+  #[serde(default)]
+  pub max_uses: Option<i32>,
+  /// Seconds until the code expires, leave out for codes that never expire
+  #[serde(default)]
+  pub expires_in: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ListInviteCodesResponse {
+  pub invite_codes: Vec<InviteCode>,
+}
+
+#[derive(Serialize)]
+pub struct InviteCodeResponse {
+  pub invite_code: InviteCode,
+}
+
+fn unknown_invite_code() -> ApiErr {
+  ApiErr::Other(
+    "unknown_invite_code".to_string(),
+    "Sorry, but this invite code doesn't exist or was revoked.".to_string(),
+  )
+}
+
+pub async fn list_invite_codes(
+  State(state): State<AppState>,
+  _: AdminCtx,
+) -> ApiResponse<ListInviteCodesResponse> {
+  match InviteCode::get_all(&state.pool).await {
+    Ok(invite_codes) => ApiResponse::Ok(ListInviteCodesResponse { invite_codes }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn create_invite_code(
+  State(state): State<AppState>,
+  admin: AdminCtx,
+  Json(payload): Json<CreateInviteCodeRequest>,
+) -> ApiResponse<InviteCodeResponse> {
+  if payload.max_uses.is_some_and(|x| x < 1) || payload.expires_in.is_some_and(|x| x < 1) {
+    return ApiResponse::Err(ApiErr::Other(
+      "invalid_invite_code_limits".to_string(),
+      "The maximum number of uses and the lifetime of an invite code must be positive.".to_string(),
+    ));
+  }
+
+  let expires_at = payload.expires_in.map(|x| now() + x);
+  match InviteCode::create(&state.pool, payload.max_uses, expires_at, admin.user.id).await {
+    Ok(invite_code) => ApiResponse::Ok(InviteCodeResponse { invite_code }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn revoke_invite_code(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path(invite_code_id): Path<i64>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(mut invite_code) = InviteCode::from_id(&state.pool, invite_code_id).await else {
+    return ApiResponse::Err(unknown_invite_code());
+  };
+
+  if invite_code.revoked_at.is_some() {
+    return ApiResponse::Err(unknown_invite_code());
+  }
+
+  match invite_code.revoke(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
@@ -3,7 +3,7 @@ use std::error::Error;
 use axum::{
   Router,
   extract::{FromRef, FromRequestParts},
-  routing::{get, post},
+  routing::{delete, get, patch, post, put},
 };
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
@@ -12,13 +12,25 @@ use webauthn_rs::prelude::Url;
 
 use crate::{
   AppState,
-  auth::{identity::IdentityAccessClaims, register::RegistrationClaims},
+  auth::{identity::IdentityAccessClaims, register::RegistrationClaims, session::UserSession},
   group::IdentityGroup,
   response::{ApiErr, ApiResponse, EmptyResponse},
-  smtp::{new_registration_message, send_mail},
+  smtp::{get_frontend_link, new_registration_message, send_mail},
+  user::invitations::Invitation,
 };
 
+pub mod attributes;
+pub mod email;
+pub mod events;
+pub mod handoff;
+pub mod impersonation;
+pub mod invitations;
+pub mod invite_codes;
+pub mod passkeys;
+pub mod recovery_codes;
 pub mod routes;
+pub mod sessions;
+pub mod totp;
 
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
@@ -29,12 +41,41 @@ pub struct User {
   pub is_suspended: bool,
   pub credential_uuid: sqlx::types::Uuid,
   pub is_admin: bool,
+  /// Only ever set by opening a link sent to the email, see [`email`]
+  pub email_verified: bool,
+}
+
+/// This should be extracted INSTEAD of User in routes that restricted sessions
+/// (started with a recovery code) may use as well. User rejects them.
+pub struct RestrictedCtx {
+  pub user: User,
+  pub claims: IdentityAccessClaims,
 }
 
 /// This should be extracted in routes where admin is required INSTEAD of
 /// extracting User. It will check admin for you.
 pub struct AdminCtx {
   pub user: User,
+  pub claims: IdentityAccessClaims,
+}
+
+/// Like AdminCtx, but also requires the admin to have used a passkey recently,
+/// for the most sensitive actions. Clients should re-authenticate and retry when
+/// this is rejected with [`ApiErr::ReauthenticationRequired`].
+pub struct SudoCtx {
+  pub user: User,
+  pub claims: IdentityAccessClaims,
+}
+
+impl AdminCtx {
+  /// For routes where only some changes are sensitive enough to need a recent
+  /// passkey, see [`SudoCtx`]
+  pub fn check_sudo(&self, state: &AppState) -> Result<(), ApiErr> {
+    if !self.claims.has_recent_passkey(state.session_lifetimes.sudo) {
+      return Err(ApiErr::ReauthenticationRequired);
+    }
+    Ok(())
+  }
 }
 
 impl User {
@@ -44,7 +85,7 @@ impl User {
     let users = sqlx::query_as!(
       User,
       r#"
-        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin FROM users
+        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin, email_verified FROM users
       "#
     )
     .fetch_all(pool)
@@ -56,7 +97,7 @@ impl User {
     let user = sqlx::query_as!(
       User,
       r#"
-        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin FROM users WHERE id = $1
+        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin, email_verified FROM users WHERE id = $1
       "#,
       user_id
     ).fetch_one(pool).await?;
@@ -67,13 +108,25 @@ impl User {
     let user = sqlx::query_as!(
       User,
       r#"
-        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin FROM users WHERE username = $1
+        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin, email_verified FROM users WHERE username = $1
       "#,
       username
     ).fetch_one(pool).await?;
     Ok(user)
   }
 
+  /// Emails are compared case-insensitively
+  pub async fn from_email(pool: &PgPool, email: String) -> Result<User, Box<dyn Error>> {
+    let user = sqlx::query_as!(
+      User,
+      r#"
+        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin, email_verified FROM users WHERE LOWER(email) = LOWER($1)
+      "#,
+      email
+    ).fetch_one(pool).await?;
+    Ok(user)
+  }
+
   pub async fn from_credential_uuid(
     pool: &PgPool,
     cred_uuid: &sqlx::types::Uuid,
@@ -81,7 +134,7 @@ impl User {
     let user = sqlx::query_as!(
       User,
       r#"
-        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin FROM users WHERE credential_uuid = $1
+        SELECT id, email, username, name, is_suspended, credential_uuid, is_admin, email_verified FROM users WHERE credential_uuid = $1
       "#,
       cred_uuid
     ).fetch_one(pool).await?;
@@ -91,15 +144,16 @@ impl User {
   pub async fn create(&mut self, pool: &PgPool) -> Result<&User, Box<dyn Error>> {
     let result = sqlx::query_scalar!(
       r#"
-        INSERT INTO users(email, username, name, is_suspended, credential_uuid, is_admin) VALUES 
-          ($1, $2, $3, $4, $5, $6) RETURNING id
+        INSERT INTO users(email, username, name, is_suspended, credential_uuid, is_admin, email_verified) VALUES 
+          ($1, $2, $3, $4, $5, $6, $7) RETURNING id
       "#,
       self.email,
       self.username,
       self.name,
       self.is_suspended,
       self.credential_uuid,
-      self.is_admin
+      self.is_admin,
+      self.email_verified
     )
     .fetch_one(pool)
     .await?;
@@ -110,10 +164,10 @@ impl User {
   pub async fn update(&self, pool: &PgPool) -> Result<(), Box<dyn Error>> {
     sqlx::query!(
       r#"
-        UPDATE users SET email=$1, username=$2, name=$3, is_suspended=$4, credential_uuid=$5, is_admin=$6
-        WHERE id=$7
+        UPDATE users SET email=$1, username=$2, name=$3, is_suspended=$4, credential_uuid=$5, is_admin=$6, email_verified=$7
+        WHERE id=$8
       "#,
-      self.email, self.username, self.name, self.is_suspended, self.credential_uuid, self.is_admin, self.id
+      self.email, self.username, self.name, self.is_suspended, self.credential_uuid, self.is_admin, self.email_verified, self.id
     ).execute(pool).await?;
     Ok(())
   }
@@ -134,19 +188,14 @@ impl User {
     Ok(results)
   }
 
-  pub fn get_registration_link(&self, state: &AppState) -> String {
-    let claims = RegistrationClaims::new(self);
+  pub fn get_registration_link(&self, state: &AppState, invitation: &Invitation) -> String {
+    let claims = RegistrationClaims::new(self, invitation);
     let token = claims.to_token(state);
-    // TODO: use webauthn instead of OIDC issuer uri
-    format!(
-      "{}/auth/register/passkey?t={}",
-      state.oidc_issuer_uri.clone(),
-      token
-    )
+    get_frontend_link(state, &format!("/auth/register/passkey?t={}", token))
   }
 
-  pub async fn send_registration_mail(&self, state: &AppState) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
-    let registration_link = self.get_registration_link(state);
+  pub async fn send_registration_mail(&self, state: &AppState, invitation: &Invitation) -> Result<Option<JoinHandle<()>>, Box<dyn Error>> {
+    let registration_link = self.get_registration_link(state, invitation);
     let registration_url = Url::parse(&registration_link)?;
     let origin = registration_url.host().unwrap();
 
@@ -171,6 +220,10 @@ where
       return Err(ApiResponse::Err(ApiErr::LoginRequired));
     };
 
+    if claims.is_restricted() {
+      return Err(ApiResponse::Err(ApiErr::RestrictedSession));
+    }
+
     let app_state = AppState::from_ref(state);
 
     let Ok(user) = User::from_user_id(&app_state.pool, claims.user_id).await else {
@@ -185,6 +238,42 @@ where
   }
 }
 
+impl<S> FromRequestParts<S> for RestrictedCtx
+where
+  AppState: FromRef<S>,
+  S: Send + Sync,
+{
+  type Rejection = ApiResponse<EmptyResponse>;
+
+  async fn from_request_parts(
+    parts: &mut http::request::Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let Some(claims) = parts.extensions.get::<IdentityAccessClaims>().cloned() else {
+      return Err(ApiResponse::Err(ApiErr::LoginRequired));
+    };
+
+    let app_state = AppState::from_ref(state);
+
+    // restricted sessions end once they've been used, so check they still exist
+    if claims.is_restricted()
+      && UserSession::from_session_id(&app_state.pool, claims.session_id).await.is_err()
+    {
+      return Err(ApiResponse::Err(ApiErr::SessionExpired));
+    }
+
+    let Ok(user) = User::from_user_id(&app_state.pool, claims.user_id).await else {
+      return Err(ApiResponse::Err(ApiErr::UserDeleted));
+    };
+
+    if user.is_suspended {
+      return Err(ApiResponse::Err(ApiErr::UserSuspended));
+    }
+
+    Ok(RestrictedCtx { user, claims })
+  }
+}
+
 impl<S> FromRequestParts<S> for AdminCtx
 where
   AppState: FromRef<S>,
@@ -200,6 +289,15 @@ where
       return Err(ApiResponse::Err(ApiErr::LoginRequired));
     };
 
+    if claims.is_restricted() {
+      return Err(ApiResponse::Err(ApiErr::RestrictedSession));
+    }
+
+    // admins can't be impersonated, but the user might have become one since
+    if claims.is_impersonated() {
+      return Err(ApiResponse::Err(ApiErr::AdminRequired));
+    }
+
     let app_state = AppState::from_ref(state);
 
     let Ok(user) = User::from_user_id(&app_state.pool, claims.user_id).await else {
@@ -214,7 +312,28 @@ where
       return Err(ApiResponse::Err(ApiErr::AdminRequired));
     }
 
-    Ok(AdminCtx { user })
+    Ok(AdminCtx { user, claims: claims.clone() })
+  }
+}
+
+impl<S> FromRequestParts<S> for SudoCtx
+where
+  AppState: FromRef<S>,
+  S: Send + Sync,
+{
+  type Rejection = ApiResponse<EmptyResponse>;
+
+  async fn from_request_parts(
+    parts: &mut http::request::Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let admin = AdminCtx::from_request_parts(parts, state).await?;
+
+    if let Err(err) = admin.check_sudo(&AppState::from_ref(state)) {
+      return Err(ApiResponse::Err(err));
+    }
+
+    Ok(SudoCtx { user: admin.user, claims: admin.claims })
   }
 }
 
@@ -228,10 +347,90 @@ pub fn router() -> Router<AppState> {
       "/v1/users/{user_id}",
       get(routes::get_user_by_id).patch(routes::update_user),
     )
+    .route(
+      "/v1/users/{user_id}/attributes/{name}",
+      put(routes::update_user_attribute).delete(routes::delete_user_attribute),
+    )
+    .route(
+      "/v1/users/{user_id}/security-events",
+      get(routes::list_user_security_events),
+    )
+    .route(
+      "/v1/users/{user_id}/revoke-sessions",
+      post(routes::revoke_user_sessions),
+    )
     .route(
       "/v1/users/{user_id}/send-registration-link",
       post(routes::send_registration_link_to_user),
     )
+    .route(
+      "/v1/users/{user_id}/impersonate",
+      post(impersonation::start_impersonation),
+    )
+    .route(
+      "/v1/users/{user_id}/impersonation-actions",
+      get(impersonation::list_impersonation_actions),
+    )
+    .route("/v1/invitations", get(invitations::list_invitations))
+    .route(
+      "/v1/invitations/{invitation_id}",
+      delete(invitations::revoke_invitation),
+    )
+    .route(
+      "/v1/invitations/{invitation_id}/resend",
+      post(invitations::resend_invitation),
+    )
+    .route(
+      "/v1/invite-codes",
+      get(invite_codes::list_invite_codes).post(invite_codes::create_invite_code),
+    )
+    .route(
+      "/v1/invite-codes/{invite_code_id}",
+      delete(invite_codes::revoke_invite_code),
+    )
     .route("/v1/user", get(routes::get_current_user))
     .route("/v1/user/groups", get(routes::get_current_user_groups))
+    .route("/v1/user/email", put(email::change_email))
+    .route("/v1/user/email/verify", post(email::send_email_verification))
+    .route("/v1/user/email/confirm", post(email::confirm_email))
+    .route(
+      "/v1/user/handoff/{code}",
+      get(handoff::get_handoff).delete(handoff::reject_handoff),
+    )
+    .route(
+      "/v1/user/handoff/{code}/approve",
+      post(handoff::approve_handoff),
+    )
+    .route("/v1/user/sessions", get(sessions::list_sessions))
+    .route(
+      "/v1/user/sessions/revoke-others",
+      post(sessions::revoke_other_sessions),
+    )
+    .route(
+      "/v1/user/sessions/{session_id}",
+      delete(sessions::revoke_session),
+    )
+    .route(
+      "/v1/user/recovery-codes",
+      get(recovery_codes::get_recovery_code_status).post(recovery_codes::regenerate_recovery_codes),
+    )
+    .route(
+      "/v1/user/totp",
+      get(totp::get_totp_status).delete(totp::delete_totp),
+    )
+    .route("/v1/user/totp/enroll", post(totp::start_totp_enrollment))
+    .route("/v1/user/totp/confirm", post(totp::confirm_totp_enrollment))
+    .route("/v1/user/passkeys", get(passkeys::list_passkeys))
+    .route(
+      "/v1/user/passkeys/{passkey_id}",
+      patch(passkeys::rename_passkey).delete(passkeys::delete_passkey),
+    )
+    .route(
+      "/v1/user/passkeys/register/initiate",
+      post(passkeys::start_add_passkey),
+    )
+    .route(
+      "/v1/user/passkeys/register/finalize",
+      post(passkeys::finish_add_passkey),
+    )
 }
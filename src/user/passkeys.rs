@@ -0,0 +1,244 @@
+use axum::{
+  Extension, Json,
+  extract::{Path, State},
+};
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::RegisterPublicKeyCredential;
+
+use crate::{
+  AppState,
+  auth::{
+    credential::WebauthnCredential,
+    identity::IdentityAccessClaims,
+    register::{
+      AuthenticatorKind, RegistrationInitiateResponse, SignedChallengeClaims,
+      create_registration_challenge, save_registered_passkey,
+    },
+    session::UserSession,
+  },
+  ratelimit::{IpRateLimit, RegistrationRoutes},
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  user::{RestrictedCtx, User, impersonation::check_can_add_credentials},
+};
+
+const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+
+#[derive(Serialize)]
+pub struct ListPasskeysResponse {
+  pub passkeys: Vec<WebauthnCredential>,
+  /// The passkey used to sign in to the current session
+  pub current_passkey_id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RenamePasskeyRequest {
+  pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct AddPasskeyInitiateRequest {
+  #[serde(default)]
+  pub authenticator: AuthenticatorKind,
+}
+
+#[derive(Deserialize)]
+pub struct AddPasskeyFinalizeRequest {
+  pub challenge_signature: String,
+  pub pk_credential: RegisterPublicKeyCredential,
+  pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyResponse {
+  pub passkey: WebauthnCredential,
+}
+
+fn validate_passkey_name(name: &str) -> Option<ApiErr> {
+  let name = name.trim();
+  if name.is_empty() || name.chars().count() > MAX_PASSKEY_NAME_LENGTH {
+    return Some(ApiErr::Other(
+      "invalid_passkey_name".to_string(),
+      format!(
+        "Passkey names must be between 1 and {} characters long.",
+        MAX_PASSKEY_NAME_LENGTH
+      ),
+    ));
+  }
+
+  None
+}
+
+async fn get_user_passkey(
+  state: &AppState,
+  user: &User,
+  passkey_id: i32,
+) -> Result<WebauthnCredential, ApiErr> {
+  let Ok(passkeys) =
+    WebauthnCredential::from_credential_uuid(&state.pool, user.credential_uuid).await
+  else {
+    return Err(ApiErr::InternalServerError);
+  };
+
+  passkeys
+    .into_iter()
+    .find(|x| x.id == passkey_id)
+    .ok_or(ApiErr::Other(
+      "unknown_passkey".to_string(),
+      "Sorry, but this passkey doesn't exist or has been deleted.".to_string(),
+    ))
+}
+
+pub async fn list_passkeys(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+) -> ApiResponse<ListPasskeysResponse> {
+  match WebauthnCredential::from_credential_uuid(&state.pool, current_user.credential_uuid).await {
+    Ok(passkeys) => ApiResponse::Ok(ListPasskeysResponse {
+      passkeys,
+      current_passkey_id: claims.webauthn_id,
+    }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn rename_passkey(
+  State(state): State<AppState>,
+  current_user: User,
+  Path(passkey_id): Path<i32>,
+  Json(payload): Json<RenamePasskeyRequest>,
+) -> ApiResponse<PasskeyResponse> {
+  if let Some(err) = validate_passkey_name(&payload.name) {
+    return ApiResponse::Err(err);
+  }
+
+  let mut passkey = match get_user_passkey(&state, &current_user, passkey_id).await {
+    Ok(passkey) => passkey,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  passkey.name = payload.name.trim().to_string();
+
+  match passkey.update(&state.pool).await {
+    Ok(_) => ApiResponse::Ok(PasskeyResponse { passkey }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Deletes a passkey and signs out every session started with it. The last
+/// usable (unlocked) passkey can't be deleted, otherwise the user would be
+/// locked out of their account. Needs a recent passkey.
+pub async fn delete_passkey(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+  Path(passkey_id): Path<i32>,
+) -> ApiResponse<EmptyResponse> {
+  if !claims.has_recent_passkey(state.session_lifetimes.sudo) {
+    return ApiResponse::Err(ApiErr::ReauthenticationRequired);
+  }
+
+  let Ok(passkeys) =
+    WebauthnCredential::from_credential_uuid(&state.pool, current_user.credential_uuid).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(passkey) = passkeys.iter().find(|x| x.id == passkey_id) else {
+    return ApiResponse::Err(ApiErr::Other(
+      "unknown_passkey".to_string(),
+      "Sorry, but this passkey doesn't exist or has been deleted.".to_string(),
+    ));
+  };
+
+  if !passkeys.iter().any(|x| x.id != passkey_id && !x.is_locked) {
+    return ApiResponse::Err(ApiErr::Other(
+      "last_passkey".to_string(),
+      "You can't delete your only passkey. Add another passkey first.".to_string(),
+    ));
+  }
+
+  match passkey.delete(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Restricted sessions may use this too, it's the only thing they're for. Other
+/// sessions need a recent passkey.
+pub async fn start_add_passkey(
+  State(state): State<AppState>,
+  ctx: RestrictedCtx,
+  _: IpRateLimit<RegistrationRoutes>,
+  payload: Option<Json<AddPasskeyInitiateRequest>>,
+) -> ApiResponse<RegistrationInitiateResponse> {
+  if let Err(err) = check_can_add_credentials(&ctx.claims) {
+    return ApiResponse::Err(err);
+  }
+
+  // restricted sessions are only for adding a passkey, so they're exempt
+  if !ctx.claims.is_restricted() && !ctx.claims.has_recent_passkey(state.session_lifetimes.sudo) {
+    return ApiResponse::Err(ApiErr::ReauthenticationRequired);
+  }
+
+  // the body is optional, older clients don't send one
+  let authenticator = payload.map(|Json(x)| x.authenticator).unwrap_or_default();
+
+  match create_registration_challenge(&state, &ctx.user, authenticator).await {
+    Ok(response) => ApiResponse::Ok(response),
+    Err(err) => ApiResponse::Err(err),
+  }
+}
+
+/// A restricted session ends once it has registered a passkey, and the user has
+/// to sign in with the new passkey.
+pub async fn finish_add_passkey(
+  State(state): State<AppState>,
+  ctx: RestrictedCtx,
+  _: IpRateLimit<RegistrationRoutes>,
+  Json(payload): Json<AddPasskeyFinalizeRequest>,
+) -> ApiResponse<PasskeyResponse> {
+  if let Err(err) = check_can_add_credentials(&ctx.claims) {
+    return ApiResponse::Err(err);
+  }
+
+  // restricted sessions are only for adding a passkey, so they're exempt
+  if !ctx.claims.is_restricted() && !ctx.claims.has_recent_passkey(state.session_lifetimes.sudo) {
+    return ApiResponse::Err(ApiErr::ReauthenticationRequired);
+  }
+
+  if let Some(err) = validate_passkey_name(&payload.name) {
+    return ApiResponse::Err(err);
+  }
+
+  let Some(signed_challenge) =
+    SignedChallengeClaims::from_token(payload.challenge_signature, &state)
+  else {
+    return ApiResponse::Err(ApiErr::InvalidChallenge);
+  };
+
+  let passkey = match save_registered_passkey(
+    &state,
+    &ctx.user,
+    signed_challenge,
+    &payload.pk_credential,
+    Some(payload.name.trim().to_string()),
+  )
+  .await
+  {
+    Ok(passkey) => passkey,
+    Err(err) => return ApiResponse::Err(err),
+  };
+
+  if ctx.claims.is_restricted() {
+    let Ok(mut session) = UserSession::from_session_id(&state.pool, ctx.claims.session_id).await
+    else {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    };
+    if session.delete_session(&state.pool).await.is_err() {
+      return ApiResponse::Err(ApiErr::InternalServerError);
+    }
+  }
+
+  ApiResponse::Ok(PasskeyResponse { passkey })
+}
@@ -0,0 +1,58 @@
+use axum::{Extension, extract::State};
+use serde::Serialize;
+
+use crate::{
+  AppState,
+  auth::{identity::IdentityAccessClaims, recovery_code::RecoveryCode},
+  response::{ApiErr, ApiResponse},
+  user::{User, impersonation::check_can_add_credentials},
+};
+
+#[derive(Serialize)]
+pub struct RecoveryCodeStatusResponse {
+  /// How many codes haven't been used yet
+  pub remaining: usize,
+  /// None if the user never generated codes
+  pub created_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RegenerateRecoveryCodesResponse {
+  /// These are only ever shown once
+  pub codes: Vec<String>,
+}
+
+pub async fn get_recovery_code_status(
+  State(state): State<AppState>,
+  current_user: User,
+) -> ApiResponse<RecoveryCodeStatusResponse> {
+  let Ok(codes) = RecoveryCode::from_user_id(&state.pool, current_user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  ApiResponse::Ok(RecoveryCodeStatusResponse {
+    remaining: codes.iter().filter(|x| x.used_at.is_none()).count(),
+    created_at: codes.iter().map(|x| x.created_at).max(),
+  })
+}
+
+/// Generates a new set of codes, which invalidates all of the previous ones. This
+/// needs a recent passkey, since it wipes out the codes the user wrote down.
+pub async fn regenerate_recovery_codes(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+) -> ApiResponse<RegenerateRecoveryCodesResponse> {
+  if let Err(err) = check_can_add_credentials(&claims) {
+    return ApiResponse::Err(err);
+  }
+
+  if !claims.has_recent_passkey(state.session_lifetimes.sudo) {
+    return ApiResponse::Err(ApiErr::ReauthenticationRequired);
+  }
+
+  match RecoveryCode::regenerate_for_user(&state.pool, current_user.id).await {
+    Ok(codes) => ApiResponse::Ok(RegenerateRecoveryCodesResponse { codes }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
@@ -7,9 +7,16 @@ use sqlx::types::Uuid;
 
 use crate::{
   AppState,
+  auth::session::UserSession,
   group::IdentityGroup,
   response::{ApiErr, ApiResponse, EmptyResponse},
-  user::{AdminCtx, User},
+  user::{
+    AdminCtx, SudoCtx, User,
+    attributes::UserAttribute,
+    email::{check_email_change, request_email_change},
+    events::SecurityEvent,
+    invitations::Invitation,
+  },
   util::UniqueConstraintViolation,
 };
 
@@ -34,9 +41,29 @@ pub struct GetUserResponse {
   pub groups: Vec<IdentityGroup>,
 }
 
+#[derive(Serialize)]
+pub struct GetUserDetailedResponse {
+  pub user: User,
+  pub groups: Vec<IdentityGroup>,
+  pub attributes: Vec<UserAttribute>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserAttributeRequest {
+  pub value: String,
+}
+
+#[derive(Serialize)]
+pub struct ListSecurityEventsResponse {
+  pub events: Vec<SecurityEvent>,
+}
+
 #[derive(Serialize)]
 pub struct UpdateUserResponse {
   pub user: User,
+  /// Email changes only apply once the new address is confirmed, this is true
+  /// if a confirmation link was sent to it
+  pub email_change_pending: bool,
 }
 
 type CreateUserResponse = UpdateUserResponse;
@@ -57,7 +84,7 @@ pub async fn get_user_by_id(
   State(state): State<AppState>,
   _: AdminCtx,
   Path(user_id): Path<i32>,
-) -> ApiResponse<GetUserResponse> {
+) -> ApiResponse<GetUserDetailedResponse> {
   let Ok(user) = User::from_user_id(&state.pool, user_id).await else {
     return ApiResponse::Err(ApiErr::UnknownUser);
   };
@@ -66,12 +93,20 @@ pub async fn get_user_by_id(
     return ApiResponse::Err(ApiErr::InternalServerError);
   };
 
-  ApiResponse::Ok(GetUserResponse { user, groups })
+  let Ok(attributes) = UserAttribute::get_attributes_for_user(&state.pool, user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  ApiResponse::Ok(GetUserDetailedResponse {
+    user,
+    groups,
+    attributes,
+  })
 }
 
 pub async fn update_user(
   State(state): State<AppState>,
-  _: AdminCtx,
+  admin: AdminCtx,
   Path(user_id): Path<i32>,
   Json(payload): Json<PartialUser>,
 ) -> ApiResponse<UpdateUserResponse> {
@@ -79,30 +114,63 @@ pub async fn update_user(
     return ApiResponse::Err(ApiErr::UnknownUser);
   };
 
+  // granting or revoking admin and changing the email need a recent passkey
+  let email_changed = payload.email.trim() != user.email;
+  if (payload.is_admin != user.is_admin || email_changed)
+    && let Err(err) = admin.check_sudo(&state)
+  {
+    return ApiResponse::Err(err);
+  }
+
+  // the email only changes once the new address is confirmed, but whether it
+  // can be changed is checked before anything else is
+  let new_email = match email_changed {
+    true => match check_email_change(&state, &user, &payload.email).await {
+      Ok(new_email) => Some(new_email),
+      Err(err) => return ApiResponse::Err(err),
+    },
+    false => None,
+  };
+
   user.name = payload.name;
-  user.email = payload.email;
   user.username = payload.username;
   user.is_suspended = payload.is_suspended;
   user.is_admin = payload.is_admin;
 
-  match user.update(&state.pool).await {
-    Ok(_) => ApiResponse::Ok(UpdateUserResponse { user }),
-    Err(err) => match UniqueConstraintViolation::from(err) {
+  if let Err(err) = user.update(&state.pool).await {
+    return match UniqueConstraintViolation::from(err) {
       Some(violation) => match violation.constraint_name.as_str() {
         "users_username_key" => ApiResponse::Err(ApiErr::UsernameExists),
-        "users_email_key" => ApiResponse::Err(ApiErr::EmailExists),
         _ => ApiResponse::Err(ApiErr::InternalServerError),
       },
       None => ApiResponse::Err(ApiErr::InternalServerError),
-    },
+    };
+  }
+
+  let email_change_pending = new_email.is_some();
+  if let Some(new_email) = new_email
+    && let Err(err) = request_email_change(&state, &user, new_email).await
+  {
+    return ApiResponse::Err(err);
   }
+
+  ApiResponse::Ok(UpdateUserResponse {
+    user,
+    email_change_pending,
+  })
 }
 
 pub async fn create_user(
   State(state): State<AppState>,
-  _: AdminCtx,
+  admin: AdminCtx,
   Json(payload): Json<PartialUser>,
 ) -> ApiResponse<UpdateUserResponse> {
+  if payload.is_admin
+    && let Err(err) = admin.check_sudo(&state)
+  {
+    return ApiResponse::Err(err);
+  }
+
   let mut user = User {
     id: 0,
     email: payload.email,
@@ -111,12 +179,16 @@ pub async fn create_user(
     is_suspended: payload.is_suspended,
     is_admin: payload.is_admin,
     credential_uuid: Uuid::new_v4(),
+    email_verified: false,
   };
 
   // TODO: consider automatically sending out registration email?
 
   match user.create(&state.pool).await {
-    Ok(_) => ApiResponse::Ok(CreateUserResponse { user }),
+    Ok(_) => ApiResponse::Ok(CreateUserResponse {
+      user,
+      email_change_pending: false,
+    }),
     Err(err) => match UniqueConstraintViolation::from(err) {
       Some(violation) => match violation.constraint_name.as_str() {
         "users_username_key" => ApiResponse::Err(ApiErr::UsernameExists),
@@ -128,7 +200,74 @@ pub async fn create_user(
   }
 }
 
+/// Sends a new registration link, revoking any link the user hasn't used yet.
+/// Whoever opens it can add a passkey, so this needs a recent passkey too.
 pub async fn send_registration_link_to_user(
+  State(state): State<AppState>,
+  admin: SudoCtx,
+  Path(user_id): Path<i32>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(user) = User::from_user_id(&state.pool, user_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownUser);
+  };
+
+  let Ok(invitation) = Invitation::create(&state.pool, user.id, Some(admin.user.id)).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  match user.send_registration_mail(&state, &invitation).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn update_user_attribute(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path((user_id, name)): Path<(i32, String)>,
+  Json(payload): Json<UpdateUserAttributeRequest>,
+) -> ApiResponse<EmptyResponse> {
+  let Ok(user) = User::from_user_id(&state.pool, user_id).await else {
+    return ApiResponse::Err(ApiErr::UnknownUser);
+  };
+
+  let attribute = UserAttribute {
+    user_id: user.id,
+    name,
+    value: payload.value,
+  };
+
+  match attribute.upsert_attribute(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn delete_user_attribute(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path((user_id, name)): Path<(i32, String)>,
+) -> ApiResponse<EmptyResponse> {
+  match UserAttribute::remove_attribute(&state.pool, user_id, name).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn list_user_security_events(
+  State(state): State<AppState>,
+  _: AdminCtx,
+  Path(user_id): Path<i32>,
+) -> ApiResponse<ListSecurityEventsResponse> {
+  match SecurityEvent::get_events_for_user(&state.pool, user_id).await {
+    Ok(events) => ApiResponse::Ok(ListSecurityEventsResponse { events }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Signs the user out of every session. Already issued access tokens stay valid
+/// until they expire, so suspend the user as well if that matters.
+pub async fn revoke_user_sessions(
   State(state): State<AppState>,
   _: AdminCtx,
   Path(user_id): Path<i32>,
@@ -137,7 +276,7 @@ pub async fn send_registration_link_to_user(
     return ApiResponse::Err(ApiErr::UnknownUser);
   };
 
-  match user.send_registration_mail(&state).await {
+  match UserSession::delete_sessions_for_user(&state.pool, user.id, None).await {
     Ok(_) => ApiResponse::EmptyOk,
     Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
   }
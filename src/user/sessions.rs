@@ -0,0 +1,106 @@
+use axum::{
+  Extension,
+  extract::{Path, State},
+};
+use serde::Serialize;
+use serde_with::{DisplayFromStr, serde_as};
+
+use crate::{
+  AppState,
+  auth::{credential::WebauthnCredential, identity::IdentityAccessClaims, session::UserSession},
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  user::User,
+};
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+  #[serde(flatten)]
+  pub session: UserSession,
+  /// None if the passkey has since been deleted
+  pub passkey_name: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct ListSessionsResponse {
+  pub sessions: Vec<SessionInfo>,
+  #[serde_as(as = "DisplayFromStr")]
+  pub current_session_id: i64,
+}
+
+pub async fn list_sessions(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+) -> ApiResponse<ListSessionsResponse> {
+  let Ok(mut sessions) = UserSession::from_user_id(&state.pool, current_user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Ok(passkeys) =
+    WebauthnCredential::from_credential_uuid(&state.pool, current_user.credential_uuid).await
+  else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  // expired sessions just haven't been purged yet
+  sessions.retain(|x| !x.is_expired(&state.session_lifetimes));
+
+  // most recently used first
+  sessions.sort_by_key(|x| std::cmp::Reverse(x.last_refreshed_at));
+
+  let sessions = sessions
+    .into_iter()
+    .map(|session| SessionInfo {
+      passkey_name: passkeys
+        .iter()
+        .find(|x| x.id == session.webauthn_id)
+        .map(|x| x.name.clone()),
+      session,
+    })
+    .collect::<Vec<SessionInfo>>();
+
+  ApiResponse::Ok(ListSessionsResponse {
+    sessions,
+    current_session_id: claims.session_id,
+  })
+}
+
+/// Access tokens already issued for a revoked session stay valid until they
+/// expire, but the session can no longer be refreshed.
+pub async fn revoke_session(
+  State(state): State<AppState>,
+  current_user: User,
+  Path(session_id): Path<i64>,
+) -> ApiResponse<EmptyResponse> {
+  let unknown_session = ApiErr::Other(
+    "unknown_session".to_string(),
+    "Sorry, but this session doesn't exist or has already been signed out.".to_string(),
+  );
+
+  let Ok(mut session) = UserSession::from_session_id(&state.pool, session_id).await else {
+    return ApiResponse::Err(unknown_session);
+  };
+
+  if session.user_id != current_user.id {
+    return ApiResponse::Err(unknown_session);
+  }
+
+  match session.delete_session(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+pub async fn revoke_other_sessions(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+) -> ApiResponse<EmptyResponse> {
+  match UserSession::delete_sessions_for_user(&state.pool, current_user.id, Some(claims.session_id))
+    .await
+  {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
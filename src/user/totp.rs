@@ -0,0 +1,187 @@
+use axum::{Extension, Json, extract::State};
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::Url;
+
+use crate::{
+  AppState,
+  auth::{
+    identity::IdentityAccessClaims,
+    totp::{
+      TotpCredential, encode_secret, encrypt_secret, generate_secret, is_totp_allowed,
+      totp_not_allowed,
+    },
+  },
+  response::{ApiErr, ApiResponse, EmptyResponse},
+  user::{User, impersonation::check_can_add_credentials},
+};
+
+#[derive(Serialize)]
+pub struct TotpStatusResponse {
+  /// Whether the user's groups allow TOTP
+  pub allowed: bool,
+  /// Only confirmed credentials can be used to sign in
+  pub credential: Option<TotpCredential>,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollResponse {
+  /// Base32 encoded, for entering the secret manually
+  pub secret: String,
+  /// For QR codes
+  pub otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpConfirmRequest {
+  pub code: String,
+}
+
+fn get_otpauth_uri(state: &AppState, user: &User, secret: &str) -> String {
+  let issuer = Url::parse(&state.oidc_issuer_uri)
+    .ok()
+    .and_then(|x| x.host_str().map(|x| x.to_string()))
+    .unwrap_or(state.oidc_issuer_uri.clone());
+
+  let mut uri = Url::parse("otpauth://totp/").expect("otpauth uri is valid");
+  uri.set_path(&format!("{}:{}", issuer, user.username));
+  uri
+    .query_pairs_mut()
+    .append_pair("secret", secret)
+    .append_pair("issuer", &issuer)
+    .append_pair("algorithm", "SHA1")
+    .append_pair("digits", "6")
+    .append_pair("period", "30");
+  uri.to_string()
+}
+
+pub async fn get_totp_status(
+  State(state): State<AppState>,
+  current_user: User,
+) -> ApiResponse<TotpStatusResponse> {
+  let Ok(groups) = current_user.get_groups(&state.pool).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  match TotpCredential::from_user_id(&state.pool, current_user.id).await {
+    Ok(credential) => ApiResponse::Ok(TotpStatusResponse {
+      allowed: is_totp_allowed(&groups),
+      credential,
+    }),
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
+
+/// Starts enrolling a new secret, which has to be confirmed with a code before
+/// it can be used. Unconfirmed secrets are replaced.
+pub async fn start_totp_enrollment(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+) -> ApiResponse<TotpEnrollResponse> {
+  if let Err(err) = check_can_add_credentials(&claims) {
+    return ApiResponse::Err(err);
+  }
+
+  let Ok(groups) = current_user.get_groups(&state.pool).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  if !is_totp_allowed(&groups) {
+    return ApiResponse::Err(totp_not_allowed());
+  }
+
+  let Ok(existing) = TotpCredential::from_user_id(&state.pool, current_user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  if existing.is_some_and(|x| x.is_confirmed) {
+    return ApiResponse::Err(ApiErr::Other(
+      "totp_already_enrolled".to_string(),
+      "You already have an authenticator app set up. Remove it first to set up a new one."
+        .to_string(),
+    ));
+  }
+
+  let secret = generate_secret();
+  let Ok(encrypted_secret) = encrypt_secret(&state, &secret) else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  if TotpCredential::save_pending(&state.pool, current_user.id, encrypted_secret)
+    .await
+    .is_err()
+  {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  let secret = encode_secret(&secret);
+  ApiResponse::Ok(TotpEnrollResponse {
+    otpauth_uri: get_otpauth_uri(&state, &current_user, &secret),
+    secret,
+  })
+}
+
+pub async fn confirm_totp_enrollment(
+  State(state): State<AppState>,
+  current_user: User,
+  Json(payload): Json<TotpConfirmRequest>,
+) -> ApiResponse<TotpStatusResponse> {
+  let Ok(credential) = TotpCredential::from_user_id(&state.pool, current_user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(mut credential) = credential.filter(|x| !x.is_confirmed) else {
+    return ApiResponse::Err(ApiErr::Other(
+      "no_pending_totp".to_string(),
+      "There is no authenticator app waiting to be confirmed.".to_string(),
+    ));
+  };
+
+  match credential.verify_and_record(&state, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => {
+      return ApiResponse::Err(ApiErr::Other(
+        "invalid_totp_code".to_string(),
+        "This code is not valid. Check the time on your device and try again.".to_string(),
+      ));
+    }
+    Err(_) => return ApiResponse::Err(ApiErr::InternalServerError),
+  }
+
+  if credential.confirm(&state.pool).await.is_err() {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  }
+
+  ApiResponse::Ok(TotpStatusResponse {
+    allowed: true,
+    credential: Some(credential),
+  })
+}
+
+/// Removes the secret and signs out every session started with it. Needs a
+/// recent passkey.
+pub async fn delete_totp(
+  State(state): State<AppState>,
+  current_user: User,
+  Extension(claims): Extension<IdentityAccessClaims>,
+) -> ApiResponse<EmptyResponse> {
+  if !claims.has_recent_passkey(state.session_lifetimes.sudo) {
+    return ApiResponse::Err(ApiErr::ReauthenticationRequired);
+  }
+
+  let Ok(credential) = TotpCredential::from_user_id(&state.pool, current_user.id).await else {
+    return ApiResponse::Err(ApiErr::InternalServerError);
+  };
+
+  let Some(credential) = credential else {
+    return ApiResponse::Err(ApiErr::Other(
+      "no_totp".to_string(),
+      "You don't have an authenticator app set up.".to_string(),
+    ));
+  };
+
+  match credential.delete(&state.pool).await {
+    Ok(_) => ApiResponse::EmptyOk,
+    Err(_) => ApiResponse::Err(ApiErr::InternalServerError),
+  }
+}
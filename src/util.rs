@@ -1,10 +1,16 @@
 // This file just contains various utils that don't really fit anywhere else
 
-use std::error::Error;
+use std::{
+  error::Error,
+  net::{IpAddr, SocketAddr},
+};
 
+use axum::extract::{ConnectInfo, FromRef, FromRequestParts};
 use base64::{Engine, prelude::BASE64_STANDARD};
 use http::HeaderMap;
 
+use crate::AppState;
+
 /// Use this to find out if a database error occurs due to a uniqueness
 /// constraint failure. You can then match by the database's constraint
 /// name (not the column name) to find which value has a conflict.
@@ -39,3 +45,75 @@ pub fn get_basic_auth_from_header(headers: &HeaderMap) -> Option<(String, String
 
   Some((username.to_string(), password.to_string()))
 }
+
+/// Returns the scheme and token from an `Authorization: <scheme> <token>` header,
+/// e.g. Bearer or DPoP access tokens.
+pub fn get_token_auth_from_header(headers: &HeaderMap) -> Option<(String, String)> {
+  let auth_value = headers.get("authorization")?;
+  let auth_str = auth_value.to_str().ok()?;
+  let (scheme, token) = auth_str.split_once(" ")?;
+
+  Some((scheme.to_string(), token.trim().to_string()))
+}
+
+/// Where a request came from, recorded on sessions so users can recognize them
+#[derive(Clone)]
+pub struct RequestMetadata {
+  pub user_agent: Option<String>,
+  pub ip_address: Option<String>,
+}
+
+impl<S> FromRequestParts<S> for RequestMetadata
+where
+  AppState: FromRef<S>,
+  S: Send + Sync,
+{
+  type Rejection = std::convert::Infallible;
+
+  async fn from_request_parts(
+    parts: &mut http::request::Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let app_state = AppState::from_ref(state);
+
+    let user_agent = parts
+      .headers
+      .get("user-agent")
+      .and_then(|x| x.to_str().ok())
+      .map(|x| x.to_string());
+
+    let peer_ip = parts
+      .extensions
+      .get::<ConnectInfo<SocketAddr>>()
+      .map(|x| x.0.ip());
+
+    // X-Forwarded-For is only believed when the request came from one of our
+    // proxies. Each proxy appends the address it got the request from, so the
+    // right-most address that isn't one of ours is the client. Anything left of
+    // that could have been made up by the client.
+    let forwarded_ip = peer_ip
+      .filter(|x| app_state.trusted_proxies.contains(x))
+      .and_then(|_| {
+        let hops = parts
+          .headers
+          .get_all("x-forwarded-for")
+          .iter()
+          .filter_map(|x| x.to_str().ok())
+          .flat_map(|x| x.split(','))
+          .map(|x| x.trim().parse::<IpAddr>().ok())
+          .collect::<Vec<Option<IpAddr>>>();
+        hops
+          .into_iter()
+          .rev()
+          .find(|x| x.is_none_or(|ip| !app_state.trusted_proxies.contains(&ip)))
+          .flatten()
+      });
+
+    let ip_address = forwarded_ip.or(peer_ip).map(|x| x.to_string());
+
+    Ok(RequestMetadata {
+      user_agent,
+      ip_address,
+    })
+  }
+}